@@ -0,0 +1,1078 @@
+use crate::cipher::{CipherKind, Keystream};
+use crate::error::ChatError;
+use crate::frame::{self, Frame, FrameType};
+use crate::kdf::DirectionSecrets;
+use crate::log::{self, Level};
+use crate::mac;
+use crate::transfer::{self, FileMessage, Transfers};
+use crate::transport::Transport;
+use std::io::{self, BufRead, Read, Write};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio::time::{self, Instant};
+
+// 每条消息以 4 字节密钥代数（epoch）和 8 字节序列号开头，均为大端
+pub const EPOCH_LEN: usize = 4;
+pub const SEQ_LEN: usize = 8;
+
+// 一次最多允许对方向前跳过的密钥代数，防止伪造的 epoch 让接收方做大量运算
+const MAX_EPOCH_JUMP: u32 = 1024;
+
+// 自动换钥的阈值：自上次换钥以来发送的消息数或字节数达到任意一个就换钥
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    pub max_messages: u64,
+    pub max_bytes: u64,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        RekeyPolicy {
+            max_messages: 100,
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+// 会话的各项时限。心跳间隔要明显短于 dead_peer，活着的对方才来得及回应
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    // 握手必须在这段时间内完成
+    pub handshake: Duration,
+    // 每隔这么久发送一个 Ping
+    pub heartbeat: Duration,
+    // 这么久没有收到对方的任何帧（包括 Pong）就认为连接已经断了
+    pub dead_peer: Duration,
+    // 这么久双方都没有聊天消息就结束会话；None 表示不限制
+    pub idle: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            handshake: Duration::from_secs(10),
+            heartbeat: Duration::from_secs(5),
+            dead_peer: Duration::from_secs(15),
+            idle: None,
+        }
+    }
+}
+
+// 单个方向的密钥材料：每个方向有独立的密钥和 MAC 密钥，
+// 由唯一使用它的线程独占，两个方向互不影响。
+// 每条消息用 nonce XOR 序列号（与 TLS 1.3 相同）得到独立的密钥流，
+// 所以丢失一条消息不会让后面的消息错位
+pub struct DirectionKeys {
+    cipher: CipherKind,
+    // 当前这一代的密钥；换钥时直接覆盖，旧密钥不再保留
+    secrets: DirectionSecrets,
+    epoch: u32,
+    // 发送方向：下一条要发送的序列号；接收方向：下一条期望的序列号
+    next_seq: u64,
+    // 接收方向累计发现的缺失消息数
+    missing: u64,
+    // 发送方向：本代密钥已加密的消息数和字节数
+    policy: RekeyPolicy,
+    messages_this_epoch: u64,
+    bytes_this_epoch: u64,
+}
+
+impl DirectionKeys {
+    pub fn new(kind: CipherKind, secrets: &DirectionSecrets) -> Self {
+        DirectionKeys {
+            cipher: kind,
+            secrets: secrets.clone(),
+            epoch: 0,
+            next_seq: 0,
+            missing: 0,
+            policy: RekeyPolicy::default(),
+            messages_this_epoch: 0,
+            bytes_this_epoch: 0,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: RekeyPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn missing(&self) -> u64 {
+        self.missing
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    // 下一条要发送（或期望收到）的序列号
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    // 推进到下一代密钥（发送方向用于自动换钥和 /rekey）
+    pub fn rekey(&mut self) {
+        self.secrets = self.secrets.ratchet();
+        self.epoch += 1;
+        self.messages_this_epoch = 0;
+        self.bytes_this_epoch = 0;
+    }
+
+    fn rekey_due(&self) -> bool {
+        self.messages_this_epoch >= self.policy.max_messages || self.bytes_this_epoch >= self.policy.max_bytes
+    }
+
+    // 第 seq 条消息的密钥流：nonce 的后 8 字节与序列号异或
+    fn keystream(&self, seq: u64) -> Box<dyn Keystream> {
+        let mut nonce = self.secrets.nonce;
+        for (byte, s) in nonce[4..].iter_mut().zip(seq.to_be_bytes()) {
+            *byte ^= s;
+        }
+        self.cipher.build(&self.secrets.key, &nonce)
+    }
+}
+
+// 加密并发送一条聊天消息
+pub fn send_message(writer: &mut impl Write, keys: &mut DirectionKeys, msg: &[u8]) -> io::Result<()> {
+    send_record(writer, keys, FrameType::Message, msg)
+}
+
+// 加密并发送一条记录：epoch || 序列号 || 密文，后附 HMAC-SHA256 标签（Encrypt-then-MAC）。
+// 标签覆盖帧类型、epoch、序列号和密文，聊天消息和文件消息共用同一个序列号空间
+pub fn send_record(writer: &mut impl Write, keys: &mut DirectionKeys, kind: FrameType, msg: &[u8]) -> io::Result<()> {
+    if keys.rekey_due() {
+        keys.rekey();
+        verbose!(
+            "[REKEY] Limit reached, ratcheting to key epoch {} (old keys discarded)",
+            keys.epoch
+        );
+    }
+    let seq = keys.next_seq;
+    keys.next_seq += 1;
+    keys.messages_this_epoch += 1;
+    keys.bytes_this_epoch += msg.len() as u64;
+
+    // 加密消息（流密码 XOR）
+    let mut ciphertext = msg.to_vec();
+    let keystream = keys.keystream(seq).apply_keystream(&mut ciphertext);
+
+    let trace = !kind.is_heartbeat();
+    if trace {
+        verbose!("[ENCRYPT] Message #{seq} (key epoch {})", keys.epoch);
+    }
+    // 明文和密钥流是秘密，只在教学模式下逐字节展示；文件数据块可能很大，只展示聊天消息。
+    // 三行作为一次输出，不会和接收线程的演示交错（不能为此持有 stdout 锁：下面的网络写可能阻塞）
+    if kind == FrameType::Message {
+        teach!(
+            "Plain: {:?} (\"{}\")\nKey: {}\nCipher: {:?}",
+            msg,
+            std::str::from_utf8(msg).unwrap_or(""),
+            spaced_hex(&keystream),
+            ciphertext
+        );
+    }
+
+    // 计算认证标签：epoch 和序列号也在标签覆盖范围内，不能被改动
+    let mut body = keys.epoch.to_be_bytes().to_vec();
+    body.extend_from_slice(&seq.to_be_bytes());
+    body.extend_from_slice(&ciphertext);
+    let message = mac::seal(&keys.secrets.mac_key, &[kind.id()], &body);
+    if trace {
+        verbose!("[MAC] HMAC-SHA256 tag: {}", to_hex(&message[body.len()..]));
+    }
+
+    // 封装成帧后发送
+    let frame = Frame::new(kind, message);
+    if trace {
+        verbose!("[NETWORK] Sending encrypted message ({} bytes)...", frame.payload.len());
+    }
+    frame::write_frame(writer, &frame)?;
+    if trace {
+        verbose!("[→] Sent {} bytes", frame::HEADER_LEN + frame.payload.len());
+    }
+    Ok(())
+}
+
+// 接收一条聊天消息；只支持聊天消息的场合（聊天室、测试）使用
+// 返回 Ok(None) 表示对方已断开（包括连接出错）；Err 表示消息被篡改、重放或类型不对，已拒绝
+pub fn receive_message(
+    reader: &mut impl Read,
+    keys: &mut DirectionKeys,
+) -> Result<Option<Vec<u8>>, String> {
+    match receive_record(reader, keys)? {
+        Some(record) if record.kind == FrameType::Message => Ok(Some(record.payload)),
+        Some(record) => Err(format!("{:?} records are not supported here", record.kind)),
+        None => Ok(None),
+    }
+}
+
+// 接收一条记录，先校验标签和序列号再解密；返回的 Frame 中 payload 是明文
+pub fn receive_record(reader: &mut impl Read, keys: &mut DirectionKeys) -> Result<Option<Frame>, String> {
+    let frame = match frame::read_frame(reader) {
+        Ok(Some(frame)) => frame,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            info!("[NETWORK] Connection error: {e}");
+            return Ok(None);
+        }
+    };
+    if frame.kind == FrameType::Handshake {
+        return Err("unexpected handshake frame after the handshake".to_string());
+    }
+    let trace = !frame.kind.is_heartbeat();
+    let n = frame.payload.len();
+    if trace {
+        verbose!("[NETWORK] Received encrypted message ({} bytes)", n);
+        verbose!("[←] Received {} bytes", frame::HEADER_LEN + n);
+    }
+
+    if n < EPOCH_LEN {
+        return Err(format!("message too short for a key epoch ({n} bytes)"));
+    }
+    let epoch = u32::from_be_bytes(frame.payload[..EPOCH_LEN].try_into().expect("length checked above"));
+
+    if epoch < keys.epoch {
+        return Err(format!(
+            "message from discarded key epoch {epoch} (current epoch {})",
+            keys.epoch
+        ));
+    }
+    if epoch - keys.epoch > MAX_EPOCH_JUMP {
+        return Err(format!("key epoch {epoch} is too far ahead of {}", keys.epoch));
+    }
+    // 对方换钥后，先在副本上推进棘轮，标签验证通过才替换掉旧密钥
+    let mut secrets = keys.secrets.clone();
+    for _ in keys.epoch..epoch {
+        secrets = secrets.ratchet();
+    }
+
+    let body = mac::open(&secrets.mac_key, &[frame.kind.id()], &frame.payload)?;
+    if trace {
+        verbose!("[MAC] Tag verified ✓");
+    }
+    if body.len() < EPOCH_LEN + SEQ_LEN {
+        return Err(format!("message too short for a sequence number ({} bytes)", body.len()));
+    }
+    let (seq, ciphertext) = body[EPOCH_LEN..].split_at(SEQ_LEN);
+    let seq = u64::from_be_bytes(seq.try_into().expect("split at SEQ_LEN"));
+
+    // 序列号必须严格递增：旧的序列号是重放或乱序，跳过的序列号说明有消息丢失
+    if seq < keys.next_seq {
+        return Err(format!(
+            "replayed or reordered message #{seq} (expected #{} or later)",
+            keys.next_seq
+        ));
+    }
+    if seq > keys.next_seq {
+        let gap = seq - keys.next_seq;
+        say!(
+            "[SEQ] ⚠ Gap detected: {gap} message(s) missing (#{} to #{})",
+            keys.next_seq,
+            seq - 1
+        );
+        keys.missing += gap;
+    }
+    keys.next_seq = seq + 1;
+    if epoch > keys.epoch {
+        verbose!("[REKEY] Peer switched to key epoch {epoch} at message #{seq} (old keys discarded)");
+        keys.secrets = secrets;
+        keys.epoch = epoch;
+    }
+
+    // 解密消息
+    let mut plaintext = ciphertext.to_vec();
+    let keystream = keys.keystream(seq).apply_keystream(&mut plaintext);
+
+    if trace {
+        verbose!("[DECRYPT] Message #{seq} (key epoch {epoch})");
+    }
+    if frame.kind != FrameType::Message || !log::shows(Level::Teach) {
+        return Ok(Some(Frame::new(frame.kind, plaintext)));
+    }
+    let text = std::str::from_utf8(&plaintext).unwrap_or("");
+    teach!(
+        "Cipher: {:?}\nKey: {}\nPlain: {:?} → \"{text}\"\n\
+         [TEST] Round-trip verified: \"{text}\" → encrypt → decrypt → \"{text}\" ✓",
+        ciphertext,
+        spaced_hex(&keystream),
+        plaintext
+    );
+    Ok(Some(Frame::new(frame.kind, plaintext)))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02X}")).collect()
+}
+
+// 64 个十六进制字符 → 32 字节（大小写均可）
+pub fn parse_hex32(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+// 教学输出用：每个字节之间留空格，方便和明文、密文逐字节对照
+fn spaced_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02X}")).collect::<Vec<_>>().join(" ")
+}
+
+// 读线程、输入线程和主循环之间传递的事件
+enum Event {
+    Line(String),
+    InputClosed,
+    Interrupted,
+    Message(Vec<u8>),
+    File(FileMessage),
+    Ping(Vec<u8>),
+    Pong,
+    Rejected(String),
+    PeerClosed,
+}
+
+impl Event {
+    // 通过认证的帧说明对方还活着
+    fn proves_peer_alive(&self) -> bool {
+        matches!(self, Event::Message(_) | Event::File(_) | Event::Ping(_) | Event::Pong)
+    }
+}
+
+// 输入 /quit 立即结束会话
+pub const QUIT_COMMAND: &str = "/quit";
+// 输入 /rekey 立即换钥，对方从我们的下一条消息开始跟随
+pub const REKEY_COMMAND: &str = "/rekey";
+
+// 全双工聊天：接收线程和输入线程各自独立运行，主循环（tokio 事件循环）负责发送、显示、
+// 文件传输和心跳。输入结束后半关闭连接，等对方也关闭后返回；对方先断开、输入 /quit、
+// 按 Ctrl-C 或会话空闲超时则立即返回；对方在 dead_peer 时限内毫无回应时返回超时错误
+pub fn run_chat(
+    stream: impl Transport,
+    send_keys: DirectionKeys,
+    receive_keys: DirectionKeys,
+    input: impl BufRead + Send + 'static,
+    transfers: Transfers,
+    timeouts: Timeouts,
+    on_message: impl FnMut(&[u8]),
+) -> Result<(), ChatError> {
+    let (events, inbox) = mpsc::unbounded_channel();
+
+    let mut reader = stream
+        .try_clone()
+        .map_err(ChatError::io("cloning the connection"))?;
+    let mut receive_keys = receive_keys;
+    let network_events = events.clone();
+    let interrupts = catch_interrupts(events.clone())?;
+    let receiver = thread::spawn(move || {
+        loop {
+            let event = match receive_record(&mut reader, &mut receive_keys) {
+                Ok(Some(record)) => match record.kind {
+                    FrameType::File => match FileMessage::decode(&record.payload) {
+                        Ok(msg) => Event::File(msg),
+                        Err(e) => Event::Rejected(e),
+                    },
+                    FrameType::Ping => Event::Ping(record.payload),
+                    FrameType::Pong => Event::Pong,
+                    _ => Event::Message(record.payload),
+                },
+                Ok(None) => break,
+                Err(e) => Event::Rejected(e),
+            };
+            if network_events.send(event).is_err() {
+                return;
+            }
+        }
+        if receive_keys.missing() > 0 {
+            info!("[SEQ] {} message(s) from the peer never arrived", receive_keys.missing());
+        }
+        let _ = network_events.send(Event::PeerClosed);
+    });
+
+    // 输入线程可能一直阻塞在 read_line 上，所以不 join，随进程一起退出
+    thread::spawn(move || {
+        for line in input.lines() {
+            let Ok(line) = line else { break };
+            if events.send(Event::Line(line)).is_err() {
+                return;
+            }
+        }
+        let _ = events.send(Event::InputClosed);
+    });
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .map_err(ChatError::io("starting the event loop"))?;
+    let mut writer = WriteDeadline::new(stream, timeouts.dead_peer)?;
+    let result = runtime.block_on(chat_loop(&mut writer, send_keys, inbox, transfers, timeouts, on_message));
+
+    // 关闭连接让接收线程退出，再收回 Ctrl-C 处理
+    let _ = writer.shutdown(Shutdown::Both);
+    let _ = receiver.join();
+    interrupts.close();
+    if writer.expired() {
+        return Err(ChatError::Timeout(format!(
+            "the peer stopped reading for {}s (dead connection?)",
+            timeouts.dead_peer.as_secs()
+        )));
+    }
+    result
+}
+
+// 主循环的写都是阻塞的：对方不再读取时，写满缓冲区后就会一直卡住，dead_peer 计时器也没有机会触发。
+// 看门狗线程发现一次写超过 limit 还没完成，就关闭连接，卡住的写随之返回错误
+struct WriteDeadline<S> {
+    inner: S,
+    // 正在进行的写是什么时候开始的；没有在写时为 None
+    started: Arc<Mutex<Option<std::time::Instant>>>,
+    expired: Arc<AtomicBool>,
+    // 被丢弃时看门狗线程退出
+    _stop: std::sync::mpsc::Sender<()>,
+}
+
+impl<S: Transport> WriteDeadline<S> {
+    fn new(inner: S, limit: Duration) -> Result<Self, ChatError> {
+        let watchdog = inner.try_clone().map_err(ChatError::io("cloning the connection"))?;
+        let started = Arc::new(Mutex::new(None::<std::time::Instant>));
+        let expired = Arc::new(AtomicBool::new(false));
+        let (stop, stopped) = std::sync::mpsc::channel::<()>();
+        let (watched, flag) = (Arc::clone(&started), Arc::clone(&expired));
+        thread::spawn(move || {
+            while stopped.recv_timeout(limit / 4) == Err(std::sync::mpsc::RecvTimeoutError::Timeout) {
+                let stuck = watched.lock().unwrap().is_some_and(|since| since.elapsed() >= limit);
+                if stuck {
+                    flag.store(true, Ordering::SeqCst);
+                    let _ = watchdog.shutdown(Shutdown::Both);
+                    return;
+                }
+            }
+        });
+        Ok(WriteDeadline { inner, started, expired, _stop: stop })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    // 看门狗是否因为写超时关闭了连接
+    fn expired(&self) -> bool {
+        self.expired.load(Ordering::SeqCst)
+    }
+
+    fn timed<T>(&mut self, op: impl FnOnce(&mut S) -> io::Result<T>) -> io::Result<T> {
+        *self.started.lock().unwrap() = Some(std::time::Instant::now());
+        let result = op(&mut self.inner);
+        *self.started.lock().unwrap() = None;
+        result
+    }
+}
+
+impl<S: Transport> Write for WriteDeadline<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.timed(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.timed(|inner| inner.flush())
+    }
+}
+
+async fn chat_loop(
+    writer: &mut WriteDeadline<impl Transport>,
+    mut send_keys: DirectionKeys,
+    mut inbox: mpsc::UnboundedReceiver<Event>,
+    mut transfers: Transfers,
+    timeouts: Timeouts,
+    mut on_message: impl FnMut(&[u8]),
+) -> Result<(), ChatError> {
+    let mut heartbeat = time::interval_at(Instant::now() + timeouts.heartbeat, timeouts.heartbeat);
+    heartbeat.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    let mut pings_sent: u64 = 0;
+    // 两个截止时间：对方最后一次有动静之后 dead_peer，最后一条聊天消息之后 idle
+    let dead_peer = time::sleep(timeouts.dead_peer);
+    let idle = time::sleep(timeouts.idle.unwrap_or(Duration::MAX / 4));
+    tokio::pin!(dead_peer, idle);
+
+    loop {
+        tokio::select! {
+            event = inbox.recv() => {
+                let Some(event) = event else { return Ok(()) };
+                if event.proves_peer_alive() {
+                    dead_peer.as_mut().reset(Instant::now() + timeouts.dead_peer);
+                }
+                if let (Event::Line(_) | Event::Message(_), Some(limit)) = (&event, timeouts.idle) {
+                    idle.as_mut().reset(Instant::now() + limit);
+                }
+                match event {
+                    Event::Line(line) => {
+                        let line = line.trim();
+                        if line.is_empty() {
+                            continue;
+                        }
+                        if line == QUIT_COMMAND {
+                            info!("[CHAT] Leaving the chat...");
+                            return Ok(());
+                        }
+                        if line == REKEY_COMMAND {
+                            send_keys.rekey();
+                            info!(
+                                "[REKEY] Ratcheted to key epoch {}; the peer switches with our next message",
+                                send_keys.epoch()
+                            );
+                            continue;
+                        }
+                        match file_command(&mut transfers, line) {
+                            Some(Ok(reply)) => {
+                                if let Some(reply) = reply {
+                                    send_record(writer, &mut send_keys, FrameType::File, &reply.encode())
+                                        .map_err(ChatError::io("sending a file message"))?;
+                                }
+                                continue;
+                            }
+                            Some(Err(e)) => {
+                                say!("[FILE] ✗ {line}: {e}");
+                                continue;
+                            }
+                            None => {}
+                        }
+                        send_message(writer, &mut send_keys, line.as_bytes())
+                            .map_err(ChatError::io("sending a message"))?;
+                    }
+                    Event::InputClosed => {
+                        info!("[CHAT] End of input, waiting for the peer to finish...");
+                        let _ = writer.shutdown(Shutdown::Write);
+                    }
+                    Event::Interrupted => {
+                        info!("\n[CHAT] Interrupted, closing the connection...");
+                        return Ok(());
+                    }
+                    Event::Message(plaintext) => on_message(&plaintext),
+                    Event::File(msg) => {
+                        if let Err(e) = transfers.handle(msg) {
+                            say!("[FILE] ✗ Transfer failed: {e}");
+                        }
+                    }
+                    Event::Ping(counter) => {
+                        // 和发送 Ping 一样：输入结束后写方向已关闭，回复失败不影响继续接收
+                        let _ = send_record(writer, &mut send_keys, FrameType::Pong, &counter);
+                    }
+                    Event::Pong => {}
+                    Event::Rejected(e) => say!("[AUTH] ✗ Message rejected: {e}"),
+                    Event::PeerClosed => {
+                        info!("[NETWORK] Peer closed the connection");
+                        return Ok(());
+                    }
+                }
+            }
+            // 文件传输每轮只读写一个数据块。yield_now 先把控制权交还给运行时，
+            // 计时器照常触发，输入和对方的消息也有机会先被处理
+            _ = task::yield_now(), if transfers.busy() => {
+                if let Some(msg) = transfers.step() {
+                    send_record(writer, &mut send_keys, FrameType::File, &msg.encode())
+                        .map_err(ChatError::io("sending a file message"))?;
+                }
+            }
+            _ = heartbeat.tick() => {
+                // 输入结束后写方向已关闭，发送失败不影响继续接收
+                pings_sent += 1;
+                let _ = send_record(writer, &mut send_keys, FrameType::Ping, &pings_sent.to_be_bytes());
+            }
+            _ = &mut dead_peer => {
+                info!("[NETWORK] ✗ Peer has not responded for {}s, closing the connection", timeouts.dead_peer.as_secs());
+                return Err(ChatError::Timeout(format!(
+                    "no response from the peer within {}s (dead connection?)",
+                    timeouts.dead_peer.as_secs()
+                )));
+            }
+            _ = &mut idle, if timeouts.idle.is_some() => {
+                info!("[CHAT] No messages for {}s, closing the idle session", timeouts.idle.unwrap_or_default().as_secs());
+                return Ok(());
+            }
+        }
+    }
+}
+
+// 处理 /send、/accept 和 /reject，返回要立即发给对方的文件消息（/send 的提议要等哈希算完，由 step 发出）；
+// 不是文件命令时返回 None
+fn file_command(transfers: &mut Transfers, line: &str) -> Option<io::Result<Option<FileMessage>>> {
+    if let Some(path) = line.strip_prefix(transfer::SEND_COMMAND).and_then(|rest| rest.strip_prefix(' ')) {
+        Some(transfers.offer(std::path::Path::new(path.trim())).map(|()| None))
+    } else if line == transfer::ACCEPT_COMMAND {
+        Some(transfers.accept().map(Some))
+    } else if line == transfer::REJECT_COMMAND {
+        Some(transfers.reject().map(Some))
+    } else {
+        None
+    }
+}
+
+// 会话期间把 Ctrl-C 变成一个普通事件，让主循环正常关闭连接
+fn catch_interrupts(events: mpsc::UnboundedSender<Event>) -> Result<signal_hook::iterator::Handle, ChatError> {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGINT])
+        .map_err(ChatError::io("installing the Ctrl-C handler"))?;
+    let handle = signals.handle();
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            let _ = events.send(Event::Interrupted);
+        }
+    });
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::os::unix::net::UnixStream;
+
+    use crate::handshake::Role;
+    use crate::kdf::SessionKeys;
+    use crate::transport;
+
+    fn keys(kind: CipherKind, role: Role) -> (DirectionKeys, DirectionKeys) {
+        let session = SessionKeys::derive(b"test shared secret", &[0u8; 32]);
+        (
+            DirectionKeys::new(kind, session.sending(role)),
+            DirectionKeys::new(kind, session.receiving(role)),
+        )
+    }
+
+    // 本地中间代理：转发一个数据块，可选地翻转其中一个字节
+    fn spawn_proxy(upstream: SocketAddr, corrupt_at: Option<usize>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut inbound, _) = listener.accept().unwrap();
+            let mut outbound = TcpStream::connect(upstream).unwrap();
+            let mut buffer = [0u8; 1024];
+            let n = inbound.read(&mut buffer).unwrap();
+            if let Some(i) = corrupt_at {
+                buffer[i] ^= 0x80;
+            }
+            outbound.write_all(&buffer[..n]).unwrap();
+        });
+        addr
+    }
+
+    fn send_through_proxy(msg: &[u8], corrupt_at: Option<usize>) -> Result<Option<Vec<u8>>, String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = spawn_proxy(listener.local_addr().unwrap(), corrupt_at);
+
+        let msg = msg.to_vec();
+        let sender = thread::spawn(move || {
+            let mut stream = TcpStream::connect(proxy).unwrap();
+            let (mut keys, _) = keys(CipherKind::Chacha20, Role::Client);
+            send_message(&mut stream, &mut keys, &msg).unwrap();
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let (_, mut keys) = keys(CipherKind::Chacha20, Role::Server);
+        let result = receive_message(&mut stream, &mut keys);
+        sender.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn untouched_message_passes_through_proxy() {
+        let result = send_through_proxy(b"hello", None);
+        assert_eq!(result, Ok(Some(b"hello".to_vec())));
+    }
+
+    #[test]
+    fn corrupted_ciphertext_is_rejected() {
+        let result = send_through_proxy(b"hello", Some(frame::HEADER_LEN + EPOCH_LEN + SEQ_LEN + 2));
+        assert_eq!(result, Err("authentication tag mismatch".to_string()));
+    }
+
+    #[test]
+    fn corrupted_tag_is_rejected() {
+        let result = send_through_proxy(b"hello", Some(frame::HEADER_LEN + EPOCH_LEN + SEQ_LEN + 5 + 10));
+        assert_eq!(result, Err("authentication tag mismatch".to_string()));
+    }
+
+    #[test]
+    fn corrupted_sequence_number_is_rejected() {
+        let result = send_through_proxy(b"hello", Some(frame::HEADER_LEN + EPOCH_LEN + SEQ_LEN - 1));
+        assert_eq!(result, Err("authentication tag mismatch".to_string()));
+    }
+
+    type Received = Result<Option<Vec<u8>>, String>;
+
+    // 按帧转发的代理：先收下全部 n 帧，再按 script 给出的下标顺序发出（可重复、可跳过）
+    fn spawn_frame_proxy(upstream: SocketAddr, n: usize, script: Vec<usize>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut inbound, _) = listener.accept().unwrap();
+            let mut outbound = TcpStream::connect(upstream).unwrap();
+            let frames: Vec<Frame> = (0..n).map(|_| frame::read_frame(&mut inbound).unwrap().unwrap()).collect();
+            for i in script {
+                frame::write_frame(&mut outbound, &frames[i]).unwrap();
+            }
+        });
+        addr
+    }
+
+    fn deliver_through_frame_proxy(n: usize, script: Vec<usize>) -> (Vec<Received>, u64) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let steps = script.len();
+        let proxy = spawn_frame_proxy(listener.local_addr().unwrap(), n, script);
+        let sender = thread::spawn(move || {
+            let mut stream = TcpStream::connect(proxy).unwrap();
+            let (mut keys, _) = keys(CipherKind::Chacha20, Role::Client);
+            for i in 0..n {
+                send_message(&mut stream, &mut keys, format!("message {i}").as_bytes()).unwrap();
+            }
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let (_, mut keys) = keys(CipherKind::Chacha20, Role::Server);
+        let results = (0..steps).map(|_| receive_message(&mut stream, &mut keys)).collect();
+        assert_eq!(receive_message(&mut stream, &mut keys), Ok(None));
+        sender.join().unwrap();
+        (results, keys.missing())
+    }
+
+    fn delivered(i: usize) -> Received {
+        Ok(Some(format!("message {i}").into_bytes()))
+    }
+
+    #[test]
+    fn replayed_messages_are_rejected() {
+        let (results, missing) = deliver_through_frame_proxy(3, vec![0, 1, 1, 0, 2, 2]);
+        assert_eq!(results[0], delivered(0));
+        assert_eq!(results[1], delivered(1));
+        assert!(results[2].as_ref().unwrap_err().contains("replayed"));
+        assert!(results[3].as_ref().unwrap_err().contains("replayed"));
+        // 重放被拒绝后，后续的正常消息照常解密
+        assert_eq!(results[4], delivered(2));
+        assert!(results[5].as_ref().unwrap_err().contains("replayed"));
+        assert_eq!(missing, 0);
+    }
+
+    #[test]
+    fn reordered_messages_are_rejected() {
+        let (results, missing) = deliver_through_frame_proxy(3, vec![1, 0, 2]);
+        assert_eq!(results[0], delivered(1));
+        assert!(results[1].as_ref().unwrap_err().contains("reordered"));
+        assert_eq!(results[2], delivered(2));
+        assert_eq!(missing, 1);
+    }
+
+    // 丢弃的消息被报告为缺口，之后的消息仍能正确解密（密钥流不会错位）
+    #[test]
+    fn dropped_messages_are_reported_as_gaps() {
+        let (results, missing) = deliver_through_frame_proxy(6, vec![0, 2, 5]);
+        assert_eq!(results, vec![delivered(0), delivered(2), delivered(5)]);
+        assert_eq!(missing, 3);
+    }
+
+    // 大消息会被 TCP 拆成很多段，小消息会被合并，两种情况都必须逐条还原
+    #[test]
+    fn large_and_back_to_back_messages_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let big: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let expected_big = big.clone();
+        let sender = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let (mut keys, _) = keys(CipherKind::Chacha20, Role::Client);
+            send_message(&mut stream, &mut keys, &big).unwrap();
+            for i in 0..1000 {
+                send_message(&mut stream, &mut keys, format!("{i}").as_bytes()).unwrap();
+            }
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let (_, mut keys) = keys(CipherKind::Chacha20, Role::Server);
+        assert_eq!(receive_message(&mut stream, &mut keys), Ok(Some(expected_big)));
+        for i in 0..1000 {
+            let received = receive_message(&mut stream, &mut keys);
+            assert_eq!(received, Ok(Some(format!("{i}").into_bytes())));
+        }
+        assert_eq!(receive_message(&mut stream, &mut keys), Ok(None));
+        sender.join().unwrap();
+    }
+
+    // 教学输出曾经为了打印 "Key:" 又从密钥流里多取字节。打开教学输出后，
+    // 双方交替收发很多条消息（跨越多次换钥），每一条都必须原样还原
+    #[test]
+    fn peers_stay_in_sync_with_teach_output_on() {
+        log::set_level(Level::Teach);
+        let policy = RekeyPolicy { max_messages: 7, max_bytes: 1024 };
+        for kind in [CipherKind::Chacha20, CipherKind::AesCtr, CipherKind::Lcg] {
+            let (client_send, client_receive) = keys(kind, Role::Client);
+            let (server_send, server_receive) = keys(kind, Role::Server);
+            let mut client = (client_send.with_policy(policy), client_receive);
+            let mut server = (server_send.with_policy(policy), server_receive);
+            for i in 0..200 {
+                let (sender, receiver) = match i % 3 {
+                    0 => (&mut server, &mut client),
+                    _ => (&mut client, &mut server),
+                };
+                let msg = format!("message {i}: {}", "x".repeat(i % 40));
+                let mut wire = Vec::new();
+                send_message(&mut wire, &mut sender.0, msg.as_bytes()).unwrap();
+                let received = receive_message(&mut wire.as_slice(), &mut receiver.1);
+                assert_eq!(received, Ok(Some(msg.into_bytes())), "{} #{i}", kind.name());
+            }
+            assert!(client.0.epoch() > 0 && server.0.epoch() > 0);
+            assert_eq!(client.1.missing() + server.1.missing(), 0);
+        }
+        log::set_level(Level::Normal);
+    }
+
+    // 测试用输入：从通道读取数据，发送端被丢弃后才返回 EOF
+    struct ChannelInput {
+        lines: std::sync::mpsc::Receiver<Vec<u8>>,
+        pending: Cursor<Vec<u8>>,
+    }
+
+    impl Read for ChannelInput {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pending.position() as usize == self.pending.get_ref().len() {
+                match self.lines.recv() {
+                    Ok(line) => self.pending = Cursor::new(line),
+                    Err(_) => return Ok(0),
+                }
+            }
+            self.pending.read(buf)
+        }
+    }
+
+    // 输入在收齐对方全部消息后才结束，避免一方提前退出
+    fn chat_peer(
+        stream: TcpStream,
+        role: Role,
+        lines: Vec<String>,
+        expected: usize,
+    ) -> thread::JoinHandle<Vec<String>> {
+        thread::spawn(move || {
+            let (tx, rx) = std::sync::mpsc::channel();
+            for line in lines {
+                tx.send(format!("{line}\n").into_bytes()).unwrap();
+            }
+            let mut tx = Some(tx);
+            let input = io::BufReader::new(ChannelInput { lines: rx, pending: Cursor::new(Vec::new()) });
+
+            let (send_keys, receive_keys) = keys(CipherKind::AesCtr, role);
+            let mut received = Vec::new();
+            let downloads = std::env::temp_dir().join(format!("rust_03-chat-{}", std::process::id()));
+            run_chat(stream, send_keys, receive_keys, input, Transfers::new(downloads), Timeouts::default(), |msg| {
+                received.push(String::from_utf8(msg.to_vec()).unwrap());
+                if received.len() == expected {
+                    tx.take();
+                }
+            })
+            .unwrap();
+            received
+        })
+    }
+
+    // 双方同时连续发送，不需要轮流；每个方向各自解密正确
+    #[test]
+    fn both_peers_can_send_concurrently() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let server_lines: Vec<String> = (0..300).map(|i| format!("server says {i}")).collect();
+        let client_lines: Vec<String> = (0..200).map(|i| format!("client says {i}")).collect();
+        let server_peer = chat_peer(server, Role::Server, server_lines.clone(), client_lines.len());
+        let client_peer = chat_peer(client, Role::Client, client_lines.clone(), server_lines.len());
+
+        assert_eq!(server_peer.join().unwrap(), client_lines);
+        assert_eq!(client_peer.join().unwrap(), server_lines);
+    }
+
+    // 双方同时发送远超套接字缓冲区的数据：写阻塞时接收线程必须照常读取，否则两边都在等对方读，
+    // 永远卡住（发送时曾经持有 stdout 锁，对面的接收线程拿不到锁就停止了读取）
+    #[test]
+    fn both_directions_stream_more_than_the_socket_buffers_hold() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        for stream in [&client, &server] {
+            let socket = socket2::SockRef::from(stream);
+            socket.set_send_buffer_size(64 * 1024).unwrap();
+            socket.set_recv_buffer_size(64 * 1024).unwrap();
+        }
+
+        // 每个方向 4 MiB
+        let lines = |who: &str| -> Vec<String> {
+            (0..16).map(|i| format!("{who} {i} {}", "x".repeat(256 * 1024))).collect()
+        };
+        let (server_lines, client_lines) = (lines("server"), lines("client"));
+        let server_peer = chat_peer(server, Role::Server, server_lines.clone(), client_lines.len());
+        let client_peer = chat_peer(client, Role::Client, client_lines.clone(), server_lines.len());
+
+        assert!(server_peer.join().unwrap() == client_lines);
+        assert!(client_peer.join().unwrap() == server_lines);
+    }
+
+    const FAST: Timeouts = Timeouts {
+        handshake: Duration::from_millis(300),
+        heartbeat: Duration::from_millis(50),
+        dead_peer: Duration::from_millis(300),
+        idle: None,
+    };
+
+    // 对方还连着但不再读取：写满缓冲区后阻塞的写也要在 dead_peer 时限内结束，报告超时
+    #[test]
+    fn peer_that_stops_reading_times_out() {
+        let (ours, _frozen) = UnixStream::pair().unwrap();
+        let lines: String = (0..64).map(|i| format!("{i} {}\n", "x".repeat(256 * 1024))).collect();
+        let (send_keys, receive_keys) = keys(CipherKind::Chacha20, Role::Client);
+        let started = std::time::Instant::now();
+        let result = run_chat(ours, send_keys, receive_keys, Cursor::new(lines), Transfers::new("."), FAST, |_| {});
+        assert!(matches!(&result, Err(ChatError::Timeout(e)) if e.contains("stopped reading")), "{result:?}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    // --stdio 的管道上也一样：卡在管道写上的写由看门狗的 shutdown 唤醒，不会被锁挡住
+    #[test]
+    fn peer_that_stops_reading_a_stdio_pipe_times_out() {
+        let (reader, _silent) = io::pipe().unwrap();
+        let (_frozen, writer) = io::pipe().unwrap();
+        let ours = transport::duplex(reader, writer).unwrap();
+        let lines: String = (0..64).map(|i| format!("{i} {}\n", "x".repeat(256 * 1024))).collect();
+        let (send_keys, receive_keys) = keys(CipherKind::Chacha20, Role::Client);
+        let started = std::time::Instant::now();
+        let result = run_chat(ours, send_keys, receive_keys, Cursor::new(lines), Transfers::new("."), FAST, |_| {});
+        assert!(matches!(&result, Err(ChatError::Timeout(e)) if e.contains("stopped reading")), "{result:?}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    // 输入结束、写方向已经半关闭之后对方的 Ping 仍会到达：回复 Pong 失败不能让会话出错
+    #[test]
+    fn ping_after_our_input_ends_is_not_an_error() {
+        let (ours, mut peer) = UnixStream::pair().unwrap();
+        let (mut peer_send, mut peer_receive) = keys(CipherKind::Chacha20, Role::Server);
+        let peer = thread::spawn(move || {
+            // 等到我们半关闭（对方读到 EOF），再发一个 Ping
+            while receive_record(&mut peer, &mut peer_receive).unwrap().is_some() {}
+            send_record(&mut peer, &mut peer_send, FrameType::Ping, &1u64.to_be_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(100));
+        });
+        let (send_keys, receive_keys) = keys(CipherKind::Chacha20, Role::Client);
+        let input = Cursor::new("");
+        let result = run_chat(ours, send_keys, receive_keys, input, Transfers::new("."), Timeouts::default(), |_| {});
+        peer.join().unwrap();
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    // 输入 /quit 后立即结束，之后的行不会发出；对方看到连接关闭后也正常返回
+    #[test]
+    fn quit_command_ends_both_sides() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let lines = vec!["bye for now".to_string(), QUIT_COMMAND.to_string(), "never sent".to_string()];
+        let client_peer = chat_peer(client, Role::Client, lines, usize::MAX);
+        let server_peer = chat_peer(server, Role::Server, Vec::new(), usize::MAX);
+
+        assert_eq!(server_peer.join().unwrap(), vec!["bye for now".to_string()]);
+        assert!(client_peer.join().unwrap().is_empty());
+    }
+
+    // 在内存中收发，记录每条消息发送时和接收后的密钥代数
+    fn epochs_over_memory(policy: RekeyPolicy, lines: &[&str]) -> (Vec<u32>, Vec<u32>) {
+        let (send_keys, _) = keys(CipherKind::Chacha20, Role::Client);
+        let mut send_keys = send_keys.with_policy(policy);
+        let (_, mut receive_keys) = keys(CipherKind::Chacha20, Role::Server);
+        let (mut sent, mut received) = (Vec::new(), Vec::new());
+        for line in lines {
+            let mut wire = Vec::new();
+            send_message(&mut wire, &mut send_keys, line.as_bytes()).unwrap();
+            sent.push(send_keys.epoch());
+            let plaintext = receive_message(&mut Cursor::new(wire), &mut receive_keys).unwrap();
+            assert_eq!(plaintext, Some(line.as_bytes().to_vec()));
+            received.push(receive_keys.epoch());
+        }
+        (sent, received)
+    }
+
+    #[test]
+    fn peers_switch_keys_at_the_same_message() {
+        let policy = RekeyPolicy {
+            max_messages: 3,
+            max_bytes: u64::MAX,
+        };
+        let lines = ["m0", "m1", "m2", "m3", "m4", "m5", "m6", "m7", "m8", "m9"];
+        let (sent, received) = epochs_over_memory(policy, &lines);
+        assert_eq!(sent, vec![0, 0, 0, 1, 1, 1, 2, 2, 2, 3]);
+        assert_eq!(received, sent);
+
+        // 按字节数换钥：每 10 字节一代
+        let policy = RekeyPolicy {
+            max_messages: u64::MAX,
+            max_bytes: 10,
+        };
+        let (sent, received) = epochs_over_memory(policy, &["0123456789", "x", "0123456789", "y"]);
+        assert_eq!(sent, vec![0, 1, 1, 2]);
+        assert_eq!(received, sent);
+    }
+
+    // 换钥后旧密钥被覆盖；用旧一代密钥加密的消息再也不会被接受
+    #[test]
+    fn old_keys_are_discarded_after_rekey() {
+        let (mut send_keys, _) = keys(CipherKind::Chacha20, Role::Client);
+        let (_, mut receive_keys) = keys(CipherKind::Chacha20, Role::Server);
+        let original = send_keys.secrets.clone();
+
+        let mut old_frame = Vec::new();
+        send_message(&mut old_frame, &mut send_keys, b"before").unwrap();
+        send_keys.rekey();
+        assert_eq!(send_keys.secrets, original.ratchet());
+        assert_ne!(send_keys.secrets, original);
+
+        let mut new_frame = Vec::new();
+        send_message(&mut new_frame, &mut send_keys, b"after").unwrap();
+        assert_eq!(
+            receive_message(&mut Cursor::new(new_frame), &mut receive_keys),
+            Ok(Some(b"after".to_vec()))
+        );
+        assert_eq!(receive_keys.secrets, send_keys.secrets);
+
+        let replay = receive_message(&mut Cursor::new(old_frame), &mut receive_keys);
+        assert!(replay.unwrap_err().contains("discarded key epoch"));
+    }
+
+    // 伪造的 epoch 无法通过标签验证，接收方不会因此换钥
+    #[test]
+    fn forged_epoch_does_not_advance_the_receiver() {
+        let (mut send_keys, _) = keys(CipherKind::Chacha20, Role::Client);
+        let (_, mut receive_keys) = keys(CipherKind::Chacha20, Role::Server);
+        let mut wire = Vec::new();
+        send_message(&mut wire, &mut send_keys, b"hello").unwrap();
+        wire[frame::HEADER_LEN + EPOCH_LEN - 1] = 1;
+
+        let result = receive_message(&mut Cursor::new(wire), &mut receive_keys);
+        assert_eq!(result, Err("authentication tag mismatch".to_string()));
+        assert_eq!(receive_keys.epoch(), 0);
+    }
+
+    #[test]
+    fn rekey_command_is_followed_by_the_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let lines = vec!["one".to_string(), REKEY_COMMAND.to_string(), "two".to_string()];
+        let client_peer = chat_peer(client, Role::Client, lines, 0);
+        let server_peer = chat_peer(server, Role::Server, Vec::new(), 2);
+
+        assert_eq!(server_peer.join().unwrap(), vec!["one".to_string(), "two".to_string()]);
+        assert!(client_peer.join().unwrap().is_empty());
+    }
+}
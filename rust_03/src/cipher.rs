@@ -0,0 +1,275 @@
+use aes::Aes256;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use clap::ValueEnum;
+
+// 可插拔的流密码后端：每个实现只负责产生密钥流，加解密都是与密钥流异或
+pub trait Keystream: Send {
+    // 生成接下来的 out.len() 个密钥流字节
+    fn fill_keystream(&mut self, out: &mut [u8]);
+
+    // 加密和解密是同一个操作：data ^= keystream。
+    // 返回实际异或进去的密钥流，教学输出展示的就是这一段，不能再另取字节
+    fn apply_keystream(&mut self, data: &mut [u8]) -> Vec<u8> {
+        let mut keystream = vec![0u8; data.len()];
+        self.fill_keystream(&mut keystream);
+        for (byte, key) in data.iter_mut().zip(&keystream) {
+            *byte ^= key;
+        }
+        keystream
+    }
+}
+
+// 命令行可选的密码算法，同时也是握手时交换的算法编号
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CipherKind {
+    Chacha20,
+    AesCtr,
+    // 教学用：只有 32 位状态，可以被轻易预测，不要用于真实通信
+    Lcg,
+}
+
+impl CipherKind {
+    pub fn id(self) -> u8 {
+        match self {
+            CipherKind::Chacha20 => 1,
+            CipherKind::AesCtr => 2,
+            CipherKind::Lcg => 0xFF,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(CipherKind::Chacha20),
+            2 => Some(CipherKind::AesCtr),
+            0xFF => Some(CipherKind::Lcg),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CipherKind::Chacha20 => "chacha20",
+            CipherKind::AesCtr => "aes-ctr",
+            CipherKind::Lcg => "lcg",
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            CipherKind::Chacha20 => "ChaCha20 (RFC 8439, 256-bit key)",
+            CipherKind::AesCtr => "AES-256-CTR (NIST SP 800-38A)",
+            CipherKind::Lcg => "LCG (a=1103515245, c=12345, m=2^32) [TEACHING ONLY - NOT SECURE]",
+        }
+    }
+
+    // 由 256 位密钥和 96 位 nonce 构造密钥流
+    pub fn build(self, key: &[u8; 32], nonce: &[u8; 12]) -> Box<dyn Keystream> {
+        match self {
+            CipherKind::Chacha20 => Box::new(ChaCha20::new(key, nonce)),
+            CipherKind::AesCtr => {
+                // 计数器块 = nonce || 32 位块计数器
+                let mut iv = [0u8; 16];
+                iv[..12].copy_from_slice(nonce);
+                Box::new(AesCtr::new(key, &iv))
+            }
+            // LCG 只有 32 位状态：种子 = 密钥前 4 字节 XOR nonce 后 4 字节，
+            // 这样每条消息（nonce 不同）至少有不同的起点
+            CipherKind::Lcg => {
+                let key = u32::from_be_bytes([key[0], key[1], key[2], key[3]]);
+                let nonce = u32::from_be_bytes([nonce[8], nonce[9], nonce[10], nonce[11]]);
+                Box::new(Lcg::new(key ^ nonce))
+            }
+        }
+    }
+}
+
+pub struct ChaCha20 {
+    inner: chacha20::ChaCha20,
+}
+
+impl ChaCha20 {
+    pub fn new(key: &[u8; 32], nonce: &[u8; 12]) -> Self {
+        ChaCha20 {
+            inner: chacha20::ChaCha20::new(key.into(), nonce.into()),
+        }
+    }
+}
+
+impl Keystream for ChaCha20 {
+    fn fill_keystream(&mut self, out: &mut [u8]) {
+        out.fill(0);
+        self.inner.apply_keystream(out);
+    }
+}
+
+pub struct AesCtr {
+    inner: ctr::Ctr128BE<Aes256>,
+}
+
+impl AesCtr {
+    pub fn new(key: &[u8; 32], iv: &[u8; 16]) -> Self {
+        AesCtr {
+            inner: ctr::Ctr128BE::<Aes256>::new(key.into(), iv.into()),
+        }
+    }
+}
+
+impl Keystream for AesCtr {
+    fn fill_keystream(&mut self, out: &mut [u8]) {
+        out.fill(0);
+        self.inner.apply_keystream(out);
+    }
+}
+
+// LCG 的乘数和增量（与 glibc rand() 相同），模数是 2^32
+pub const LCG_A: u32 = 1103515245;
+pub const LCG_C: u32 = 12345;
+
+// 流密码生成器（线性同余发生器 LCG），仅作教学演示
+pub struct Lcg {
+    a: u32,
+    c: u32,
+    m: u64, // 改为 u64 避免字面量溢出
+    next: u32,
+}
+
+impl Lcg {
+    pub fn new(seed: u32) -> Self {
+        Lcg {
+            a: LCG_A,
+            c: LCG_C,
+            m: 0x100000000, // 2^32，用 u64 存储
+            next: seed,
+        }
+    }
+}
+
+impl Keystream for Lcg {
+    fn fill_keystream(&mut self, out: &mut [u8]) {
+        for byte in out.iter_mut() {
+            self.next = ((self.a as u64 * self.next as u64 + self.c as u64) % self.m) as u32;
+            *byte = (self.next >> 24) as u8; // 取高 8 位作为字节输出
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // RFC 8439 A.1 测试向量 #1：全零密钥和 nonce，计数器从 0 开始
+    #[test]
+    fn chacha20_rfc8439_keystream() {
+        let mut cipher = ChaCha20::new(&[0u8; 32], &[0u8; 12]);
+        let mut keystream = [0u8; 64];
+        cipher.fill_keystream(&mut keystream);
+        assert_eq!(
+            keystream.to_vec(),
+            hex("76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7
+                 da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586")
+        );
+    }
+
+    // RFC 8439 2.4.2：加密示例，计数器从 1 开始（跳过第一个 64 字节块）
+    #[test]
+    fn chacha20_rfc8439_encryption() {
+        let key: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let mut cipher = ChaCha20::new(&key, &nonce);
+        cipher.fill_keystream(&mut [0u8; 64]);
+
+        let mut data = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+        cipher.apply_keystream(&mut data);
+        assert_eq!(
+            data,
+            hex("6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b
+                 f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8
+                 07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736
+                 5af90bbf74a35be6b40b8eedf2785e42874d")
+        );
+    }
+
+    // NIST SP 800-38A F.5.5：CTR-AES256.Encrypt
+    #[test]
+    fn aes_ctr_sp800_38a() {
+        let key: [u8; 32] = hex("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4")
+            .try_into()
+            .unwrap();
+        let iv: [u8; 16] = hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff").try_into().unwrap();
+        let mut cipher = AesCtr::new(&key, &iv);
+
+        let mut data = hex("6bc1bee22e409f96e93d7e117393172a ae2d8a571e03ac9c9eb76fac45af8e51");
+        cipher.apply_keystream(&mut data);
+        assert_eq!(
+            data,
+            hex("601ec313775789a5b7a7f504bbf3d228 f443e3ca4d62b59aca84e990cacaf5c5")
+        );
+    }
+
+    // 分段取密钥流与一次取出的结果必须一致，否则聊天双方会失步
+    #[test]
+    fn keystream_is_position_based() {
+        for kind in [CipherKind::Chacha20, CipherKind::AesCtr, CipherKind::Lcg] {
+            let (key, nonce) = ([0x42u8; 32], [0x24u8; 12]);
+            let mut whole = kind.build(&key, &nonce);
+            let mut parts = kind.build(&key, &nonce);
+            let mut expected = [0u8; 100];
+            whole.fill_keystream(&mut expected);
+            let mut actual = [0u8; 100];
+            parts.fill_keystream(&mut actual[..7]);
+            parts.fill_keystream(&mut actual[7..70]);
+            parts.fill_keystream(&mut actual[70..]);
+            assert_eq!(expected, actual, "{}", kind.name());
+        }
+    }
+
+    // 每条消息的 nonce 不同，密钥流也必须不同（包括 LCG）
+    #[test]
+    fn nonce_changes_the_keystream() {
+        for kind in [CipherKind::Chacha20, CipherKind::AesCtr, CipherKind::Lcg] {
+            let key = [0x42u8; 32];
+            let mut other_nonce = [0u8; 12];
+            other_nonce[11] = 1;
+            let (mut a, mut b) = ([0u8; 32], [0u8; 32]);
+            kind.build(&key, &[0u8; 12]).fill_keystream(&mut a);
+            kind.build(&key, &other_nonce).fill_keystream(&mut b);
+            assert_ne!(a, b, "{}", kind.name());
+        }
+    }
+
+    // 返回的密钥流正是异或进数据的那一段，并且之后的密钥流从它后面接着开始
+    #[test]
+    fn apply_keystream_returns_the_bytes_it_used() {
+        for kind in [CipherKind::Chacha20, CipherKind::AesCtr, CipherKind::Lcg] {
+            let (key, nonce) = ([0x42u8; 32], [0x24u8; 12]);
+            let mut expected = [0u8; 40];
+            kind.build(&key, &nonce).fill_keystream(&mut expected);
+
+            let mut cipher = kind.build(&key, &nonce);
+            let mut data = *b"sixteen byte msg";
+            let used = cipher.apply_keystream(&mut data);
+            assert_eq!(used, expected[..16], "{}", kind.name());
+            let xored: Vec<u8> = b"sixteen byte msg".iter().zip(&used).map(|(p, k)| p ^ k).collect();
+            assert_eq!(xored, data);
+            let mut rest = [0u8; 24];
+            cipher.fill_keystream(&mut rest);
+            assert_eq!(rest, expected[16..], "{}", kind.name());
+        }
+    }
+
+    #[test]
+    fn cipher_ids_round_trip() {
+        for kind in [CipherKind::Chacha20, CipherKind::AesCtr, CipherKind::Lcg] {
+            assert_eq!(CipherKind::from_id(kind.id()), Some(kind));
+        }
+        assert_eq!(CipherKind::from_id(0), None);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::chat::{EPOCH_LEN, SEQ_LEN};
+use crate::cipher::{Keystream, LCG_A, LCG_C, Lcg};
+use crate::frame::{self, FrameType};
+use crate::mac::TAG_LEN;
+use std::io::Read;
+
+// 对 LCG 密钥流的已知明文攻击（教学演示）。
+//
+// LCG 每一步输出状态的最高 8 位，状态只有 32 位：知道第一个密钥流字节后，
+// 只剩低 24 位未知，穷举 2^24 种可能并用后面几个已知字节筛选即可还原完整状态。
+// 每条消息的种子 = 密钥前 4 字节 ^ nonce 后 4 字节 ^ 序列号，前两项对整个 epoch 不变，
+// 所以破解出一条消息的种子，同一方向、同一 epoch 的所有消息都能解密
+
+// 截获的一条加密记录（线路上的原样字段，标签已去掉）
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CapturedRecord {
+    pub kind: FrameType,
+    pub epoch: u32,
+    pub seq: u64,
+    pub ciphertext: Vec<u8>,
+}
+
+// 解析一个方向上截获的原始字节流（例如从抓包工具中导出的单向 TCP 数据）。
+// 开头的明文握手帧被跳过，其余每一帧拆出 epoch、序列号和密文
+pub fn parse_capture(reader: &mut impl Read) -> Result<Vec<CapturedRecord>, String> {
+    let mut records = Vec::new();
+    while let Some(frame) = frame::read_frame(reader).map_err(|e| format!("reading the capture: {e}"))? {
+        if frame.kind == FrameType::Handshake {
+            continue;
+        }
+        let payload = &frame.payload;
+        if payload.len() < EPOCH_LEN + SEQ_LEN + TAG_LEN {
+            return Err(format!("{:?} frame too short for a record ({} bytes)", frame.kind, payload.len()));
+        }
+        let (epoch, rest) = payload.split_at(EPOCH_LEN);
+        let (seq, rest) = rest.split_at(SEQ_LEN);
+        records.push(CapturedRecord {
+            kind: frame.kind,
+            epoch: u32::from_be_bytes(epoch.try_into().expect("split at EPOCH_LEN")),
+            seq: u64::from_be_bytes(seq.try_into().expect("split at SEQ_LEN")),
+            ciphertext: rest[..rest.len() - TAG_LEN].to_vec(),
+        });
+    }
+    Ok(records)
+}
+
+// 2^32 的模逆：a 是奇数所以一定存在，用牛顿迭代每次把正确的位数翻倍
+fn inverse(a: u32) -> u32 {
+    let mut inv = a;
+    for _ in 0..5 {
+        inv = inv.wrapping_mul(2u32.wrapping_sub(a.wrapping_mul(inv)));
+    }
+    inv
+}
+
+// 找出所有能产生这段密钥流的种子。第一个字节固定了第一个状态的高 8 位，
+// 只需穷举低 24 位；已知字节越多，剩下的候选越少（一般 4 个字节就只剩一个）
+pub fn recover_seeds(keystream: &[u8]) -> Vec<u32> {
+    let Some((&first, rest)) = keystream.split_first() else {
+        return Vec::new();
+    };
+    let a_inv = inverse(LCG_A);
+    (0..1u32 << 24)
+        .map(|low| (first as u32) << 24 | low)
+        .filter(|&state| {
+            let mut next = state;
+            rest.iter().all(|&byte| {
+                next = next.wrapping_mul(LCG_A).wrapping_add(LCG_C);
+                (next >> 24) as u8 == byte
+            })
+        })
+        .map(|state| state.wrapping_sub(LCG_C).wrapping_mul(a_inv))
+        .collect()
+}
+
+// 破解结果：每条记录在同一 epoch 内就能解密，其他 epoch 的密钥已经换过，得到 None
+#[derive(Debug)]
+pub struct Cracked {
+    pub epoch: u32,
+    // 与序列号无关的部分：密钥前 4 字节 ^ nonce 后 4 字节
+    pub base_seed: u32,
+    pub plaintexts: Vec<Option<Vec<u8>>>,
+}
+
+// 用第 seq 条记录的已知明文开头破解整个方向
+pub fn crack(records: &[CapturedRecord], seq: u64, known: &[u8]) -> Result<Cracked, String> {
+    let target = records
+        .iter()
+        .find(|record| record.seq == seq)
+        .ok_or_else(|| format!("message #{seq} is not in the capture"))?;
+    if known.len() > target.ciphertext.len() {
+        return Err(format!(
+            "known plaintext is {} bytes but message #{seq} only has {}",
+            known.len(),
+            target.ciphertext.len()
+        ));
+    }
+    let keystream: Vec<u8> = known.iter().zip(&target.ciphertext).map(|(p, c)| p ^ c).collect();
+    let seed = match recover_seeds(&keystream)[..] {
+        [seed] => seed,
+        [] => return Err(format!("no LCG state produces this keystream; was message #{seq} really LCG-encrypted?")),
+        ref seeds => {
+            return Err(format!(
+                "{} candidate states remain; give more known plaintext bytes",
+                seeds.len()
+            ));
+        }
+    };
+    // 种子 = base ^ 序列号低 32 位（与 chat 模块里 nonce 的构造方式一致）
+    let base_seed = seed ^ seq as u32;
+    let plaintexts = records
+        .iter()
+        .map(|record| {
+            (record.epoch == target.epoch).then(|| {
+                let mut plaintext = record.ciphertext.clone();
+                Lcg::new(base_seed ^ record.seq as u32).apply_keystream(&mut plaintext);
+                plaintext
+            })
+        })
+        .collect();
+    Ok(Cracked { epoch: target.epoch, base_seed, plaintexts })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{self, DirectionKeys, RekeyPolicy};
+    use crate::cipher::CipherKind;
+    use crate::frame::Frame;
+    use crate::handshake::Role;
+    use crate::kdf::SessionKeys;
+
+    // 完全离线：用 LCG 会话密钥加密一段对话，写成线路上的字节流，再只凭已知明文破解
+    fn captured_conversation(messages: &[&str], policy: RekeyPolicy) -> Vec<u8> {
+        let session = SessionKeys::derive(b"secret the attacker never sees", &[7u8; 32]);
+        let mut keys = DirectionKeys::new(CipherKind::Lcg, session.sending(Role::Client)).with_policy(policy);
+        let mut wire = Vec::new();
+        frame::write_frame(&mut wire, &Frame::new(FrameType::Handshake, b"hello".to_vec())).unwrap();
+        for msg in messages {
+            chat::send_message(&mut wire, &mut keys, msg.as_bytes()).unwrap();
+        }
+        wire
+    }
+
+    #[test]
+    fn recovers_the_seed_from_a_few_keystream_bytes() {
+        let seed = 0xDEADBEEF;
+        let mut keystream = [0u8; 6];
+        Lcg::new(seed).fill_keystream(&mut keystream);
+        assert_eq!(recover_seeds(&keystream), vec![seed]);
+        // 只有一个字节时还有大量候选，真正的种子一定在其中
+        let candidates = recover_seeds(&keystream[..1]);
+        assert!(candidates.len() > 1 && candidates.contains(&seed));
+    }
+
+    #[test]
+    fn known_plaintext_decrypts_the_rest_of_the_conversation() {
+        let messages = ["hello bob", "the vault code is 4711", "see you at noon"];
+        let wire = captured_conversation(&messages, RekeyPolicy::default());
+        let records = parse_capture(&mut wire.as_slice()).unwrap();
+        assert_eq!(records.len(), messages.len());
+
+        let cracked = crack(&records, 0, b"hello").unwrap();
+        let plaintexts: Vec<_> = cracked.plaintexts.into_iter().map(Option::unwrap).collect();
+        assert_eq!(plaintexts, messages.map(|m| m.as_bytes().to_vec()));
+    }
+
+    // 换钥之后密钥经过 HKDF 棘轮，已知明文只能攻破它所在的 epoch
+    #[test]
+    fn other_epochs_need_their_own_known_plaintext() {
+        let policy = RekeyPolicy { max_messages: 2, ..RekeyPolicy::default() };
+        let wire = captured_conversation(&["hello one", "hello two", "hello three"], policy);
+        let records = parse_capture(&mut wire.as_slice()).unwrap();
+        assert_eq!(records[2].epoch, 1);
+
+        let cracked = crack(&records, 1, b"hello").unwrap();
+        assert_eq!(cracked.plaintexts[0].as_deref(), Some(&b"hello one"[..]));
+        assert_eq!(cracked.plaintexts[2], None);
+        let cracked = crack(&records, 2, b"hello").unwrap();
+        assert_eq!(cracked.plaintexts[2].as_deref(), Some(&b"hello three"[..]));
+    }
+
+    #[test]
+    fn too_little_known_plaintext_is_ambiguous() {
+        let wire = captured_conversation(&["hello"], RekeyPolicy::default());
+        let records = parse_capture(&mut wire.as_slice()).unwrap();
+        assert!(crack(&records, 0, b"h").unwrap_err().contains("candidate states"));
+        assert!(crack(&records, 5, b"hello").unwrap_err().contains("not in the capture"));
+    }
+}
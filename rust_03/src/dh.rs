@@ -0,0 +1,589 @@
+use crate::montgomery::{self, Montgomery};
+use crate::prime;
+use crate::secret::Secret;
+use clap::ValueEnum;
+use num_bigint::BigUint;
+use rand::Rng;
+
+// 可选的 DH 群：RFC 3526 MODP 群和 RFC 7919 FFDHE 群，生成元都是 2，p 都是安全素数
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DhGroup {
+    Modp2048,
+    Modp3072,
+    Modp4096,
+    Ffdhe2048,
+    Ffdhe3072,
+    Ffdhe4096,
+    // 教学用：64 位安全素数，几秒钟就能求离散对数，不要用于真实通信
+    Toy64,
+    // genparams 生成的参数：服务器用 --params 加载并在握手中发送，客户端按策略检查后使用
+    Custom,
+}
+
+impl DhGroup {
+    pub fn id(self) -> u8 {
+        match self {
+            DhGroup::Modp2048 => 14,
+            DhGroup::Modp3072 => 15,
+            DhGroup::Modp4096 => 16,
+            DhGroup::Ffdhe2048 => 0x20,
+            DhGroup::Ffdhe3072 => 0x21,
+            DhGroup::Ffdhe4096 => 0x22,
+            DhGroup::Toy64 => 0xFF,
+            DhGroup::Custom => 0xFE,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            14 => Some(DhGroup::Modp2048),
+            15 => Some(DhGroup::Modp3072),
+            16 => Some(DhGroup::Modp4096),
+            0x20 => Some(DhGroup::Ffdhe2048),
+            0x21 => Some(DhGroup::Ffdhe3072),
+            0x22 => Some(DhGroup::Ffdhe4096),
+            0xFF => Some(DhGroup::Toy64),
+            0xFE => Some(DhGroup::Custom),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DhGroup::Modp2048 => "modp2048",
+            DhGroup::Modp3072 => "modp3072",
+            DhGroup::Modp4096 => "modp4096",
+            DhGroup::Ffdhe2048 => "ffdhe2048",
+            DhGroup::Ffdhe3072 => "ffdhe3072",
+            DhGroup::Ffdhe4096 => "ffdhe4096",
+            DhGroup::Toy64 => "toy64",
+            DhGroup::Custom => "custom",
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            DhGroup::Modp2048 => "2048-bit MODP group 14 (RFC 3526)",
+            DhGroup::Modp3072 => "3072-bit MODP group 15 (RFC 3526)",
+            DhGroup::Modp4096 => "4096-bit MODP group 16 (RFC 3526)",
+            DhGroup::Ffdhe2048 => "ffdhe2048 (RFC 7919)",
+            DhGroup::Ffdhe3072 => "ffdhe3072 (RFC 7919)",
+            DhGroup::Ffdhe4096 => "ffdhe4096 (RFC 7919)",
+            DhGroup::Toy64 => "64-bit safe prime [TEACHING ONLY - NOT SECURE]",
+            DhGroup::Custom => "custom safe-prime group (genparams)",
+        }
+    }
+
+    // 标准群的 p 和 g；Custom 的参数不是常量，由服务器在握手中发送
+    pub fn params(self) -> Option<DhParams> {
+        let hex = match self {
+            DhGroup::Modp2048 => MODP2048_P,
+            DhGroup::Modp3072 => MODP3072_P,
+            DhGroup::Modp4096 => MODP4096_P,
+            DhGroup::Ffdhe2048 => FFDHE2048_P,
+            DhGroup::Ffdhe3072 => FFDHE3072_P,
+            DhGroup::Ffdhe4096 => FFDHE4096_P,
+            DhGroup::Toy64 => TOY64_P,
+            DhGroup::Custom => return None,
+        };
+        Some(DhParams {
+            p: BigUint::parse_bytes(hex.as_bytes(), 16).expect("group primes are valid hex"),
+            g: BigUint::from(2u32),
+        })
+    }
+}
+
+// 一组 DH 参数：安全素数 p 和生成元 g
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DhParams {
+    pub p: BigUint,
+    pub g: BigUint,
+}
+
+impl DhParams {
+    // 生成 bits 位的安全素数 p = 2q + 1，并选一个生成 q 阶子群的 g。
+    // p ≡ 7 (mod 8) 时 2 是二次剩余，直接用 2；否则用 4 = 2^2，它总是二次剩余
+    pub fn generate(bits: u64, on_candidate: impl FnMut()) -> Self {
+        let p = prime::generate_safe_prime(bits, on_candidate);
+        let g = if small_mod(&p, 8) == 7 { 2u32 } else { 4 };
+        DhParams { p, g: BigUint::from(g) }
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.p.bits()
+    }
+
+    // 公钥和共享密钥在线路上的固定长度（字节）
+    pub fn byte_len(&self) -> usize {
+        self.p.bits().div_ceil(8) as usize
+    }
+
+    // 检查参数本身是否可用：p 是安全素数，并且 1 < g < p-1。
+    // 安全素数下除了 1 和 p-1（阶为 1 和 2）以外，每个元素的阶都是 q 或 2q，
+    // 所以这两个条件就排除了小子群攻击
+    pub fn verify(&self) -> Result<(), String> {
+        if !prime::is_safe_prime(&self.p, prime::MR_ROUNDS) {
+            return Err("p is not a safe prime".to_string());
+        }
+        if self.g <= BigUint::from(1u32) || self.g >= &self.p - 1u32 {
+            return Err("g must satisfy 1 < g < p-1 to generate a large subgroup".to_string());
+        }
+        Ok(())
+    }
+
+    // 握手中的编码：len(p) u16 | p | len(g) u16 | g，均为大端
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for n in [&self.p, &self.g] {
+            let bytes = n.to_bytes_be();
+            out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+            out.extend_from_slice(&bytes);
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let malformed = || format!("malformed DH parameters ({} bytes)", bytes.len());
+        let mut rest = bytes;
+        let mut next = || {
+            let (len, tail) = rest.split_first_chunk::<2>().ok_or_else(malformed)?;
+            let len = u16::from_be_bytes(*len) as usize;
+            let (value, tail) = tail.split_at_checked(len).ok_or_else(malformed)?;
+            rest = tail;
+            Ok::<_, String>(BigUint::from_bytes_be(value))
+        };
+        let (p, g) = (next()?, next()?);
+        if !rest.is_empty() {
+            return Err(malformed());
+        }
+        Ok(DhParams { p, g })
+    }
+
+    // genparams 写出的文本格式，和 known_peers 一样每行一个 "名字 十六进制值"
+    pub fn to_text(&self) -> String {
+        format!(
+            "# rust_03 DH parameters: {}-bit safe prime p = 2q + 1, generator g\np {:X}\ng {:X}\n",
+            self.bits(),
+            self.p,
+            self.g
+        )
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (mut p, mut g) = (None, None);
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let (name, value) = line.split_once(' ').ok_or_else(|| format!("malformed line: {line}"))?;
+            let value = BigUint::parse_bytes(value.trim().as_bytes(), 16)
+                .ok_or_else(|| format!("{name} is not a hex number"))?;
+            match name {
+                "p" => p = Some(value),
+                "g" => g = Some(value),
+                other => return Err(format!("unknown parameter {other}")),
+            }
+        }
+        Ok(DhParams {
+            p: p.ok_or("missing p")?,
+            g: g.ok_or("missing g")?,
+        })
+    }
+}
+
+fn small_mod(n: &BigUint, m: u32) -> u32 {
+    (n % m).to_u32_digits().first().copied().unwrap_or(0)
+}
+
+// genparams 能生成的最大参数，也是默认接受的上限
+pub const MAX_BITS: u64 = 8192;
+
+// 客户端接受服务器自定义参数的条件
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DhPolicy {
+    pub min_bits: u64,
+    // 素性检测的耗时随位数急剧增长：恶意服务器发来几十万位的 p 就能让客户端长时间空转，读超时也管不到
+    pub max_bits: u64,
+}
+
+impl Default for DhPolicy {
+    fn default() -> Self {
+        DhPolicy { min_bits: 2048, max_bits: MAX_BITS }
+    }
+}
+
+impl DhPolicy {
+    // 长度检查在素性检测之前，过大的参数不会进入 Miller-Rabin
+    pub fn check(&self, params: &DhParams) -> Result<(), String> {
+        if params.bits() < self.min_bits {
+            return Err(format!(
+                "{}-bit DH parameters are below the required {} bits",
+                params.bits(),
+                self.min_bits
+            ));
+        }
+        if params.bits() > self.max_bits {
+            return Err(format!(
+                "{}-bit DH parameters are above the supported {} bits",
+                params.bits(),
+                self.max_bits
+            ));
+        }
+        params.verify()
+    }
+
+    // 标准群是固定的常量，只需要检查长度：toy64 这样的教学用小群必须显式降低 min_bits 才能使用
+    pub fn check_group(&self, group: DhGroup) -> Result<(), String> {
+        match group.params() {
+            Some(params) if params.bits() < self.min_bits => Err(format!(
+                "the {} group has only {} bits, below the required {} (lower --min-dh-bits to use it)",
+                group.name(),
+                params.bits(),
+                self.min_bits
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+// 一次握手用的临时 DH 密钥对。私钥以定长 limb 保存在 Secret 里，离开作用域时清零
+pub struct DhKeypair {
+    params: DhParams,
+    ladder: Montgomery,
+    private_key: Secret<Vec<u64>>,
+    public_key: BigUint,
+}
+
+impl DhKeypair {
+    // 私钥在 [2, p-2] 中均匀选取：取和 p 同样位数的随机数，不在范围内就重新抽取（拒绝采样）。
+    // 只有被丢弃的候选会影响耗时，最终私钥的值不会
+    pub fn generate(params: &DhParams) -> Self {
+        let ladder = Montgomery::new(&params.p);
+        let n = ladder.limbs();
+        let upper = montgomery::to_limbs(&(&params.p - 1u32), n);
+        let two = montgomery::to_limbs(&BigUint::from(2u32), n);
+        let top_mask = u64::MAX >> (n as u64 * 64 - params.bits());
+        let mut private_key = Secret::new(vec![0u64; n]);
+        loop {
+            rand::rng().fill(&mut private_key[..]);
+            private_key[n - 1] &= top_mask;
+            if montgomery::less_than(&private_key, &upper) && !montgomery::less_than(&private_key, &two) {
+                break;
+            }
+        }
+        let public_key = montgomery::to_be_bytes(&ladder.pow(&params.g, &private_key), params.byte_len());
+        DhKeypair {
+            params: params.clone(),
+            ladder,
+            private_key,
+            public_key: BigUint::from_bytes_be(&public_key),
+        }
+    }
+
+    // 私钥的大端编码，只用于 --teach 的输出
+    pub fn private_bytes(&self) -> Secret<Vec<u8>> {
+        Secret::new(montgomery::to_be_bytes(&self.private_key, self.params.byte_len()))
+    }
+
+    pub fn public_key(&self) -> &BigUint {
+        &self.public_key
+    }
+
+    pub fn public_bytes(&self) -> Vec<u8> {
+        to_fixed_bytes(&self.public_key, self.params.byte_len())
+    }
+
+    // 计算共享密钥：their_public^private mod p，先检查对方公钥的范围
+    pub fn shared_secret(&self, their_public: &[u8]) -> Result<Secret<Vec<u8>>, String> {
+        let their_public = parse_public(&self.params, their_public)?;
+        let secret = self.ladder.pow(&their_public, &self.private_key);
+        Ok(Secret::new(montgomery::to_be_bytes(&secret, self.params.byte_len())))
+    }
+}
+
+// 对方公钥必须满足 1 < y < p-1：0、1 和 p-1 会把共享密钥限制在 {0, 1, p-1} 中
+pub fn parse_public(params: &DhParams, bytes: &[u8]) -> Result<BigUint, String> {
+    if bytes.len() != params.byte_len() {
+        return Err(format!(
+            "public key has {} bytes, expected {}",
+            bytes.len(),
+            params.byte_len()
+        ));
+    }
+    let y = BigUint::from_bytes_be(bytes);
+    if y <= BigUint::from(1u32) || y >= &params.p - 1u32 {
+        return Err("public key out of range (must satisfy 1 < y < p-1)".to_string());
+    }
+    Ok(y)
+}
+
+// 大端编码并左侧补零到固定长度
+fn to_fixed_bytes(n: &BigUint, len: usize) -> Vec<u8> {
+    let bytes = n.to_bytes_be();
+    let mut out = vec![0u8; len - bytes.len()];
+    out.extend_from_slice(&bytes);
+    out
+}
+
+// 2^64 - 1469，小于 2^64 的最大安全素数
+const TOY64_P: &str = "FFFFFFFFFFFFFA43";
+
+const MODP2048_P: &str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AACAA68FFFFFFFFFFFFFFFF",
+);
+
+const MODP3072_P: &str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33",
+    "A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7",
+    "ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864",
+    "D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2",
+    "08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A93AD2CAFFFFFFFFFFFFFFFF",
+);
+
+const MODP4096_P: &str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33",
+    "A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7",
+    "ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864",
+    "D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2",
+    "08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A92108011A723C12A787E6D7",
+    "88719A10BDBA5B2699C327186AF4E23C1A946834B6150BDA2583E9CA2AD44CE8",
+    "DBBBC2DB04DE8EF92E8EFC141FBECAA6287C59474E6BC05D99B2964FA090C3A2",
+    "233BA186515BE7ED1F612970CEE2D7AFB81BDD762170481CD0069127D5B05AA9",
+    "93B4EA988D8FDDC186FFB7DC90A6C08F4DF435C934063199FFFFFFFFFFFFFFFF",
+);
+
+const FFDHE2048_P: &str = concat!(
+    "FFFFFFFFFFFFFFFFADF85458A2BB4A9AAFDC5620273D3CF1D8B9C583CE2D3695",
+    "A9E13641146433FBCC939DCE249B3EF97D2FE363630C75D8F681B202AEC4617A",
+    "D3DF1ED5D5FD65612433F51F5F066ED0856365553DED1AF3B557135E7F57C935",
+    "984F0C70E0E68B77E2A689DAF3EFE8721DF158A136ADE73530ACCA4F483A797A",
+    "BC0AB182B324FB61D108A94BB2C8E3FBB96ADAB760D7F4681D4F42A3DE394DF4",
+    "AE56EDE76372BB190B07A7C8EE0A6D709E02FCE1CDF7E2ECC03404CD28342F61",
+    "9172FE9CE98583FF8E4F1232EEF28183C3FE3B1B4C6FAD733BB5FCBC2EC22005",
+    "C58EF1837D1683B2C6F34A26C1B2EFFA886B423861285C97FFFFFFFFFFFFFFFF",
+);
+
+const FFDHE3072_P: &str = concat!(
+    "FFFFFFFFFFFFFFFFADF85458A2BB4A9AAFDC5620273D3CF1D8B9C583CE2D3695",
+    "A9E13641146433FBCC939DCE249B3EF97D2FE363630C75D8F681B202AEC4617A",
+    "D3DF1ED5D5FD65612433F51F5F066ED0856365553DED1AF3B557135E7F57C935",
+    "984F0C70E0E68B77E2A689DAF3EFE8721DF158A136ADE73530ACCA4F483A797A",
+    "BC0AB182B324FB61D108A94BB2C8E3FBB96ADAB760D7F4681D4F42A3DE394DF4",
+    "AE56EDE76372BB190B07A7C8EE0A6D709E02FCE1CDF7E2ECC03404CD28342F61",
+    "9172FE9CE98583FF8E4F1232EEF28183C3FE3B1B4C6FAD733BB5FCBC2EC22005",
+    "C58EF1837D1683B2C6F34A26C1B2EFFA886B4238611FCFDCDE355B3B6519035B",
+    "BC34F4DEF99C023861B46FC9D6E6C9077AD91D2691F7F7EE598CB0FAC186D91C",
+    "AEFE130985139270B4130C93BC437944F4FD4452E2D74DD364F2E21E71F54BFF",
+    "5CAE82AB9C9DF69EE86D2BC522363A0DABC521979B0DEADA1DBF9A42D5C4484E",
+    "0ABCD06BFA53DDEF3C1B20EE3FD59D7C25E41D2B66C62E37FFFFFFFFFFFFFFFF",
+);
+
+const FFDHE4096_P: &str = concat!(
+    "FFFFFFFFFFFFFFFFADF85458A2BB4A9AAFDC5620273D3CF1D8B9C583CE2D3695",
+    "A9E13641146433FBCC939DCE249B3EF97D2FE363630C75D8F681B202AEC4617A",
+    "D3DF1ED5D5FD65612433F51F5F066ED0856365553DED1AF3B557135E7F57C935",
+    "984F0C70E0E68B77E2A689DAF3EFE8721DF158A136ADE73530ACCA4F483A797A",
+    "BC0AB182B324FB61D108A94BB2C8E3FBB96ADAB760D7F4681D4F42A3DE394DF4",
+    "AE56EDE76372BB190B07A7C8EE0A6D709E02FCE1CDF7E2ECC03404CD28342F61",
+    "9172FE9CE98583FF8E4F1232EEF28183C3FE3B1B4C6FAD733BB5FCBC2EC22005",
+    "C58EF1837D1683B2C6F34A26C1B2EFFA886B4238611FCFDCDE355B3B6519035B",
+    "BC34F4DEF99C023861B46FC9D6E6C9077AD91D2691F7F7EE598CB0FAC186D91C",
+    "AEFE130985139270B4130C93BC437944F4FD4452E2D74DD364F2E21E71F54BFF",
+    "5CAE82AB9C9DF69EE86D2BC522363A0DABC521979B0DEADA1DBF9A42D5C4484E",
+    "0ABCD06BFA53DDEF3C1B20EE3FD59D7C25E41D2B669E1EF16E6F52C3164DF4FB",
+    "7930E9E4E58857B6AC7D5F42D69F6D187763CF1D5503400487F55BA57E31CC7A",
+    "7135C886EFB4318AED6A1E012D9E6832A907600A918130C46DC778F971AD0038",
+    "092999A333CB8B7A1A1DB93D7140003C2A4ECEA9F98D0ACC0A8291CDCEC97DCF",
+    "8EC9B55A7F88A46B4DB5A851F44182E1C68A007E5E655F6AFFFFFFFFFFFFFFFF",
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [DhGroup; 7] = [
+        DhGroup::Modp2048,
+        DhGroup::Modp3072,
+        DhGroup::Modp4096,
+        DhGroup::Ffdhe2048,
+        DhGroup::Ffdhe3072,
+        DhGroup::Ffdhe4096,
+        DhGroup::Toy64,
+    ];
+
+    #[test]
+    fn group_sizes() {
+        let bits: Vec<u64> = ALL.iter().map(|g| g.params().unwrap().bits()).collect();
+        assert_eq!(bits, [2048, 3072, 4096, 2048, 3072, 4096, 64]);
+        for group in ALL {
+            assert_eq!(DhGroup::from_id(group.id()), Some(group));
+            // RFC 3526 / RFC 7919 的素数都以 64 个 1 开头和结尾
+            let p = group.params().unwrap().p;
+            if group != DhGroup::Toy64 {
+                assert_eq!(&p % (BigUint::from(1u32) << 64u32), BigUint::from(u64::MAX));
+            }
+        }
+    }
+
+    // Fermat 检验：p 和 q = (p-1)/2 都应是（概率）素数
+    #[test]
+    fn groups_use_safe_primes() {
+        for group in [DhGroup::Modp2048, DhGroup::Ffdhe2048, DhGroup::Toy64] {
+            let p = group.params().unwrap().p;
+            let q = (&p - 1u32) >> 1u32;
+            for n in [&p, &q] {
+                for a in [2u32, 3, 5, 7] {
+                    let a = BigUint::from(a);
+                    assert_eq!(a.modpow(&(n - 1u32), n), BigUint::from(1u32), "{}", group.name());
+                }
+            }
+        }
+    }
+
+    // 密钥对的两次模幂都走 Montgomery ladder，结果和库的 modpow 一致
+    #[test]
+    fn keypair_matches_library_modpow() {
+        let params = DhGroup::Modp2048.params().unwrap();
+        let keypair = DhKeypair::generate(&params);
+        let x = BigUint::from_bytes_be(&keypair.private_bytes());
+        assert_eq!(*keypair.public_key(), params.g.modpow(&x, &params.p));
+
+        let theirs = BigUint::from(0xDEADBEEFu32);
+        let shared = keypair.shared_secret(&to_fixed_bytes(&theirs, params.byte_len())).unwrap();
+        assert_eq!(BigUint::from_bytes_be(&shared), theirs.modpow(&x, &params.p));
+    }
+
+    #[test]
+    fn both_sides_agree_on_secret() {
+        for group in [DhGroup::Ffdhe2048, DhGroup::Toy64] {
+            let params = group.params().unwrap();
+            let alice = DhKeypair::generate(&params);
+            let bob = DhKeypair::generate(&params);
+            let a = alice.shared_secret(&bob.public_bytes()).unwrap();
+            let b = bob.shared_secret(&alice.public_bytes()).unwrap();
+            assert_eq!(a[..], b[..]);
+            assert_eq!(a.len(), params.byte_len());
+        }
+    }
+
+    // 拒绝采样得到的私钥总在 [2, p-2] 中，公钥就是 g^x mod p
+    #[test]
+    fn private_keys_are_in_range() {
+        let params = DhParams { p: BigUint::from(0xFFEFu32), g: BigUint::from(4u32) };
+        for _ in 0..200 {
+            let keypair = DhKeypair::generate(&params);
+            let x = BigUint::from_bytes_be(&keypair.private_bytes());
+            assert!(x >= BigUint::from(2u32) && x <= &params.p - 2u32, "{x}");
+            assert_eq!(*keypair.public_key(), params.g.modpow(&x, &params.p));
+        }
+    }
+
+    #[test]
+    fn rejects_degenerate_public_keys() {
+        let params = DhGroup::Modp2048.params().unwrap();
+        let keypair = DhKeypair::generate(&params);
+        let p = &params.p;
+        for bad in [BigUint::ZERO, BigUint::from(1u32), p - 1u32, p.clone(), p + 1u32] {
+            let bytes = if bad.bits() as usize > params.byte_len() * 8 {
+                bad.to_bytes_be()
+            } else {
+                to_fixed_bytes(&bad, params.byte_len())
+            };
+            assert!(keypair.shared_secret(&bytes).is_err(), "accepted {bad}");
+        }
+        // 长度不对的公钥（例如截断的消息）也要拒绝
+        assert!(keypair.shared_secret(&keypair.public_bytes()[1..]).is_err());
+        assert!(keypair.shared_secret(&[2u8; 8]).is_err());
+    }
+
+    // 标准群也能通过客户端对自定义参数做的检查（大群的素性已由上面的 Fermat 检验覆盖）
+    #[test]
+    fn standard_groups_pass_the_parameter_checks() {
+        DhGroup::Toy64.params().unwrap().verify().unwrap();
+        assert_eq!(DhGroup::Custom.params(), None);
+        assert_eq!(DhGroup::from_id(DhGroup::Custom.id()), Some(DhGroup::Custom));
+    }
+
+    #[test]
+    fn generated_params_round_trip_and_verify() {
+        let params = DhParams::generate(64, || {});
+        assert_eq!(params.bits(), 64);
+        params.verify().unwrap();
+        assert_eq!(DhParams::parse(&params.to_text()), Ok(params.clone()));
+        assert_eq!(DhParams::decode(&params.encode()), Ok(params.clone()));
+        assert!(DhParams::decode(&params.encode()[1..]).is_err());
+
+        let alice = DhKeypair::generate(&params);
+        let bob = DhKeypair::generate(&params);
+        assert_eq!(
+            alice.shared_secret(&bob.public_bytes()).unwrap()[..],
+            bob.shared_secret(&alice.public_bytes()).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn policy_rejects_small_or_broken_params() {
+        let params = DhParams::generate(64, || {});
+        assert!(DhPolicy::default().check(&params).unwrap_err().contains("below"));
+        let policy = DhPolicy { min_bits: 64, ..DhPolicy::default() };
+        policy.check(&params).unwrap();
+
+        // p 是素数但不是安全素数：p - 1 有很多小因子
+        let not_safe = DhParams { p: BigUint::from(0xFFFFFFFFFFFFFFC5u64), g: BigUint::from(2u32) };
+        assert!(policy.check(&not_safe).unwrap_err().contains("safe prime"));
+        // g = p - 1 只生成 {1, p-1}
+        let weak_g = DhParams { g: &params.p - 1u32, ..params.clone() };
+        assert!(policy.check(&weak_g).unwrap_err().contains("1 < g < p-1"));
+    }
+
+    // 线路格式允许约 50 万位的 p：超过上限的参数直接拒绝，不做素性检测（否则这个测试要跑上几个小时）
+    #[test]
+    fn oversize_params_are_rejected_before_the_primality_check() {
+        let huge = DhParams { p: (BigUint::from(1u32) << 400_000u32) - 1u32, g: BigUint::from(2u32) };
+        let decoded = DhParams::decode(&huge.encode()).unwrap();
+        let error = DhPolicy::default().check(&decoded).unwrap_err();
+        assert!(error.contains("above the supported 8192 bits"), "{error}");
+        let barely = DhParams { p: (BigUint::from(1u32) << MAX_BITS) + 1u32, g: BigUint::from(2u32) };
+        assert!(DhPolicy::default().check(&barely).unwrap_err().contains("above"));
+    }
+
+    // 长度为 0 的 p 解码出来是 0；最小长度设成 0 时也必须被拒绝，而不是在计算 p-1 时崩溃
+    #[test]
+    fn zero_modulus_is_rejected() {
+        let params = DhParams::decode(&[0, 0, 0, 1, 2]).unwrap();
+        assert_eq!(params.p, BigUint::from(0u32));
+        let any_size = DhPolicy { min_bits: 0, ..DhPolicy::default() };
+        assert!(any_size.check(&params).unwrap_err().contains("safe prime"));
+        let four = DhParams { p: BigUint::from(4u32), g: BigUint::from(2u32) };
+        assert!(four.verify().is_err());
+    }
+
+    // 标准群同样受最小长度约束：toy64 只有显式放宽之后才能使用
+    #[test]
+    fn policy_applies_to_standard_groups() {
+        assert!(DhPolicy::default().check_group(DhGroup::Toy64).unwrap_err().contains("toy64"));
+        DhPolicy::default().check_group(DhGroup::Ffdhe2048).unwrap();
+        DhPolicy { min_bits: 64, ..DhPolicy::default() }.check_group(DhGroup::Toy64).unwrap();
+        assert!(DhPolicy { min_bits: 3072, ..DhPolicy::default() }.check_group(DhGroup::Modp2048).is_err());
+        // 自定义群的长度在收到参数后由 check 检查
+        DhPolicy::default().check_group(DhGroup::Custom).unwrap();
+    }
+}
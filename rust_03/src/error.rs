@@ -0,0 +1,103 @@
+use crate::frame::VersionMismatch;
+use std::fmt;
+use std::io;
+
+// 程序中所有会导致会话终止的错误。每一类都有自己的退出码，方便脚本区分：
+//   2 = 参数不合法（与 clap 的用法错误一致），3 = 网络或文件 I/O，
+//   4 = 握手失败，5 = 协议版本不兼容，6 = 身份认证失败，7 = 对方超时无响应
+#[derive(Debug)]
+pub enum ChatError {
+    Usage(String),
+    Io { context: String, source: io::Error },
+    Handshake(String),
+    ProtocolVersion { expected: u8, received: u8 },
+    Authentication(String),
+    Timeout(String),
+}
+
+impl ChatError {
+    // 用法：.map_err(ChatError::io("connecting to 127.0.0.1:8080"))
+    pub fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> ChatError {
+        let context = context.into();
+        move |source| match source.get_ref().and_then(|inner| inner.downcast_ref::<VersionMismatch>()) {
+            Some(mismatch) => ChatError::ProtocolVersion {
+                expected: mismatch.expected,
+                received: mismatch.received,
+            },
+            None => ChatError::Io { context, source },
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ChatError::Usage(_) => 2,
+            ChatError::Io { .. } => 3,
+            ChatError::Handshake(_) => 4,
+            ChatError::ProtocolVersion { .. } => 5,
+            ChatError::Authentication(_) => 6,
+            ChatError::Timeout(_) => 7,
+        }
+    }
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatError::Usage(msg) => write!(f, "{msg}"),
+            ChatError::Io { context, source } => write!(f, "I/O error while {context}: {source}"),
+            ChatError::Handshake(msg) => write!(f, "handshake failed: {msg}"),
+            ChatError::ProtocolVersion { expected, received } => write!(
+                f,
+                "protocol version mismatch: peer speaks version {received}, we speak version {expected}"
+            ),
+            ChatError::Authentication(msg) => write!(f, "authentication failed: {msg}"),
+            ChatError::Timeout(msg) => write!(f, "timed out: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ChatError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame;
+    use std::io::Cursor;
+
+    #[test]
+    fn exit_codes_are_distinct() {
+        let errors = [
+            ChatError::Usage("x".to_string()),
+            ChatError::io("reading")(io::ErrorKind::BrokenPipe.into()),
+            ChatError::Handshake("x".to_string()),
+            ChatError::ProtocolVersion { expected: 1, received: 2 },
+            ChatError::Authentication("x".to_string()),
+            ChatError::Timeout("x".to_string()),
+        ];
+        let mut codes: Vec<i32> = errors.iter().map(ChatError::exit_code).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+        assert!(!codes.contains(&0) && !codes.contains(&1));
+    }
+
+    // 帧头里的版本号不对时，I/O 错误会被识别为协议版本错误
+    #[test]
+    fn bad_frame_version_maps_to_protocol_error() {
+        let wire = vec![9u8, 2, 0, 0, 0, 0];
+        let err = frame::read_frame(&mut Cursor::new(wire)).unwrap_err();
+        match ChatError::io("reading")(err) {
+            ChatError::ProtocolVersion { expected, received } => {
+                assert_eq!((expected, received), (frame::VERSION, 9));
+            }
+            other => panic!("unexpected error: {other}"),
+        }
+    }
+}
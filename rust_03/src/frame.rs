@@ -0,0 +1,196 @@
+use std::io::{self, Read, Write};
+
+// 线路帧格式：
+// +---------+------+----------------+-----------+
+// | version | type | length (u32BE) | payload   |
+// | 1 byte  | 1 B  | 4 bytes        | length B  |
+// +---------+------+----------------+-----------+
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 6;
+
+// 单帧上限，防止对方用一个巨大的长度字段耗尽内存
+pub const MAX_PAYLOAD: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameType {
+    // 加密后的聊天消息：密文 || 认证标签
+    Message,
+    // 明文握手消息：算法协商、DH 公钥
+    Handshake,
+    // 加密后的文件传输消息：格式与 Message 相同，明文是 transfer 模块的控制消息或数据块
+    File,
+    // 加密后的心跳：一方定期发送 Ping，另一方立即用 Pong 原样返回其中的计数器
+    Ping,
+    Pong,
+}
+
+impl FrameType {
+    pub fn id(self) -> u8 {
+        match self {
+            FrameType::Message => 1,
+            FrameType::Handshake => 2,
+            FrameType::File => 3,
+            FrameType::Ping => 4,
+            FrameType::Pong => 5,
+        }
+    }
+
+    // 心跳每隔几秒就有一个，不打印它们的加解密过程
+    pub fn is_heartbeat(self) -> bool {
+        matches!(self, FrameType::Ping | FrameType::Pong)
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(FrameType::Message),
+            2 => Some(FrameType::Handshake),
+            3 => Some(FrameType::File),
+            4 => Some(FrameType::Ping),
+            5 => Some(FrameType::Pong),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameType,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(kind: FrameType, payload: Vec<u8>) -> Self {
+        Frame { kind, payload }
+    }
+}
+
+// 一次性写出完整的帧（帧头和负载合并写入，避免被拆成两个 TCP 段）
+pub fn write_frame(writer: &mut impl Write, frame: &Frame) -> io::Result<()> {
+    if frame.payload.len() > MAX_PAYLOAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame payload too large ({} bytes)", frame.payload.len()),
+        ));
+    }
+    let mut bytes = Vec::with_capacity(HEADER_LEN + frame.payload.len());
+    bytes.push(VERSION);
+    bytes.push(frame.kind.id());
+    bytes.extend_from_slice(&(frame.payload.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&frame.payload);
+    writer.write_all(&bytes)?;
+    writer.flush()
+}
+
+// 读取一个完整的帧；在帧边界遇到 EOF 时返回 Ok(None)
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Option<Frame>> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut filled = 0;
+    while filled < HEADER_LEN {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    if header[0] != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            VersionMismatch {
+                expected: VERSION,
+                received: header[0],
+            },
+        ));
+    }
+    let kind = FrameType::from_id(header[1])
+        .ok_or_else(|| invalid_data(format!("unknown frame type {}", header[1])))?;
+    let len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+    if len > MAX_PAYLOAD {
+        return Err(invalid_data(format!("frame payload too large ({len} bytes)")));
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(Frame { kind, payload }))
+}
+
+// 帧头版本号不兼容；包装在 io::Error 中，调用方可以通过 downcast 识别出来
+#[derive(Debug)]
+pub struct VersionMismatch {
+    pub expected: u8,
+    pub received: u8,
+}
+
+impl std::fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unsupported protocol version {} (expected {})", self.received, self.expected)
+    }
+}
+
+impl std::error::Error for VersionMismatch {}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn back_to_back_frames_are_split_correctly() {
+        let mut wire = Vec::new();
+        for payload in [&b""[..], b"a", b"hello world"] {
+            write_frame(&mut wire, &Frame::new(FrameType::Message, payload.to_vec())).unwrap();
+        }
+
+        let mut reader = Cursor::new(wire);
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap().payload, b"");
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap().payload, b"a");
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap().payload, b"hello world");
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    // 模拟 TCP 每次只交付一个字节
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let end = buf.len().min(1);
+            self.0.read(&mut buf[..end])
+        }
+    }
+
+    #[test]
+    fn frame_survives_fragmented_reads() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &Frame::new(FrameType::Message, vec![7u8; 3000])).unwrap();
+        let frame = read_frame(&mut Trickle(Cursor::new(wire))).unwrap().unwrap();
+        assert_eq!(frame.payload, vec![7u8; 3000]);
+    }
+
+    #[test]
+    fn rejects_bad_header_and_truncation() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &Frame::new(FrameType::Message, b"payload".to_vec())).unwrap();
+
+        let mut bad_version = wire.clone();
+        bad_version[0] = 9;
+        assert!(read_frame(&mut Cursor::new(bad_version)).is_err());
+
+        let mut bad_type = wire.clone();
+        bad_type[1] = 0xEE;
+        assert!(read_frame(&mut Cursor::new(bad_type)).is_err());
+
+        let mut too_long = wire.clone();
+        too_long[2..6].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(read_frame(&mut Cursor::new(too_long)).is_err());
+
+        let truncated = wire[..wire.len() - 1].to_vec();
+        assert!(read_frame(&mut Cursor::new(truncated)).is_err());
+        assert!(read_frame(&mut Cursor::new(wire[..3].to_vec())).is_err());
+    }
+}
@@ -0,0 +1,640 @@
+use crate::cipher::CipherKind;
+use crate::dh::{DhGroup, DhKeypair, DhParams, DhPolicy};
+use crate::error::ChatError;
+use crate::frame::{self, Frame, FrameType};
+use crate::identity::{self, Identity};
+use crate::kdf::SessionKeys;
+use crate::kex::{KexKind, X25519Keypair};
+use crate::secret::Secret;
+use ed25519_dalek::VerifyingKey;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+
+// 连接中的角色，决定握手记录的顺序以及本端用哪个方向的密钥收发
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+impl Role {
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Server => "SERVER",
+            Role::Client => "CLIENT",
+        }
+    }
+
+    pub fn peer_name(self) -> &'static str {
+        self.peer().name()
+    }
+
+    // 签名时使用的角色标签，防止把一方的签名反射给另一方
+    fn label(self) -> &'static str {
+        match self {
+            Role::Server => "server",
+            Role::Client => "client",
+        }
+    }
+
+    pub fn peer(self) -> Role {
+        match self {
+            Role::Server => Role::Client,
+            Role::Client => Role::Server,
+        }
+    }
+}
+
+// 握手记录：按“客户端消息、服务器消息”的固定顺序哈希所有握手消息，
+// 双方得到相同的摘要，用作密钥派生的 salt
+pub struct Transcript {
+    role: Role,
+    hasher: Sha256,
+}
+
+impl Transcript {
+    pub fn new(role: Role) -> Self {
+        Transcript {
+            role,
+            hasher: Sha256::new(),
+        }
+    }
+
+    // 记录同一轮中双方各自发送的一条消息
+    pub fn record(&mut self, ours: &[u8], theirs: &[u8]) {
+        let (client, server) = match self.role {
+            Role::Client => (ours, theirs),
+            Role::Server => (theirs, ours),
+        };
+        for msg in [client, server] {
+            self.hasher.update((msg.len() as u32).to_be_bytes());
+            self.hasher.update(msg);
+        }
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        self.hasher.clone().finalize().into()
+    }
+}
+
+// 握手中双方必须一致的参数
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub cipher: CipherKind,
+    pub kex: KexKind,
+    pub group: DhGroup,
+    // 是否用长期身份密钥签名握手；关闭后任何中间人都能读到全部内容（仅用于教学演示）
+    pub authenticate: bool,
+}
+
+// 握手成功后的结果
+#[derive(Debug)]
+pub struct Established {
+    pub hello: Hello,
+    pub keys: SessionKeys,
+    // 对方已通过签名验证的长期身份公钥；未认证的握手为 None
+    pub peer_identity: Option<VerifyingKey>,
+}
+
+// 一次握手的参数：本端的角色、希望使用的算法，以及用来签名的长期身份密钥
+#[derive(Clone, Copy)]
+pub struct Handshake<'a> {
+    pub role: Role,
+    pub hello: Hello,
+    pub identity: &'a Identity,
+    // 自定义群（DhGroup::Custom）的参数：服务器必须提供；客户端提供时要求服务器发来的完全相同
+    pub params: Option<&'a DhParams>,
+    // 客户端接受服务器自定义参数的条件
+    pub dh_policy: DhPolicy,
+}
+
+impl<'a> Handshake<'a> {
+    pub fn new(role: Role, hello: Hello, identity: &'a Identity) -> Self {
+        Handshake { role, hello, identity, params: None, dh_policy: DhPolicy::default() }
+    }
+
+    pub fn with_params(mut self, params: &'a DhParams) -> Self {
+        self.params = Some(params);
+        self
+    }
+
+    pub fn with_dh_policy(mut self, policy: DhPolicy) -> Self {
+        self.dh_policy = policy;
+        self
+    }
+
+    // 完整握手：先协商算法，再做密钥交换，然后用长期身份密钥签名认证，
+    // 最后用 HKDF 从共享密钥和握手记录派生会话密钥。可以运行在任意双向字节流上
+    pub fn perform(&self, stream: &mut (impl Read + Write)) -> Result<Established, ChatError> {
+        let mut transcript = Transcript::new(self.role);
+        let hello = negotiate(stream, self.hello, &mut transcript)?;
+        let secret = match hello.kex {
+            KexKind::Dh => {
+                let params = self.exchange_params(stream, hello.group, &mut transcript)?;
+                dh_exchange(stream, &params, &mut transcript)?
+            }
+            KexKind::X25519 => x25519_exchange(stream, &mut transcript)?,
+        };
+        let peer_identity = if hello.authenticate {
+            Some(authenticate(stream, self.role, self.identity, &mut transcript)?)
+        } else {
+            say!("[AUTH] ⚠ Handshake is NOT authenticated: a man in the middle can read everything");
+            None
+        };
+
+        let transcript_hash = transcript.hash();
+        verbose!("[KDF] HKDF-SHA256(salt = transcript, ikm = shared secret)");
+        verbose!("Transcript hash: {}", crate::chat::to_hex(&transcript_hash));
+        let keys = SessionKeys::derive(&secret, &transcript_hash);
+        for (label, secrets) in [
+            ("client->server", &keys.client_to_server),
+            ("server->client", &keys.server_to_client),
+        ] {
+            teach!(
+                "{label}: key = {}, nonce = {}, mac = {}",
+                crate::chat::to_hex(&secrets.key),
+                crate::chat::to_hex(&secrets.nonce),
+                crate::chat::to_hex(&secrets.mac_key)
+            );
+        }
+        Ok(Established {
+            hello,
+            keys,
+            peer_identity,
+        })
+    }
+
+    // DH 参数由服务器决定并在握手中发送，客户端检查后才使用：
+    // 标准群必须与约定的常量完全一致，并且不短于 dh_policy 的最小长度；
+    // 自定义群要通过 dh_policy（最小长度、安全素数、生成元）
+    fn exchange_params(
+        &self,
+        stream: &mut (impl Read + Write),
+        group: DhGroup,
+        transcript: &mut Transcript,
+    ) -> Result<DhParams, ChatError> {
+        self.dh_policy.check_group(group).map_err(ChatError::Handshake)?;
+        let expected = group.params().or_else(|| self.params.cloned());
+        if self.role == Role::Server {
+            let params = expected.ok_or_else(|| {
+                ChatError::Handshake("the custom group needs parameters loaded with --params".to_string())
+            })?;
+            let encoded = params.encode();
+            verbose!("[NETWORK] Sending our {}-bit DH parameters ({} bytes)...", params.bits(), encoded.len());
+            send(stream, encoded.clone())?;
+            transcript.record(&encoded, &[]);
+            return Ok(params);
+        }
+
+        let encoded = receive(stream)?;
+        transcript.record(&[], &encoded);
+        let params = DhParams::decode(&encoded).map_err(ChatError::Handshake)?;
+        verbose!("[NETWORK] Received {}-bit DH parameters ({} bytes) ✓", params.bits(), encoded.len());
+        match expected {
+            Some(expected) if params != expected => Err(ChatError::Handshake(format!(
+                "server sent DH parameters that differ from {}",
+                if group == DhGroup::Custom { "our --params file" } else { group.name() }
+            ))),
+            Some(_) => Ok(params),
+            None => {
+                verbose!(
+                    "[DH] Checking the server's parameters (safe prime, generator, ≥ {} bits)...",
+                    self.dh_policy.min_bits
+                );
+                self.dh_policy
+                    .check(&params)
+                    .map_err(|e| ChatError::Handshake(format!("rejected the server's DH parameters: {e}")))?;
+                info!("[DH] Server's custom {}-bit group passed the policy checks ✓", params.bits());
+                Ok(params)
+            }
+        }
+    }
+}
+
+// 握手第一步：交换双方选择的密码算法、密钥交换方式和 DH 群，不一致则拒绝继续
+pub fn negotiate(
+    stream: &mut (impl Read + Write),
+    ours: Hello,
+    transcript: &mut Transcript,
+) -> Result<Hello, ChatError> {
+    verbose!(
+        "[HANDSHAKE] Proposing cipher: {}, kex: {}, group: {}, auth: {}",
+        ours.cipher.name(),
+        ours.kex.name(),
+        ours.group.name(),
+        auth_name(ours.authenticate)
+    );
+    let our_hello = vec![ours.cipher.id(), ours.kex.id(), ours.group.id(), ours.authenticate as u8];
+    send(stream, our_hello.clone())?;
+
+    let theirs = receive(stream)?;
+    transcript.record(&our_hello, &theirs);
+    if theirs.len() != 4 {
+        return Err(ChatError::Handshake(format!("malformed hello ({} bytes)", theirs.len())));
+    }
+    let cipher = CipherKind::from_id(theirs[0])
+        .ok_or_else(|| ChatError::Handshake(format!("peer proposed unknown cipher id {}", theirs[0])))?;
+    let kex = KexKind::from_id(theirs[1])
+        .ok_or_else(|| ChatError::Handshake(format!("peer proposed unknown key exchange id {}", theirs[1])))?;
+    let group = DhGroup::from_id(theirs[2])
+        .ok_or_else(|| ChatError::Handshake(format!("peer proposed unknown DH group id {}", theirs[2])))?;
+    let authenticate = match theirs[3] {
+        0 => false,
+        1 => true,
+        other => {
+            return Err(ChatError::Handshake(format!(
+                "peer proposed unknown authentication mode {other}"
+            )));
+        }
+    };
+    if kex != ours.kex {
+        return Err(ChatError::Handshake(format!(
+            "key exchange mismatch: we use {}, peer uses {}",
+            ours.kex.name(),
+            kex.name()
+        )));
+    }
+    if cipher != ours.cipher {
+        return Err(ChatError::Handshake(format!(
+            "cipher mismatch: we use {}, peer uses {}",
+            ours.cipher.name(),
+            cipher.name()
+        )));
+    }
+    if group != ours.group {
+        return Err(ChatError::Handshake(format!(
+            "DH group mismatch: we use {}, peer uses {}",
+            ours.group.name(),
+            group.name()
+        )));
+    }
+    if authenticate != ours.authenticate {
+        return Err(ChatError::Handshake(format!(
+            "authentication mismatch: we use {}, peer uses {}",
+            auth_name(ours.authenticate),
+            auth_name(authenticate)
+        )));
+    }
+    info!(
+        "[HANDSHAKE] Peer agreed on cipher: {}, kex: {}, group: {}, auth: {} ✓",
+        cipher.name(),
+        kex.name(),
+        group.name(),
+        auth_name(authenticate)
+    );
+    Ok(ours)
+}
+
+fn auth_name(authenticate: bool) -> &'static str {
+    if authenticate { "ed25519" } else { "none" }
+}
+
+// DH 密钥交换逻辑，返回定长（与 p 等长）的共享密钥
+pub fn dh_exchange(
+    stream: &mut (impl Read + Write),
+    params: &DhParams,
+    transcript: &mut Transcript,
+) -> Result<Secret<Vec<u8>>, ChatError> {
+    let (p, g) = (&params.p, &params.g);
+    verbose!("[DH] Starting key exchange...");
+    verbose!("[DH] Using a {}-bit group:", params.bits());
+    teach!("p = {p:X} ({}-bit safe prime - public)", p.bits());
+    teach!("g = {g} (generator - public)");
+
+    // 生成随机私钥
+    let keypair = DhKeypair::generate(params);
+    let public_key = keypair.public_key();
+    verbose!("[DH] Generating our keypair...");
+    teach!("private_key = {} (random, 2 <= x <= p-2)", crate::chat::to_hex(&keypair.private_bytes()));
+
+    // 计算公钥：g^private mod p（常数时间的 Montgomery ladder）
+    teach!("public_key = g^private mod p");
+    teach!("= {g}^private_key mod p");
+    teach!("= {public_key:X}");
+
+    // 发送自己的公钥
+    let public_bytes = keypair.public_bytes();
+    verbose!("[NETWORK] Sending public key ({} bytes)...", public_bytes.len());
+    teach!("→ Send our public: {public_key:X}");
+    send(stream, public_bytes.clone())?;
+
+    // 接收对方的公钥
+    let their_public = receive(stream)?;
+    transcript.record(&public_bytes, &their_public);
+    verbose!("[NETWORK] Received public key ({} bytes) ✓", their_public.len());
+    teach!("← Receive their public: {}", crate::chat::to_hex(&their_public));
+
+    // 计算共享密钥：their_public^private mod p（对方公钥先做范围检查）
+    let shared_secret = keypair.shared_secret(&their_public).map_err(ChatError::Handshake)?;
+    verbose!("[DH] Computing shared secret...");
+    teach!("Formula: secret = (their_public)^(our_private) mod p");
+    teach!("= {}", crate::chat::to_hex(&shared_secret));
+
+    Ok(shared_secret)
+}
+
+// X25519 密钥交换：双方各发送 32 字节公钥，共享密钥进入与 DH 相同的派生流程
+pub fn x25519_exchange(
+    stream: &mut (impl Read + Write),
+    transcript: &mut Transcript,
+) -> Result<Secret<Vec<u8>>, ChatError> {
+    verbose!("[X25519] Starting key exchange (RFC 7748, Curve25519)...");
+
+    // 生成随机私钥
+    let keypair = X25519Keypair::generate();
+    verbose!("[X25519] Generating our keypair...");
+    teach!("private_key = {} (clamped 255-bit scalar)", crate::chat::to_hex(&keypair.private_bytes()[..]));
+    teach!("public_key = private * basepoint(u=9)");
+    teach!("= {}", crate::chat::to_hex(&keypair.public_bytes()));
+
+    // 发送自己的公钥
+    verbose!("[NETWORK] Sending public key (32 bytes)...");
+    send(stream, keypair.public_bytes().to_vec())?;
+
+    // 接收对方的公钥
+    let their_public = receive(stream)?;
+    transcript.record(&keypair.public_bytes(), &their_public);
+    verbose!("[NETWORK] Received public key ({} bytes) ✓", their_public.len());
+    teach!("← Receive their public: {}", crate::chat::to_hex(&their_public));
+
+    // 计算共享密钥：our_private * their_public
+    let shared_secret = keypair.shared_secret(&their_public).map_err(ChatError::Handshake)?;
+    verbose!("[X25519] Computing shared secret...");
+    teach!("Formula: secret = our_private * their_public");
+    teach!("= {}", crate::chat::to_hex(&shared_secret));
+
+    Ok(shared_secret)
+}
+
+// 身份认证：双方用 Ed25519 长期密钥对目前的握手记录签名。
+// 记录里已经包含双方的临时公钥，中间人替换任何一个公钥都会让签名失效
+pub fn authenticate(
+    stream: &mut (impl Read + Write),
+    role: Role,
+    identity: &Identity,
+    transcript: &mut Transcript,
+) -> Result<VerifyingKey, ChatError> {
+    let signed_hash = transcript.hash();
+    verbose!("[AUTH] Signing the handshake with our identity key...");
+    verbose!("Our fingerprint: {}", identity::fingerprint(&identity.public_key()));
+    let our_msg = identity.sign_handshake(role.label(), &signed_hash);
+    send(stream, our_msg.clone())?;
+
+    let their_msg = receive(stream)?;
+    let peer_identity = identity::verify_handshake(role.peer().label(), &signed_hash, &their_msg)
+        .map_err(ChatError::Authentication)?;
+    transcript.record(&our_msg, &their_msg);
+    info!(
+        "[AUTH] {} signature verified ✓ (fingerprint {})",
+        role.peer_name(),
+        identity::fingerprint(&peer_identity)
+    );
+    Ok(peer_identity)
+}
+
+fn send(stream: &mut impl Write, payload: Vec<u8>) -> Result<(), ChatError> {
+    frame::write_frame(stream, &Frame::new(FrameType::Handshake, payload))
+        .map_err(ChatError::io("sending a handshake message"))
+}
+
+fn receive(stream: &mut impl Read) -> Result<Vec<u8>, ChatError> {
+    match frame::read_frame(stream).map_err(ChatError::io("reading a handshake message"))? {
+        Some(frame) if frame.kind == FrameType::Handshake => Ok(frame.payload),
+        Some(frame) => Err(ChatError::Handshake(format!(
+            "unexpected {:?} frame during handshake",
+            frame.kind
+        ))),
+        None => Err(ChatError::Handshake("peer closed the connection during handshake".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    type Outcome = Result<Established, ChatError>;
+
+    fn handshake_pair(server: Hello, client: Hello) -> (Outcome, Outcome) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            Handshake::new(Role::Client, client, &Identity::generate()).perform(&mut stream)
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        let server = Handshake::new(Role::Server, server, &Identity::generate()).perform(&mut stream);
+        (server, client.join().unwrap())
+    }
+
+    #[test]
+    fn peers_derive_the_same_secret() {
+        let hello = Hello {
+            cipher: CipherKind::Chacha20,
+            kex: KexKind::Dh,
+            group: DhGroup::Ffdhe2048,
+            authenticate: true,
+        };
+        let (server, client) = handshake_pair(hello, hello);
+        let (server, client) = (server.unwrap(), client.unwrap());
+        assert_eq!(server.hello, hello);
+        assert_eq!(server.keys, client.keys);
+        assert_eq!(server.keys.sending(Role::Server), client.keys.receiving(Role::Client));
+    }
+
+    #[test]
+    fn x25519_peers_derive_the_same_secret() {
+        let hello = Hello {
+            cipher: CipherKind::AesCtr,
+            kex: KexKind::X25519,
+            group: DhGroup::Ffdhe2048,
+            authenticate: true,
+        };
+        let (server, client) = handshake_pair(hello, hello);
+        let (server, client) = (server.unwrap(), client.unwrap());
+        assert_eq!(server.keys, client.keys);
+    }
+
+    #[test]
+    fn peers_learn_each_others_identity() {
+        let hello = Hello {
+            cipher: CipherKind::Chacha20,
+            kex: KexKind::X25519,
+            group: DhGroup::Toy64,
+            authenticate: true,
+        };
+        let server_identity = Identity::generate();
+        let client_identity = Identity::generate();
+        let server_public = server_identity.public_key();
+        let client_public = client_identity.public_key();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            Handshake::new(Role::Client, hello, &client_identity).perform(&mut stream)
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        let server = Handshake::new(Role::Server, hello, &server_identity).perform(&mut stream).unwrap();
+        let client = client.join().unwrap().unwrap();
+        assert_eq!(server.peer_identity, Some(client_public));
+        assert_eq!(client.peer_identity, Some(server_public));
+    }
+
+    // 中间人替换临时公钥后重新转发签名：签名覆盖的记录不同，必须被拒绝
+    #[test]
+    fn signature_over_a_different_exchange_is_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let attacker = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            receive(&mut stream).unwrap();
+            // 签名来自另一次握手（不同的记录）
+            let other = Identity::generate().sign_handshake("client", &[0u8; 32]);
+            send(&mut stream, other).unwrap();
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut transcript = Transcript::new(Role::Server);
+        transcript.record(b"client key", b"server key");
+        let result = authenticate(&mut stream, Role::Server, &Identity::generate(), &mut transcript);
+        attacker.join().unwrap();
+        assert!(result.unwrap_err().to_string().contains("signature verification failed"));
+    }
+
+    #[test]
+    fn mismatched_kex_is_rejected() {
+        let server = Hello {
+            cipher: CipherKind::Chacha20,
+            kex: KexKind::X25519,
+            group: DhGroup::Ffdhe2048,
+            authenticate: true,
+        };
+        let client = Hello {
+            kex: KexKind::Dh,
+            ..server
+        };
+        let (server, client) = handshake_pair(server, client);
+        assert!(server.unwrap_err().to_string().contains("key exchange mismatch"));
+        assert!(client.unwrap_err().to_string().contains("key exchange mismatch"));
+    }
+
+    #[test]
+    fn mismatched_group_is_rejected() {
+        let server = Hello {
+            cipher: CipherKind::Chacha20,
+            kex: KexKind::Dh,
+            group: DhGroup::Modp2048,
+            authenticate: true,
+        };
+        let client = Hello {
+            group: DhGroup::Ffdhe2048,
+            ..server
+        };
+        let (server, client) = handshake_pair(server, client);
+        assert!(server.unwrap_err().to_string().contains("DH group mismatch"));
+        assert!(client.unwrap_err().to_string().contains("DH group mismatch"));
+    }
+
+    #[test]
+    fn mismatched_authentication_is_rejected() {
+        let server = Hello {
+            cipher: CipherKind::Chacha20,
+            kex: KexKind::X25519,
+            group: DhGroup::Toy64,
+            authenticate: true,
+        };
+        let client = Hello {
+            authenticate: false,
+            ..server
+        };
+        let (server, client) = handshake_pair(server, client);
+        assert!(server.unwrap_err().to_string().contains("authentication mismatch"));
+        assert!(client.unwrap_err().to_string().contains("authentication mismatch"));
+    }
+
+    // 默认策略下双方都拒绝 toy64，握手在交换 DH 参数之前就停止
+    #[test]
+    fn toy_group_needs_an_explicit_policy() {
+        let hello = Hello {
+            cipher: CipherKind::Chacha20,
+            kex: KexKind::Dh,
+            group: DhGroup::Toy64,
+            authenticate: false,
+        };
+        let (server, client) = handshake_pair(hello, hello);
+        assert!(server.unwrap_err().to_string().contains("below the required 2048"));
+        assert!(client.is_err());
+    }
+
+    #[test]
+    fn transcript_order_is_role_independent() {
+        let mut client = Transcript::new(Role::Client);
+        let mut server = Transcript::new(Role::Server);
+        client.record(b"client hello", b"server hello");
+        server.record(b"server hello", b"client hello");
+        assert_eq!(client.hash(), server.hash());
+
+        client.record(b"client key", b"server key");
+        assert_ne!(client.hash(), server.hash());
+    }
+
+    // 服务器用 genparams 生成的参数；客户端只有在参数满足策略时才接受
+    #[test]
+    fn custom_params_are_sent_by_the_server_and_checked_by_the_client() {
+        let hello = Hello {
+            cipher: CipherKind::Chacha20,
+            kex: KexKind::Dh,
+            group: DhGroup::Custom,
+            authenticate: false,
+        };
+        let params = DhParams::generate(64, || {});
+        let other = DhParams::generate(64, || {});
+        let run = |client_params: Option<DhParams>, min_bits: u64| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                let identity = Identity::generate();
+                let policy = DhPolicy { min_bits, ..DhPolicy::default() };
+                let mut handshake = Handshake::new(Role::Client, hello, &identity).with_dh_policy(policy);
+                if let Some(params) = &client_params {
+                    handshake = handshake.with_params(params);
+                }
+                handshake.perform(&mut stream)
+            });
+            let (mut stream, _) = listener.accept().unwrap();
+            let identity = Identity::generate();
+            let server = Handshake::new(Role::Server, hello, &identity).with_params(&params).perform(&mut stream);
+            (server, client.join().unwrap())
+        };
+
+        let (server, client) = run(None, 64);
+        assert_eq!(server.unwrap().keys, client.unwrap().keys);
+        let (_, client) = run(Some(params.clone()), 2048);
+        assert!(client.is_ok(), "a matching --params file skips the size policy");
+
+        let (_, client) = run(None, 2048);
+        assert!(client.unwrap_err().to_string().contains("below the required 2048 bits"));
+        let (_, client) = run(Some(other), 64);
+        assert!(client.unwrap_err().to_string().contains("differ"));
+    }
+
+    // 对方发来 p-1 作为公钥时必须中止握手
+    #[test]
+    fn degenerate_public_key_is_rejected() {
+        let group = DhGroup::Modp2048;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let attacker = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            receive(&mut stream).unwrap();
+            let p_minus_one = (group.params().unwrap().p - 1u32).to_bytes_be();
+            send(&mut stream, p_minus_one).unwrap();
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        let result = dh_exchange(&mut stream, &group.params().unwrap(), &mut Transcript::new(Role::Server));
+        attacker.join().unwrap();
+        assert!(result.unwrap_err().to_string().contains("out of range"));
+    }
+}
@@ -0,0 +1,283 @@
+use crate::chat::parse_hex32;
+use crate::secret::Secret;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const IDENTITY_FILE: &str = "identity.key";
+const KNOWN_PEERS_FILE: &str = "known_peers";
+
+// 身份消息 = Ed25519 公钥 (32) || 签名 (64)
+pub const IDENTITY_MSG_LEN: usize = 32 + 64;
+
+// 默认配置目录：$RUST_03_HOME，否则 $HOME/.rust_03
+pub fn default_config_dir() -> PathBuf {
+    if let Ok(dir) = std::env::var("RUST_03_HOME") {
+        return PathBuf::from(dir);
+    }
+    match std::env::var("HOME") {
+        Ok(home) => Path::new(&home).join(".rust_03"),
+        Err(_) => PathBuf::from(".rust_03"),
+    }
+}
+
+// 长期身份密钥（Ed25519），用来给握手签名
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        let mut seed = Secret::new([0u8; 32]);
+        rand::rng().fill_bytes(&mut seed[..]);
+        Identity {
+            signing_key: SigningKey::from_bytes(&seed),
+        }
+    }
+
+    // 从配置目录读取身份密钥；第一次运行时生成并保存
+    pub fn load_or_create(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(IDENTITY_FILE);
+        if path.exists() {
+            let text = Secret::new(fs::read_to_string(&path)?);
+            let seed = Secret::new(parse_hex32(text.trim()).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not a valid identity key", path.display()),
+                )
+            })?);
+            return Ok(Identity {
+                signing_key: SigningKey::from_bytes(&seed),
+            });
+        }
+
+        fs::create_dir_all(dir)?;
+        let identity = Identity::generate();
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&path)?;
+        writeln!(file, "{}", *Secret::new(crate::chat::to_hex(identity.signing_key.as_bytes())))?;
+        info!("[IDENTITY] Generated new identity key at {}", path.display());
+        Ok(identity)
+    }
+
+    pub fn public_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    // 对握手记录签名：记录中已包含双方的临时公钥，因此签名同时绑定了本端的临时公钥
+    pub fn sign_handshake(&self, signer: &str, transcript_hash: &[u8; 32]) -> Vec<u8> {
+        let signature = self.signing_key.sign(&signed_message(signer, transcript_hash));
+        let mut msg = self.public_key().to_bytes().to_vec();
+        msg.extend_from_slice(&signature.to_bytes());
+        msg
+    }
+}
+
+// 校验对方的身份消息，返回对方的长期公钥
+pub fn verify_handshake(signer: &str, transcript_hash: &[u8; 32], msg: &[u8]) -> Result<VerifyingKey, String> {
+    if msg.len() != IDENTITY_MSG_LEN {
+        return Err(format!("malformed identity message ({} bytes)", msg.len()));
+    }
+    let public: [u8; 32] = msg[..32].try_into().expect("length checked above");
+    let public = VerifyingKey::from_bytes(&public).map_err(|_| "invalid identity public key".to_string())?;
+    let signature = Signature::from_slice(&msg[32..]).map_err(|_| "malformed signature".to_string())?;
+    public
+        .verify(&signed_message(signer, transcript_hash), &signature)
+        .map_err(|_| "identity signature verification failed".to_string())?;
+    Ok(public)
+}
+
+fn signed_message(signer: &str, transcript_hash: &[u8; 32]) -> Vec<u8> {
+    let mut msg = format!("rust_03 v1 identity {signer} ").into_bytes();
+    msg.extend_from_slice(transcript_hash);
+    msg
+}
+
+// 公钥指纹：SHA-256 的前 16 字节，每 2 字节一组
+pub fn fingerprint(key: &VerifyingKey) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    let groups: Vec<String> = digest[..16]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect();
+    format!("SHA256:{}", groups.join(":"))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Trust {
+    // 与之前记录的指纹一致
+    Known,
+    // 第一次见到，已记录（trust on first use）
+    New,
+}
+
+// known_peers 文件：每行 “<名称> <公钥 hex>”
+pub struct KnownPeers {
+    path: PathBuf,
+    entries: Vec<(String, [u8; 32])>,
+}
+
+impl KnownPeers {
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(KNOWN_PEERS_FILE);
+        let mut entries = Vec::new();
+        if path.exists() {
+            // 每行是 "<名称> <公钥十六进制>"。名称可能带空格（例如 unix:/path with space），
+            // 公钥不会，所以按最后一个空格切分；看不懂的行直接报错，不能悄悄当成没见过的对方
+            for (number, line) in fs::read_to_string(&path)?.lines().enumerate() {
+                let line = line.trim_end();
+                if line.is_empty() {
+                    continue;
+                }
+                let entry = line
+                    .rsplit_once(' ')
+                    .and_then(|(name, key)| Some((name.to_string(), parse_hex32(key)?)))
+                    .filter(|(name, _)| !name.is_empty())
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{} line {}: expected \"<name> <key>\"", path.display(), number + 1),
+                        )
+                    })?;
+                entries.push(entry);
+            }
+        }
+        Ok(KnownPeers { path, entries })
+    }
+
+    // 已知名称的公钥变化时直接失败，不给用户“继续”的选项
+    pub fn check(&mut self, name: &str, key: &VerifyingKey) -> Result<Trust, String> {
+        if let Some((_, known)) = self.entries.iter().find(|(n, _)| n == name) {
+            if known == key.as_bytes() {
+                return Ok(Trust::Known);
+            }
+            let known = VerifyingKey::from_bytes(known)
+                .map(|k| fingerprint(&k))
+                .unwrap_or_else(|_| "<invalid>".to_string());
+            return Err(format!(
+                "IDENTITY OF {name} HAS CHANGED! Expected {known}, got {}. \
+                 Someone may be intercepting the connection. \
+                 If the change is legitimate, remove the entry from {}",
+                fingerprint(key),
+                self.path.display()
+            ));
+        }
+
+        // 换行会把一条记录拆成两行
+        if name.contains(['\n', '\r']) {
+            return Err(format!("cannot remember {name:?}: the name contains a line break"));
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .map_err(|e| format!("cannot update {}: {e}", self.path.display()))?;
+        writeln!(file, "{name} {}", crate::chat::to_hex(key.as_bytes()))
+            .map_err(|e| format!("cannot update {}: {e}", self.path.display()))?;
+        self.entries.push((name.to_string(), key.to_bytes()));
+        Ok(Trust::New)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust_03-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn identity_persists_across_loads() {
+        let dir = temp_dir("identity");
+        let first = Identity::load_or_create(&dir).unwrap();
+        let second = Identity::load_or_create(&dir).unwrap();
+        assert_eq!(first.public_key(), second.public_key());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn signature_binds_transcript_and_role() {
+        let identity = Identity::generate();
+        let msg = identity.sign_handshake("server", &[1u8; 32]);
+        assert_eq!(verify_handshake("server", &[1u8; 32], &msg).unwrap(), identity.public_key());
+        assert!(verify_handshake("server", &[2u8; 32], &msg).is_err());
+        assert!(verify_handshake("client", &[1u8; 32], &msg).is_err());
+
+        let mut forged = msg.clone();
+        forged[40] ^= 1;
+        assert!(verify_handshake("server", &[1u8; 32], &forged).is_err());
+        assert!(verify_handshake("server", &[1u8; 32], &msg[..90]).is_err());
+    }
+
+    #[test]
+    fn known_peers_trust_on_first_use() {
+        let dir = temp_dir("known-peers");
+        let alice = Identity::generate().public_key();
+        let mallory = Identity::generate().public_key();
+
+        let mut peers = KnownPeers::load(&dir).unwrap();
+        assert_eq!(peers.check("example:8080", &alice), Ok(Trust::New));
+        assert_eq!(peers.check("example:8080", &alice), Ok(Trust::Known));
+
+        // 重新加载后仍然记得，并且指纹变化时硬失败
+        let mut peers = KnownPeers::load(&dir).unwrap();
+        assert_eq!(peers.check("example:8080", &alice), Ok(Trust::Known));
+        assert!(peers.check("example:8080", &mallory).unwrap_err().contains("HAS CHANGED"));
+        assert_eq!(peers.check("other:8080", &mallory), Ok(Trust::New));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // 带空格的名称（Unix socket 路径）也能原样保存和读回
+    #[test]
+    fn known_peers_keep_names_with_spaces() {
+        let dir = temp_dir("known-peers-spaces");
+        let alice = Identity::generate().public_key();
+        let mallory = Identity::generate().public_key();
+        let name = "unix:/tmp/path with space/chat.sock";
+
+        let mut peers = KnownPeers::load(&dir).unwrap();
+        assert_eq!(peers.check(name, &alice), Ok(Trust::New));
+        let mut peers = KnownPeers::load(&dir).unwrap();
+        assert_eq!(peers.check(name, &alice), Ok(Trust::Known));
+        assert!(peers.check(name, &mallory).unwrap_err().contains("HAS CHANGED"));
+        assert!(peers.check("unix:/tmp/a\nb", &alice).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // 看不懂的行要报错，不能跳过之后把对方当成第一次见面
+    #[test]
+    fn malformed_known_peers_lines_are_reported() {
+        let dir = temp_dir("known-peers-malformed");
+        fs::create_dir_all(&dir).unwrap();
+        let key = crate::chat::to_hex(Identity::generate().public_key().as_bytes());
+        for bad in ["example:8080".to_string(), format!("example:8080 {}", &key[2..]), format!(" {key}")] {
+            fs::write(dir.join(KNOWN_PEERS_FILE), format!("good:1 {key}\n\n{bad}\n")).unwrap();
+            let error = KnownPeers::load(&dir).err().expect("the bad line was skipped");
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(error.to_string().contains("line 3"), "{error}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fingerprint_format() {
+        let fp = fingerprint(&Identity::generate().public_key());
+        assert!(fp.starts_with("SHA256:"));
+        assert_eq!(fp.len(), "SHA256:".len() + 8 * 4 + 7);
+    }
+}
@@ -0,0 +1,218 @@
+use crate::handshake::Role;
+use crate::secret::Secret;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+// 一个方向所需的全部密钥材料；和下面的 SessionKeys 一样在释放时清零，Debug 和 Secret 一样不显示内容
+#[derive(Clone, PartialEq, Eq, Zeroize, ZeroizeOnDrop)]
+pub struct DirectionSecrets {
+    pub key: [u8; 32],
+    pub nonce: [u8; 12],
+    pub mac_key: [u8; 32],
+}
+
+impl DirectionSecrets {
+    // 对称哈希棘轮：用当前密钥材料派生下一代密钥。
+    // HKDF 是单向的，拿到新密钥也推不出旧密钥，因此旧消息仍然安全（前向保密）
+    pub fn ratchet(&self) -> DirectionSecrets {
+        let mut current = Secret::new(Vec::with_capacity(32 + 12 + 32));
+        current.extend_from_slice(&self.key);
+        current.extend_from_slice(&self.nonce);
+        current.extend_from_slice(&self.mac_key);
+        let hkdf = Hkdf::<Sha256>::new(None, &current);
+        expand_direction(&hkdf, "ratchet")
+    }
+}
+
+impl fmt::Debug for DirectionSecrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DirectionSecrets(<redacted>)")
+    }
+}
+
+// 会话密钥表：两个方向的密钥互相独立
+#[derive(Clone, PartialEq, Eq, Zeroize, ZeroizeOnDrop)]
+pub struct SessionKeys {
+    // HKDF 提取出的 PRK：两个方向的密钥都由它展开，离线解密录制的日志只需要这 32 字节
+    pub session_key: [u8; 32],
+    pub client_to_server: DirectionSecrets,
+    pub server_to_client: DirectionSecrets,
+}
+
+impl SessionKeys {
+    // HKDF-SHA256（RFC 5869）：
+    //   PRK = Extract(salt = transcript_hash, IKM = shared_secret)
+    //   每个输出 = Expand(PRK, "rust_03 v1 <方向> <用途>", 长度)
+    // 把握手记录作为 salt，任何被篡改的握手消息都会得到不同的密钥
+    pub fn derive(shared_secret: &[u8], transcript_hash: &[u8; 32]) -> Self {
+        let (prk, _) = Hkdf::<Sha256>::extract(Some(transcript_hash), shared_secret);
+        Self::from_session_key(prk.into())
+    }
+
+    // 只做 Expand：由会话主密钥（PRK）重新得到两个方向的密钥
+    pub fn from_session_key(session_key: [u8; 32]) -> Self {
+        let hkdf = Hkdf::<Sha256>::from_prk(&session_key).expect("a SHA-256 output is a valid PRK");
+        SessionKeys {
+            session_key,
+            client_to_server: expand_direction(&hkdf, "client->server"),
+            server_to_client: expand_direction(&hkdf, "server->client"),
+        }
+    }
+
+    pub fn sending(&self, role: Role) -> &DirectionSecrets {
+        match role {
+            Role::Client => &self.client_to_server,
+            Role::Server => &self.server_to_client,
+        }
+    }
+
+    pub fn receiving(&self, role: Role) -> &DirectionSecrets {
+        match role {
+            Role::Client => &self.server_to_client,
+            Role::Server => &self.client_to_server,
+        }
+    }
+}
+
+// 握手结果（Established）的 Debug 也经过这里，打印会话时不会带出密钥
+impl fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionKeys(<redacted>)")
+    }
+}
+
+fn expand_direction(hkdf: &Hkdf<Sha256>, direction: &str) -> DirectionSecrets {
+    let mut secrets = DirectionSecrets {
+        key: [0u8; 32],
+        nonce: [0u8; 12],
+        mac_key: [0u8; 32],
+    };
+    expand(hkdf, direction, "key", &mut secrets.key);
+    expand(hkdf, direction, "nonce", &mut secrets.nonce);
+    expand(hkdf, direction, "mac", &mut secrets.mac_key);
+    secrets
+}
+
+fn expand(hkdf: &Hkdf<Sha256>, direction: &str, purpose: &str, out: &mut [u8]) {
+    let info = format!("rust_03 v1 {direction} {purpose}");
+    hkdf.expand(info.as_bytes(), out)
+        .expect("output is far below the HKDF length limit");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Digest;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // RFC 5869 A.1：HKDF-SHA256 基本测试用例
+    #[test]
+    fn hkdf_rfc5869_case1() {
+        let ikm = [0x0bu8; 22];
+        let salt = hex("000102030405060708090a0b0c");
+        let info = hex("f0f1f2f3f4f5f6f7f8f9");
+        let (prk, hkdf) = Hkdf::<Sha256>::extract(Some(&salt), &ikm);
+        assert_eq!(
+            prk.to_vec(),
+            hex("077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5")
+        );
+        let mut okm = [0u8; 42];
+        hkdf.expand(&info, &mut okm).unwrap();
+        assert_eq!(
+            okm.to_vec(),
+            hex("3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865")
+        );
+    }
+
+    // 本协议密钥表的已知答案（用独立的 HMAC 实现计算得到）
+    #[test]
+    fn session_key_schedule_known_answer() {
+        let secret: Vec<u8> = (0..32).collect();
+        let transcript: [u8; 32] = Sha256::digest(b"transcript").into();
+        let keys = SessionKeys::derive(&secret, &transcript);
+
+        assert_eq!(
+            keys.client_to_server.key.to_vec(),
+            hex("52142812efd3a9386cbc9bfa762d48ffbbb7020a1366e3d1f9451fdf0e9af6a3")
+        );
+        assert_eq!(keys.client_to_server.nonce.to_vec(), hex("87dc738edf3870cd92b913d8"));
+        assert_eq!(
+            keys.client_to_server.mac_key.to_vec(),
+            hex("212d94043164113c15b1f970b8f720ce688eaa8d8a0f9fb6b52501f6e5f50aa6")
+        );
+        assert_eq!(
+            keys.server_to_client.key.to_vec(),
+            hex("f9a1df803500d90c19e5df6b1a4af951ac5dfcde67dd849f66c519a61e51594d")
+        );
+        assert_eq!(keys.server_to_client.nonce.to_vec(), hex("3c25dde52c9a4978f667234a"));
+        assert_eq!(
+            keys.server_to_client.mac_key.to_vec(),
+            hex("2a2a9e78d8a2d47cb1c8a78794145a056234389b21bce25a97d044b6432f3cf9")
+        );
+    }
+
+    #[test]
+    fn roles_see_mirrored_directions() {
+        let keys = SessionKeys::derive(b"secret", &[7u8; 32]);
+        assert_eq!(keys.sending(Role::Client), keys.receiving(Role::Server));
+        assert_eq!(keys.sending(Role::Server), keys.receiving(Role::Client));
+        assert_ne!(keys.sending(Role::Client), keys.sending(Role::Server));
+    }
+
+    #[test]
+    fn ratchet_is_deterministic_and_moves_forward() {
+        let keys = SessionKeys::derive(b"secret", &[3u8; 32]);
+        let current = keys.client_to_server.clone();
+        let next = current.ratchet();
+        assert_eq!(next, current.ratchet());
+        assert_ne!(next.key, current.key);
+        assert_ne!(next.nonce, current.nonce);
+        assert_ne!(next.mac_key, current.mac_key);
+        assert_ne!(next.ratchet(), current);
+        // 两个方向的棘轮互不相交
+        assert_ne!(keys.server_to_client.ratchet(), next);
+    }
+
+    // 只凭会话主密钥就能还原整张密钥表（decrypt-log 依赖这一点）
+    #[test]
+    fn session_key_alone_rebuilds_the_schedule() {
+        let keys = SessionKeys::derive(b"secret", &[5u8; 32]);
+        assert_eq!(SessionKeys::from_session_key(keys.session_key), keys);
+    }
+
+    // 同一个共享密钥，不同的握手记录必须得到不同的密钥
+    #[test]
+    fn transcript_binds_the_keys() {
+        let a = SessionKeys::derive(b"secret", &[1u8; 32]);
+        let b = SessionKeys::derive(b"secret", &[2u8; 32]);
+        assert_ne!(a.client_to_server.key, b.client_to_server.key);
+    }
+
+    // 和 Secret 一样，Debug 不显示任何密钥字节；握手结果的 Debug 也一样
+    #[test]
+    fn debug_output_hides_the_keys() {
+        use crate::cipher::CipherKind;
+        use crate::dh::DhGroup;
+        use crate::handshake::{Established, Hello};
+        use crate::kex::KexKind;
+
+        let keys = SessionKeys::derive(b"secret", &[6u8; 32]);
+        assert_eq!(format!("{keys:?}"), "SessionKeys(<redacted>)");
+        assert_eq!(format!("{:?}", keys.client_to_server), "DirectionSecrets(<redacted>)");
+
+        let hello =
+            Hello { cipher: CipherKind::Chacha20, kex: KexKind::Dh, group: DhGroup::Ffdhe2048, authenticate: false };
+        let leaked = format!("{:?}", keys.session_key);
+        let established = format!("{:?}", Established { hello, keys, peer_identity: None });
+        assert!(established.contains("SessionKeys(<redacted>)"), "{established}");
+        assert!(!established.contains(&leaked[1..leaked.len() - 1]), "{established}");
+    }
+}
@@ -0,0 +1,158 @@
+use crate::secret::Secret;
+use clap::ValueEnum;
+use rand::RngCore;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// 密钥交换算法：经典的有限域 DH（使用 --group 选择的群）或椭圆曲线 X25519
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum KexKind {
+    Dh,
+    X25519,
+}
+
+impl KexKind {
+    pub fn id(self) -> u8 {
+        match self {
+            KexKind::Dh => 1,
+            KexKind::X25519 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(KexKind::Dh),
+            2 => Some(KexKind::X25519),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KexKind::Dh => "dh",
+            KexKind::X25519 => "x25519",
+        }
+    }
+}
+
+// X25519 公钥和共享密钥的长度
+pub const X25519_LEN: usize = 32;
+
+// 一次握手用的临时 X25519 密钥对（RFC 7748）
+pub struct X25519Keypair {
+    private_key: StaticSecret,
+    public_key: PublicKey,
+}
+
+impl X25519Keypair {
+    pub fn generate() -> Self {
+        let mut bytes = Secret::new([0u8; X25519_LEN]);
+        rand::rng().fill_bytes(&mut bytes[..]);
+        Self::from_private(*bytes)
+    }
+
+    // 私钥会按 RFC 7748 进行 clamping
+    pub fn from_private(bytes: [u8; X25519_LEN]) -> Self {
+        let private_key = StaticSecret::from(bytes);
+        let public_key = PublicKey::from(&private_key);
+        X25519Keypair {
+            private_key,
+            public_key,
+        }
+    }
+
+    pub fn private_bytes(&self) -> Secret<[u8; X25519_LEN]> {
+        Secret::new(self.private_key.to_bytes())
+    }
+
+    pub fn public_bytes(&self) -> [u8; X25519_LEN] {
+        self.public_key.to_bytes()
+    }
+
+    // 计算共享密钥；对方发来小阶点时结果全为零，必须拒绝
+    pub fn shared_secret(&self, their_public: &[u8]) -> Result<Secret<Vec<u8>>, String> {
+        let their_public: [u8; X25519_LEN] = their_public.try_into().map_err(|_| {
+            format!(
+                "X25519 public key has {} bytes, expected {X25519_LEN}",
+                their_public.len()
+            )
+        })?;
+        let shared = self.private_key.diffie_hellman(&PublicKey::from(their_public));
+        if !shared.was_contributory() {
+            return Err("X25519 public key is a low-order point".to_string());
+        }
+        Ok(Secret::new(shared.as_bytes().to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex32(s: &str) -> [u8; 32] {
+        core::array::from_fn(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap())
+    }
+
+    // RFC 7748 5.2：单次标量乘法
+    #[test]
+    fn rfc7748_scalar_multiplication() {
+        let out = x25519_dalek::x25519(
+            hex32("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4"),
+            hex32("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c"),
+        );
+        assert_eq!(out, hex32("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552"));
+
+        let out = x25519_dalek::x25519(
+            hex32("4b66e9d4d1b4673c5ad22691957d6af5c11b6421e0ea01d42ca4169e7918ba0d"),
+            hex32("e5210f12786811d3f4b7959d0538ae2c31dbe7106fc03c3efc4cd549c715a493"),
+        );
+        assert_eq!(out, hex32("95cbde9476e8907d7aade45cb4b873f88b595a68799fa152e6f8f7647aac7957"));
+    }
+
+    // RFC 7748 5.2：迭代 1000 次
+    #[test]
+    fn rfc7748_iterated() {
+        let mut k = hex32("0900000000000000000000000000000000000000000000000000000000000000");
+        let mut u = k;
+        for i in 1..=1000 {
+            let out = x25519_dalek::x25519(k, u);
+            u = k;
+            k = out;
+            if i == 1 {
+                assert_eq!(k, hex32("422c8e7a6227d7bca1350b3e2bb7279f7897b87bb6854b783c60e80311ae3079"));
+            }
+        }
+        assert_eq!(k, hex32("684cf59ba83309552800ef566f2f4d3c1c3887c49360e3875f2eb94d99532c51"));
+    }
+
+    // RFC 7748 6.1：Alice 和 Bob 的完整交换
+    #[test]
+    fn rfc7748_key_agreement() {
+        let alice = X25519Keypair::from_private(hex32(
+            "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a",
+        ));
+        let bob = X25519Keypair::from_private(hex32(
+            "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb",
+        ));
+        assert_eq!(
+            alice.public_bytes(),
+            hex32("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a")
+        );
+        assert_eq!(
+            bob.public_bytes(),
+            hex32("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f")
+        );
+        let shared = hex32("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        assert_eq!(alice.shared_secret(&bob.public_bytes()).unwrap()[..], shared);
+        assert_eq!(bob.shared_secret(&alice.public_bytes()).unwrap()[..], shared);
+    }
+
+    #[test]
+    fn rejects_low_order_and_short_keys() {
+        let keypair = X25519Keypair::generate();
+        assert!(keypair.shared_secret(&[0u8; 32]).is_err());
+        let mut one = [0u8; 32];
+        one[0] = 1;
+        assert!(keypair.shared_secret(&one).is_err());
+        assert!(keypair.shared_secret(&[9u8; 31]).is_err());
+    }
+}
@@ -0,0 +1,36 @@
+// 加密聊天协议库：握手、密钥流、分帧和会话都可以脱离命令行单独使用，
+// 并且可以运行在任意 Read + Write 的字节流上（包括测试用的内存管道）
+
+// 必须放在最前面，后面的模块才能直接使用 say!、info!、verbose! 和 teach!
+#[macro_use]
+pub mod log;
+
+pub mod chat;
+pub mod cipher;
+pub mod crack;
+pub mod dh;
+pub mod error;
+pub mod frame;
+pub mod handshake;
+pub mod identity;
+pub mod kdf;
+pub mod kex;
+pub mod mac;
+pub mod mitm;
+pub mod montgomery;
+pub mod net;
+pub mod prime;
+pub mod record;
+pub mod room;
+pub mod secret;
+pub mod session;
+pub mod transfer;
+pub mod transport;
+pub mod tui;
+
+pub use cipher::Keystream;
+pub use error::ChatError;
+pub use frame::Frame;
+pub use handshake::Handshake;
+pub use session::Session;
+pub use transport::Transport;
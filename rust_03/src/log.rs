@@ -0,0 +1,118 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::mpsc::Sender;
+
+// 输出的详细程度，由低到高，每一级都包含前一级的全部输出
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    // 只显示聊天内容、需要回应的提示、警告和错误
+    Quiet,
+    // 默认：连接、握手结果、身份指纹、文件传输进度等状态
+    Normal,
+    // 再加上每条消息的序列号、MAC 标签、换钥和网络字节数，都是公开信息
+    Verbose,
+    // 教学模式：完整的逐步演示，包括私钥、共享密钥、会话密钥、密钥流和明文字节。
+    // 秘密只会在这一级出现
+    Teach,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Normal as u8);
+
+// 整个进程共用一个级别，启动时由命令行参数设置一次
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    decode(LEVEL.load(Ordering::Relaxed))
+}
+
+fn decode(value: u8) -> Level {
+    match value {
+        0 => Level::Quiet,
+        1 => Level::Normal,
+        2 => Level::Verbose,
+        _ => Level::Teach,
+    }
+}
+
+// 当前级别是否会显示 level 这一级的输出
+pub fn shows(level: Level) -> bool {
+    self::level() >= level
+}
+
+// 输出的去向：默认打印到 stdout；终端界面（tui.rs）运行期间，每一行连同级别发给界面线程，
+// 避免打印打乱界面
+static SINK: Mutex<Option<Sender<(Level, String)>>> = Mutex::new(None);
+
+// 把输出改发到 sink；传入 None 恢复打印到 stdout
+pub fn redirect(sink: Option<Sender<(Level, String)>>) {
+    *SINK.lock().unwrap_or_else(|e| e.into_inner()) = sink;
+}
+
+// 输出一行，宏都经过这里；调用前已经按级别过滤过
+pub fn emit(level: Level, args: fmt::Arguments) {
+    match &*SINK.lock().unwrap_or_else(|e| e.into_inner()) {
+        Some(sink) => {
+            let _ = sink.send((level, args.to_string()));
+        }
+        None => println!("{args}"),
+    }
+}
+
+// 聊天内容、提示、警告和错误：任何级别都显示
+#[macro_export]
+macro_rules! say {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Quiet, format_args!($($arg)*))
+    };
+}
+
+// 普通状态信息：默认显示，--quiet 时隐藏
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::shows($crate::log::Level::Normal) {
+            $crate::log::emit($crate::log::Level::Normal, format_args!($($arg)*));
+        }
+    };
+}
+
+// 公开的协议细节：--verbose 及以上显示
+#[macro_export]
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::log::shows($crate::log::Level::Verbose) {
+            $crate::log::emit($crate::log::Level::Verbose, format_args!($($arg)*));
+        }
+    };
+}
+
+// 教学演示，可能包含秘密：只在 --teach 时显示
+#[macro_export]
+macro_rules! teach {
+    ($($arg:tt)*) => {
+        if $crate::log::shows($crate::log::Level::Teach) {
+            $crate::log::emit($crate::log::Level::Teach, format_args!($($arg)*));
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 秘密只允许出现在最高一级
+    #[test]
+    fn levels_are_ordered_and_round_trip() {
+        let levels = [Level::Quiet, Level::Normal, Level::Verbose, Level::Teach];
+        for pair in levels.windows(2) {
+            assert!(pair[0] < pair[1]);
+        }
+        for level in levels {
+            assert_eq!(decode(level as u8), level);
+        }
+        assert_eq!(levels.iter().max(), Some(&Level::Teach));
+    }
+}
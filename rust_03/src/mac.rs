@@ -0,0 +1,83 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// HMAC-SHA256 标签长度
+pub const TAG_LEN: usize = 32;
+
+// Encrypt-then-MAC：在密文后面附上标签。
+// 标签同时覆盖不随消息发送的 header（例如帧类型），发送的只有 ciphertext || tag
+pub fn seal(key: &[u8; 32], header: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(header);
+    mac.update(ciphertext);
+    let mut message = ciphertext.to_vec();
+    message.extend_from_slice(&mac.finalize().into_bytes());
+    message
+}
+
+// 校验标签并返回其中的密文；比较是常数时间的
+pub fn open<'a>(key: &[u8; 32], header: &[u8], message: &'a [u8]) -> Result<&'a [u8], String> {
+    if message.len() < TAG_LEN {
+        return Err(format!(
+            "message too short for an authentication tag ({} bytes)",
+            message.len()
+        ));
+    }
+    let (ciphertext, tag) = message.split_at(message.len() - TAG_LEN);
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(header);
+    mac.update(ciphertext);
+    mac.verify_slice(tag)
+        .map_err(|_| "authentication tag mismatch".to_string())?;
+    Ok(ciphertext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4231 测试用例 2
+    #[test]
+    fn hmac_sha256_rfc4231() {
+        let mut mac = HmacSha256::new_from_slice(b"Jefe").unwrap();
+        mac.update(b"what do ya want for nothing?");
+        let tag: [u8; 32] = mac.finalize().into_bytes().into();
+        assert_eq!(
+            tag,
+            [
+                0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e,
+                0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75, 0xc7,
+                0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83,
+                0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec, 0x38, 0x43,
+            ]
+        );
+    }
+
+    #[test]
+    fn open_rejects_flipped_and_truncated_messages() {
+        let key = [42u8; 32];
+        let sealed = seal(&key, &[], b"ciphertext");
+        assert_eq!(open(&key, &[], &sealed).unwrap(), b"ciphertext");
+
+        for i in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 0x01;
+            assert!(open(&key, &[], &tampered).is_err(), "flip at byte {i} accepted");
+        }
+        assert!(open(&key, &[], &sealed[..sealed.len() - 1]).is_err());
+        assert!(open(&key, &[], &sealed[..10]).is_err());
+        assert!(open(&[43u8; 32], &[], &sealed).is_err());
+    }
+
+    #[test]
+    fn header_is_authenticated_but_not_sent() {
+        let key = [42u8; 32];
+        let sealed = seal(&key, &[1], b"ciphertext");
+        assert_eq!(sealed.len(), b"ciphertext".len() + TAG_LEN);
+        assert_eq!(open(&key, &[1], &sealed).unwrap(), b"ciphertext");
+        assert!(open(&key, &[3], &sealed).is_err());
+        assert!(open(&key, &[], &sealed).is_err());
+    }
+}
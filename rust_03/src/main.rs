@@ -1,284 +1,758 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use rust_03::chat::{self, RekeyPolicy, Timeouts};
+use rust_03::cipher::CipherKind;
+use rust_03::dh::{self, DhGroup, DhParams, DhPolicy};
+use rust_03::frame::FrameType;
+use rust_03::handshake::{Hello, Role};
+use rust_03::identity::{self, Identity, KnownPeers, Trust};
+use rust_03::kex::KexKind;
+use rust_03::log::{self, Level};
+use rust_03::net;
+use rust_03::record::{self, Recorded};
+use rust_03::transfer::{self, FileMessage, Transfers};
+use rust_03::transport::{self, Duplex};
+use rust_03::{ChatError, Handshake, Session, Transport, crack, info, mitm, prime, room, say, tui, verbose};
+use std::fs::{self, File};
+use std::io::{self, IsTerminal};
 use std::net::{TcpListener, TcpStream};
-use std::io::{self, Read, Write};
-
-// DH 参数（64 位素数和生成元）
-const P: u64 = 0xD87FA3E291B4C7F3;
-const G: u64 = 2;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 
 // 命令行参数结构
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Stream cipher chat with Diffie-Hellman key generation")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    #[command(flatten)]
+    verbosity: Verbosity,
+}
+
+// 输出详细程度，对所有子命令有效；什么都不加就是 normal
+#[derive(clap::Args, Debug)]
+#[command(next_help_heading = "Output")]
+struct Verbosity {
+    /// Only show chat messages, prompts, warnings and errors
+    #[arg(long, short, global = true, conflicts_with_all = ["verbose", "teach"])]
+    quiet: bool,
+    /// Also show sequence numbers, MAC tags, rekeys and byte counts (no secrets)
+    #[arg(long, short, global = true, conflicts_with = "teach")]
+    verbose: bool,
+    /// Step-by-step walkthrough that prints private keys, session keys and keystream bytes
+    #[arg(long, global = true)]
+    teach: bool,
+}
+
+impl Verbosity {
+    fn level(&self) -> Level {
+        if self.teach {
+            Level::Teach
+        } else if self.verbose {
+            Level::Verbose
+        } else if self.quiet {
+            Level::Quiet
+        } else {
+            Level::Normal
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
 enum Command {
     #[command(name = "server")]
     Server {
+        #[arg(default_value = "8080", conflicts_with_all = ["unix", "stdio"])]
+        port: u16,
+        /// Address to listen on, e.g. 127.0.0.1 or [::1]:9000 [default: every IPv4 and IPv6 interface]
+        #[arg(long, value_name = "ADDR", conflicts_with_all = ["unix", "stdio"])]
+        bind: Option<String>,
+        /// Stream cipher backend (must match the client)
+        #[arg(long, value_enum, default_value_t = CipherKind::Chacha20)]
+        cipher: CipherKind,
+        /// Key exchange: finite-field DH or X25519 (must match the peer)
+        #[arg(long, value_enum, default_value_t = KexKind::Dh)]
+        kex: KexKind,
+        /// Diffie-Hellman group (must match the peer)
+        #[arg(long, value_enum, default_value_t = DhGroup::Ffdhe2048)]
+        group: DhGroup,
+        /// Skip identity signatures (teaching only: allows a man in the middle)
+        #[arg(long)]
+        no_auth: bool,
+        #[command(flatten)]
+        session: SessionArgs,
+    },
+    // 多人聊天室：接受任意多个客户端，把每条消息转发给其他所有人
+    #[command(name = "room")]
+    Room {
         #[arg(default_value = "8080")]
         port: u16,
+        /// Address to listen on, e.g. 127.0.0.1 or [::1]:9000 [default: every IPv4 and IPv6 interface]
+        #[arg(long, value_name = "ADDR")]
+        bind: Option<String>,
+        /// Stream cipher backend (every client must use the same one)
+        #[arg(long, value_enum, default_value_t = CipherKind::Chacha20)]
+        cipher: CipherKind,
+        /// Key exchange: finite-field DH or X25519 (must match the peer)
+        #[arg(long, value_enum, default_value_t = KexKind::Dh)]
+        kex: KexKind,
+        /// Diffie-Hellman group (must match the peer)
+        #[arg(long, value_enum, default_value_t = DhGroup::Ffdhe2048)]
+        group: DhGroup,
+        /// Skip identity signatures (teaching only: allows a man in the middle)
+        #[arg(long)]
+        no_auth: bool,
+        /// Refuse DH groups with fewer bits than this (lower it to 64 to use --group toy64)
+        #[arg(long, default_value_t = DhPolicy::default().min_bits)]
+        min_dh_bits: u64,
+        /// Directory holding the identity key and known_peers [default: $HOME/.rust_03]
+        #[arg(long)]
+        config_dir: Option<PathBuf>,
     },
     #[command(name = "client")]
     Client {
-        #[arg(default_value = "127.0.0.1:8080")]
+        #[arg(default_value = "127.0.0.1:8080", conflicts_with_all = ["unix", "stdio"])]
         addr: String,
+        /// Name the server behind a --stdio tunnel is remembered by in known_peers (e.g. the ssh host)
+        #[arg(long, value_name = "NAME", requires = "stdio", required_if_eq("stdio", "true"))]
+        peer_name: Option<String>,
+        /// Stream cipher backend (must match the server)
+        #[arg(long, value_enum, default_value_t = CipherKind::Chacha20)]
+        cipher: CipherKind,
+        /// Key exchange: finite-field DH or X25519 (must match the peer)
+        #[arg(long, value_enum, default_value_t = KexKind::Dh)]
+        kex: KexKind,
+        /// Diffie-Hellman group (must match the peer)
+        #[arg(long, value_enum, default_value_t = DhGroup::Ffdhe2048)]
+        group: DhGroup,
+        /// Skip identity signatures (teaching only: allows a man in the middle)
+        #[arg(long)]
+        no_auth: bool,
+        #[command(flatten)]
+        session: SessionArgs,
+    },
+    // 中间人演示：分别与客户端和服务器做密钥交换，解密并打印所有流量（仅限本机）
+    #[command(name = "mitm")]
+    Mitm {
+        /// Address to accept the client on (loopback only)
+        #[arg(default_value = "127.0.0.1:9090")]
+        listen: String,
+        /// Address of the real server (loopback only)
+        #[arg(default_value = "127.0.0.1:8080")]
+        upstream: String,
+        /// Stream cipher backend (must match both peers)
+        #[arg(long, value_enum, default_value_t = CipherKind::Chacha20)]
+        cipher: CipherKind,
+        /// Key exchange: finite-field DH or X25519 (must match both peers)
+        #[arg(long, value_enum, default_value_t = KexKind::Dh)]
+        kex: KexKind,
+        /// Diffie-Hellman group (must match both peers)
+        #[arg(long, value_enum, default_value_t = DhGroup::Ffdhe2048)]
+        group: DhGroup,
+        /// Must match the peers; with authentication the MITM is exposed
+        #[arg(long)]
+        no_auth: bool,
+        /// DH parameters written by genparams (implies --group custom; the server must use the same file)
+        #[arg(long)]
+        params: Option<PathBuf>,
+        /// Refuse DH groups with fewer bits than this (lower it to 64 to use --group toy64)
+        #[arg(long, default_value_t = DhPolicy::default().min_bits)]
+        min_dh_bits: u64,
+    },
+    // 密码分析演示：凭几个已知明文字节还原 LCG 的 32 位状态，解密截获的整段对话
+    #[command(name = "crack")]
+    Crack {
+        /// Raw bytes captured from one direction of an LCG session (handshake frames are skipped)
+        capture: PathBuf,
+        /// Plaintext known to start the target message (4 or more bytes is usually enough)
+        #[arg(long)]
+        known: String,
+        /// Sequence number of the message the known plaintext belongs to [default: first chat message]
+        #[arg(long)]
+        seq: Option<u64>,
+    },
+    // 生成自定义 DH 参数（安全素数和生成元），供 server/client --params 使用
+    #[command(name = "genparams")]
+    GenParams {
+        /// Size of the safe prime p in bits (2048 or more for real use)
+        #[arg(long, default_value_t = 2048)]
+        bits: u64,
+        /// File to write the parameters to
+        #[arg(long, short, default_value = "dhparams.txt")]
+        out: PathBuf,
+    },
+    // 离线解密 --record 录制的日志，按时间顺序还原双方的对话
+    #[command(name = "decrypt-log")]
+    DecryptLog {
+        /// Recording written by server/client --record
+        log: PathBuf,
+        /// File holding the session key [default: LOG.key, written next to the recording]
+        #[arg(long, value_name = "FILE")]
+        key: Option<PathBuf>,
     },
 }
 
-// 流密码生成器（线性同余发生器 LCG）
-struct LCG {
-    a: u32,
-    c: u32,
-    m: u64,  // 改为 u64 避免字面量溢出
-    next: u32,
+// 服务器和客户端共用的会话参数
+#[derive(clap::Args, Debug)]
+struct SessionArgs {
+    /// Ratchet to fresh keys after this many sent messages
+    #[arg(long, default_value_t = RekeyPolicy::default().max_messages)]
+    rekey_messages: u64,
+    /// Ratchet to fresh keys after this many sent plaintext bytes
+    #[arg(long, default_value_t = RekeyPolicy::default().max_bytes)]
+    rekey_bytes: u64,
+    /// Directory holding the identity key and known_peers [default: $HOME/.rust_03]
+    #[arg(long)]
+    config_dir: Option<PathBuf>,
+    /// Full-screen chat with scrollback, a fixed input line and a status bar (F2 shows the --verbose/--teach trace)
+    #[arg(long, conflicts_with = "stdio")]
+    tui: bool,
+    /// Where files received with /accept are saved
+    #[arg(long, default_value = "downloads")]
+    download_dir: PathBuf,
+    /// Record every frame sent and received, with timestamps, to this file (read it with decrypt-log)
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// DH parameters written by genparams (implies --group custom; the client then requires exactly these)
+    #[arg(long)]
+    params: Option<PathBuf>,
+    /// Refuse custom DH parameters with fewer bits than this
+    #[arg(long, default_value_t = DhPolicy::default().min_bits)]
+    min_dh_bits: u64,
+    #[command(flatten)]
+    transport: TransportArgs,
+    #[command(flatten)]
+    timeouts: TimeoutArgs,
 }
 
-impl LCG {
-    fn new(seed: u32) -> Self {
-        LCG {
-            a: 1103515245,
-            c: 12345,
-            m: 0x100000000,  // 2^32，用 u64 存储
-            next: seed,
+impl SessionArgs {
+    fn rekey_policy(&self) -> RekeyPolicy {
+        RekeyPolicy { max_messages: self.rekey_messages, max_bytes: self.rekey_bytes }
+    }
+
+    fn config_dir(&self) -> PathBuf {
+        self.config_dir.clone().unwrap_or_else(identity::default_config_dir)
+    }
+
+    fn dh_policy(&self) -> DhPolicy {
+        DhPolicy { min_bits: self.min_dh_bits, ..DhPolicy::default() }
+    }
+
+    // toy64 这样短于 --min-dh-bits 的标准群在等待连接之前就拒绝，不要等握手时才报错。
+    // 自定义群的参数由服务器发送，所以服务器一方还必须有 --params；客户端没有时接受服务器发来的参数
+    fn check_group(&self, hello: Hello, role: Role) -> Result<(), ChatError> {
+        if role == Role::Server && hello.kex == KexKind::Dh && hello.group == DhGroup::Custom && self.params.is_none() {
+            return Err(ChatError::Usage(
+                "--group custom needs --params FILE on the server (create it with genparams)".to_string(),
+            ));
         }
+        check_group(self.dh_policy(), hello)
     }
 
-    fn next_byte(&mut self) -> u8 {
-        self.next = ((self.a as u64 * self.next as u64 + self.c as u64) % self.m) as u32;
-        (self.next >> 24) as u8  // 取高 8 位作为字节输出
+    // 终端界面要画在 stdout 上，在等待连接之前就检查，不要等握手完才报错
+    fn check_tui(&self) -> Result<(), ChatError> {
+        if self.tui && !io::stdout().is_terminal() {
+            return Err(ChatError::Usage("--tui needs a terminal on stdout".to_string()));
+        }
+        Ok(())
+    }
+
+    // 加载了参数文件时改用自定义群
+    fn group(&self, requested: DhGroup) -> DhGroup {
+        if self.params.is_some() { DhGroup::Custom } else { requested }
+    }
+
+    fn dh_params(&self) -> Result<Option<DhParams>, ChatError> {
+        self.params.as_deref().map(|path| load_params(path, self.dh_policy())).transpose()
     }
 }
 
-// DH 密钥交换逻辑
-fn dh_exchange(stream: &mut TcpStream) -> u32 {
-    // 生成随机私钥（64 位）：使用最新 rand 库的 random() 方法
-    let private_key: u64 = rand::random();
-    println!("[DH] Generating our keypair...");
-    println!("private_key = {private_key:016X} (random 64-bit)");
-
-    // 计算公钥：g^private mod p
-    let public_key = mod_pow(G, private_key, P);
-    println!("public_key = g^private mod p");
-    println!("= {G}^{private_key:016X} mod {P:016X}");
-    println!("= {public_key:016X}");
-
-    // 发送自己的公钥
-    println!("[NETWORK] Sending public key (8 bytes)...");
-    println!("→ Send our public: {public_key:016X}");
-    stream.write_all(&public_key.to_be_bytes()).unwrap();
-
-    // 接收对方的公钥
-    let mut their_public = [0u8; 8];
-    stream.read_exact(&mut their_public).unwrap();
-    let their_public = u64::from_be_bytes(their_public);
-    println!("[NETWORK] Received public key (8 bytes) ✓");
-    println!("← Receive their public: {their_public:016X}");
-
-    // 计算共享密钥：their_public^private mod p
-    let shared_secret = mod_pow(their_public, private_key, P);
-    println!("[DH] Computing shared secret...");
-    println!("Formula: secret = (their_public)^(our_private) mod p");
-    println!("secret = ({their_public:016X})^{private_key:016X} mod {P:016X}");
-    println!("= {shared_secret:016X}");
-
-    // 将 64 位共享密钥截断为 32 位，作为 LCG 种子
-    (shared_secret >> 32) as u32
+// 连接方式：默认 TCP，也可以是 Unix 域套接字或者 stdin/stdout
+#[derive(clap::Args, Debug)]
+#[group(multiple = false)]
+struct TransportArgs {
+    /// Use a Unix-domain socket at this path instead of TCP
+    #[arg(long, value_name = "PATH")]
+    unix: Option<PathBuf>,
+    /// Run the protocol over stdin/stdout (for ssh or socat tunnels); chat input then comes from the terminal
+    #[arg(long)]
+    stdio: bool,
 }
 
-// 快速幂取模（用于 DH 密钥计算）
-fn mod_pow(mut base: u64, mut exp: u64, modulus: u64) -> u64 {
-    if modulus == 1 {
-        return 0;
+impl TransportArgs {
+    // --stdio 时把 stdin/stdout 交给协议。之后的提示信息都转到 stderr，所以必须在打印任何东西之前调用
+    fn open_stdio(&self) -> Result<Option<Duplex>, ChatError> {
+        if !self.stdio {
+            return Ok(None);
+        }
+        transport::stdio().map(Some).map_err(ChatError::io("taking over stdin/stdout"))
     }
-    let mut result = 1;
-    base %= modulus;
-    while exp > 0 {
-        if exp % 2 == 1 {
-            result = (result * base) % modulus;
+}
+
+// 服务器和客户端共用的超时参数（单位：秒）
+#[derive(clap::Args, Debug)]
+struct TimeoutArgs {
+    /// Give up if the peer has not completed the handshake after this many seconds
+    #[arg(long, default_value_t = Timeouts::default().handshake.as_secs())]
+    handshake_timeout: u64,
+    /// Send an encrypted ping every this many seconds
+    #[arg(long, default_value_t = Timeouts::default().heartbeat.as_secs())]
+    heartbeat: u64,
+    /// Declare the peer dead after this many seconds without any frame from it
+    #[arg(long, default_value_t = Timeouts::default().dead_peer.as_secs())]
+    dead_peer_timeout: u64,
+    /// Close the session after this many seconds without chat messages (0 = never)
+    #[arg(long, default_value_t = 0)]
+    idle_timeout: u64,
+}
+
+impl TimeoutArgs {
+    fn timeouts(&self) -> Result<Timeouts, ChatError> {
+        if self.heartbeat == 0 || self.heartbeat >= self.dead_peer_timeout {
+            return Err(ChatError::Usage(format!(
+                "--heartbeat ({}s) must be non-zero and shorter than --dead-peer-timeout ({}s)",
+                self.heartbeat, self.dead_peer_timeout
+            )));
         }
-        exp >>= 1;
-        base = (base * base) % modulus;
+        Ok(Timeouts {
+            handshake: Duration::from_secs(self.handshake_timeout),
+            heartbeat: Duration::from_secs(self.heartbeat),
+            dead_peer: Duration::from_secs(self.dead_peer_timeout),
+            idle: (self.idle_timeout > 0).then(|| Duration::from_secs(self.idle_timeout)),
+        })
     }
-    result
 }
 
 // 服务器逻辑
-fn run_server(port: u16) {
-    let listener = TcpListener::bind(format!("0.0.0.0:{port}")).unwrap();
-    println!("[SERVER] Listening on 0.0.0.0:{port}");
-    println!("[SERVER] Waiting for client...");
-
-    let (mut stream, addr) = listener.accept().unwrap();
-    println!("[CLIENT] Connected from {addr}");
-
-    println!("[DH] Starting key exchange...");
-    println!("[DH] Using hardcoded DH parameters:");
-    println!("p = {P:016X} (64-bit prime - public)");
-    println!("g = {G} (generator - public)");
-
-    // 执行 DH 密钥交换，获取流密码种子
-    let seed = dh_exchange(&mut stream);
-    println!("[VERIFY] Both sides computed the same secret ✓");
-
-    // 初始化流密码生成器
-    let mut keystream = LCG::new(seed);
-    println!("[STREAM] Generating keystream from secret...");
-    println!("Algorithm: LCG (a=1103515245, c=12345, m=2^32)");
-    println!("Seed: secret = {seed:08X}");
-
-    // 启动聊天循环
-    println!("✓ Secure channel established!");
-    println!("[CHAT] Type message:");
-
-    let (mut reader, mut writer) = (stream.try_clone().unwrap(), stream);
-    let mut input = String::new();
-
-    loop {
-        // 发送消息逻辑
-        io::stdin().read_line(&mut input).unwrap();
-        let msg = input.trim().as_bytes();
-        if msg.is_empty() {
-            input.clear();
-            continue;
-        }
+fn run_server(port: u16, bind: Option<&str>, hello: Hello, args: &SessionArgs) -> Result<(), ChatError> {
+    let stdio = args.transport.open_stdio()?;
+    args.check_tui()?;
+    args.check_group(hello, Role::Server)?;
+    let timeouts = args.timeouts.timeouts()?;
+    let identity = load_identity(&args.config_dir())?;
+    let params = args.dh_params()?;
+    let handshake = dh_handshake(Handshake::new(Role::Server, hello, &identity), params.as_ref(), args);
+
+    if let Some(stream) = stdio {
+        info!("[SERVER] Speaking the protocol over stdin/stdout");
+        return server_session(stream, "stdio", &handshake, args, timeouts);
+    }
+    if let Some(path) = &args.transport.unix {
+        let listener = bind_unix(path)?;
+        info!("[SERVER] Listening on {}", path.display());
+        info!("[SERVER] Waiting for client...");
+        let accepted = listener.accept().map_err(ChatError::io("accepting a client"));
+        // 套接字文件只服务这一次连接，接受之后就删掉
+        let _ = fs::remove_file(path);
+        let (stream, _) = accepted?;
+        info!("[CLIENT] Connected on {}", path.display());
+        return server_session(stream, &format!("unix:{}", path.display()), &handshake, args, timeouts);
+    }
 
-        // 加密消息（流密码 XOR）：使用 idx 作为循环变量
-        let mut ciphertext = vec![0u8; msg.len()];
-        for idx in 0..msg.len() {
-            ciphertext[idx] = msg[idx] ^ keystream.next_byte();
-        }
+    let listener = listen_tcp(bind, port)?;
+    info!("[SERVER] Listening on {}", net::describe(&listener));
+    info!("[SERVER] Waiting for client...");
 
-        println!("[ENCRYPT]");
-        println!("Plain: {:?} (\"{}\")", msg, std::str::from_utf8(msg).unwrap_or(""));
-        print!("Key: ");
-        for idx in 0..msg.len() {
-            print!("{:02X} ", keystream.next_byte());
-        }
-        println!("\nCipher: {:?}", ciphertext);
-
-        // 发送加密消息
-        writer.write_all(&ciphertext).unwrap();
-        println!("[NETWORK] Sending encrypted message ({} bytes)...", msg.len());
-        println!("[→] Sent {} bytes", msg.len());
-        input.clear();
-
-        // 接收消息逻辑
-        let mut buffer = [0u8; 1024];
-        let n = reader.read(&mut buffer).unwrap();
-        if n == 0 {
-            break;
-        }
+    let (stream, addr) = listener.accept().map_err(ChatError::io("accepting a client"))?;
+    let addr = net::canonical(addr);
+    info!("[CLIENT] Connected from {addr}");
+    server_session(stream, &addr.to_string(), &handshake, args, timeouts)
+}
 
-        // 解密消息：使用 idx 作为循环变量
-        let mut plaintext = vec![0u8; n];
-        for idx in 0..n {
-            plaintext[idx] = buffer[idx] ^ keystream.next_byte();
-        }
+// 在已经建立的连接上完成握手（服务器一方），然后进入聊天
+fn server_session(
+    stream: impl Transport,
+    client: &str,
+    handshake: &Handshake,
+    args: &SessionArgs,
+    timeouts: Timeouts,
+) -> Result<(), ChatError> {
+    let stream = recorded(stream, Role::Server, args.record.as_deref())?;
+
+    // 协商算法、执行密钥交换，并用 HKDF 派生两个方向的会话密钥
+    let session = Session::establish(stream, handshake, args.rekey_policy(), timeouts)?;
+    match session.peer_identity() {
+        Some(peer) => info!("[IDENTITY] Client fingerprint: {}", identity::fingerprint(peer)),
+        None => say!("[IDENTITY] ⚠ Client is NOT authenticated"),
+    }
+    chat(session, client, args)
+}
 
-        println!("[NETWORK] Received encrypted message ({} bytes)", n);
-        println!("[←] Received {} bytes", n);
-        println!("[DECRYPT]");
-        println!("Cipher: {:?}", &buffer[0..n]);
-        print!("Key: ");
-        for idx in 0..n {
-            print!("{:02X} ", keystream.next_byte());
+// 绑定 Unix 域套接字。上次异常退出可能留下套接字文件：确认它是套接字、而且没有服务器还在监听，才删掉重建
+fn bind_unix(path: &Path) -> Result<UnixListener, ChatError> {
+    let context = format!("binding {}", path.display());
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        if UnixStream::connect(path).is_ok() {
+            return Err(ChatError::io(context)(io::ErrorKind::AddrInUse.into()));
         }
-        println!("\nPlain: {:?} → \"{}\"", plaintext, std::str::from_utf8(&plaintext).unwrap_or(""));
-        println!("[TEST] Round-trip verified: \"{}\" → encrypt → decrypt → \"{}\" ✓",
-            std::str::from_utf8(&plaintext).unwrap_or(""),
-            std::str::from_utf8(&plaintext).unwrap_or("")
-        );
-        println!("[CLIENT] {}", std::str::from_utf8(&plaintext).unwrap_or(""));
+        fs::remove_file(path).map_err(ChatError::io(context.clone()))?;
+    }
+    UnixListener::bind(path).map_err(ChatError::io(context))
+}
+
+// 打开 TCP 监听：没有 --bind 时在 [::]:port 上双栈监听，同时覆盖 IPv4 和 IPv6
+fn listen_tcp(bind: Option<&str>, port: u16) -> Result<TcpListener, ChatError> {
+    let addr = match bind {
+        Some(spec) => net::parse_bind(spec, port).map_err(ChatError::Usage)?,
+        None => net::any_address(port),
+    };
+    net::listen(addr).map_err(ChatError::io(format!("binding {addr}")))
+}
+
+// 聊天室服务器逻辑
+fn run_room(port: u16, bind: Option<&str>, hello: Hello, policy: DhPolicy, config_dir: &Path) -> Result<(), ChatError> {
+    if hello.kex == KexKind::Dh && hello.group == DhGroup::Custom {
+        return Err(ChatError::Usage("the room has no --params, so it cannot use --group custom".to_string()));
     }
+    check_group(policy, hello)?;
+    let identity = Arc::new(load_identity(config_dir)?);
+    let listener = listen_tcp(bind, port)?;
+    info!(
+        "[ROOM] Listening on {} (cipher: {}, group: {})",
+        net::describe(&listener),
+        hello.cipher.name(),
+        hello.group.name()
+    );
+    info!("[ROOM] Waiting for clients...");
+    room::serve(listener, hello, policy, identity, Timeouts::default().handshake);
+    Ok(())
 }
 
 // 客户端逻辑
-fn run_client(addr: String) {
-    let mut stream = TcpStream::connect(addr).unwrap();
-
-    println!("[DH] Starting key exchange...");
-    println!("[DH] Using hardcoded DH parameters:");
-    println!("p = {P:016X} (64-bit prime - public)");
-    println!("g = {G} (generator - public)");
-
-    // 执行 DH 密钥交换，获取流密码种子
-    let seed = dh_exchange(&mut stream);
-    println!("[VERIFY] Both sides computed the same secret ✓");
-
-    // 初始化流密码生成器
-    let mut keystream = LCG::new(seed);
-    println!("[STREAM] Generating keystream from secret...");
-    println!("Algorithm: LCG (a=1103515245, c=12345, m=2^32)");
-    println!("Seed: secret = {seed:08X}");
-
-    // 启动聊天循环
-    println!("✓ Secure channel established!");
-    println!("[CHAT] Type message:");
-
-    let (mut reader, mut writer) = (stream.try_clone().unwrap(), stream);
-    let mut input = String::new();
-
-    loop {
-        // 接收消息逻辑
-        let mut buffer = [0u8; 1024];
-        let n = reader.read(&mut buffer).unwrap();
-        if n == 0 {
-            break;
-        }
+fn run_client(addr: String, peer_name: Option<&str>, hello: Hello, args: &SessionArgs) -> Result<(), ChatError> {
+    let stdio = args.transport.open_stdio()?;
+    args.check_tui()?;
+    args.check_group(hello, Role::Client)?;
+    let timeouts = args.timeouts.timeouts()?;
+    let config_dir = args.config_dir();
+    let identity = load_identity(&config_dir)?;
+    let params = args.dh_params()?;
+    let mut known_peers = KnownPeers::load(&config_dir).map_err(ChatError::io("reading known_peers"))?;
+    let handshake = dh_handshake(Handshake::new(Role::Client, hello, &identity), params.as_ref(), args);
+
+    // known_peers 按连接的目标记录服务器指纹；--stdio 看不到隧道的另一头是谁，由 --peer-name 命名
+    if let Some(stream) = stdio {
+        info!("[CLIENT] Speaking the protocol over stdin/stdout");
+        let name = format!("stdio:{}", peer_name.unwrap_or_default());
+        return client_session(stream, &name, &mut known_peers, &handshake, args, timeouts);
+    }
+    if let Some(path) = &args.transport.unix {
+        let stream =
+            UnixStream::connect(path).map_err(ChatError::io(format!("connecting to {}", path.display())))?;
+        let name = format!("unix:{}", path.display());
+        return client_session(stream, &name, &mut known_peers, &handshake, args, timeouts);
+    }
+    // 主机名可能解析出多个地址（IPv6 和 IPv4），依次尝试直到有一个连上
+    let stream = net::connect(&addr).map_err(ChatError::io(format!("connecting to {addr}")))?;
+    client_session(stream, &addr, &mut known_peers, &handshake, args, timeouts)
+}
 
-        // 解密消息：使用 idx 作为循环变量
-        let mut plaintext = vec![0u8; n];
-        for idx in 0..n {
-            plaintext[idx] = buffer[idx] ^ keystream.next_byte();
+// 在已经建立的连接上完成握手（客户端一方），核对服务器指纹，然后进入聊天
+fn client_session(
+    stream: impl Transport,
+    server: &str,
+    known_peers: &mut KnownPeers,
+    handshake: &Handshake,
+    args: &SessionArgs,
+    timeouts: Timeouts,
+) -> Result<(), ChatError> {
+    let stream = recorded(stream, Role::Client, args.record.as_deref())?;
+
+    // 协商算法、执行密钥交换，并用 HKDF 派生两个方向的会话密钥
+    let session = Session::establish(stream, handshake, args.rekey_policy(), timeouts)?;
+
+    // trust on first use：第一次连接时记录服务器指纹，之后指纹变化直接中止
+    match session.peer_identity() {
+        Some(peer) => {
+            let fingerprint = identity::fingerprint(peer);
+            match known_peers.check(server, peer).map_err(ChatError::Authentication)? {
+                Trust::Known => info!("[IDENTITY] Server {server} matches known fingerprint {fingerprint} ✓"),
+                Trust::New => {
+                    info!("[IDENTITY] First connection to {server}, trusting fingerprint {fingerprint}");
+                    info!("[IDENTITY] Saved to known_peers; verify it with the server operator out of band");
+                }
+            }
         }
+        None => say!("[IDENTITY] ⚠ Server is NOT authenticated"),
+    }
+    chat(session, server, args)
+}
+
+// 读取 --params 文件；自己的参数也要满足同样的策略，避免误用过小或不合格的参数
+fn load_params(path: &Path, policy: DhPolicy) -> Result<DhParams, ChatError> {
+    let text = fs::read_to_string(path).map_err(ChatError::io(format!("reading {}", path.display())))?;
+    let params = DhParams::parse(&text)
+        .and_then(|params| policy.check(&params).map(|()| params))
+        .map_err(|e| ChatError::Usage(format!("{}: {e}", path.display())))?;
+    info!("[DH] Loaded {}-bit parameters from {} ✓", params.bits(), path.display());
+    Ok(params)
+}
+
+// 把 --params 和 --min-dh-bits 交给握手
+// 只有有限域 DH 用得到 --group；X25519 不受影响
+fn check_group(policy: DhPolicy, hello: Hello) -> Result<(), ChatError> {
+    if hello.kex == KexKind::Dh {
+        policy.check_group(hello.group).map_err(ChatError::Usage)?;
+    }
+    Ok(())
+}
+
+fn dh_handshake<'a>(handshake: Handshake<'a>, params: Option<&'a DhParams>, args: &SessionArgs) -> Handshake<'a> {
+    let handshake = handshake.with_dh_policy(args.dh_policy());
+    match params {
+        Some(params) => handshake.with_params(params),
+        None => handshake,
+    }
+}
+
+// 指定了 --record 时把连接包装成录制的传输层，否则原样使用
+fn recorded<S: Transport>(stream: S, role: Role, record: Option<&Path>) -> Result<Recorded<S>, ChatError> {
+    let Some(path) = record else {
+        return Ok(Recorded::plain(stream));
+    };
+    let stream = Recorded::to_file(stream, role, path)
+        .map_err(ChatError::io(format!("creating the recording {}", path.display())))?;
+    info!("[RECORD] Recording every frame to {}", path.display());
+    Ok(stream)
+}
 
-        println!("[NETWORK] Received encrypted message ({} bytes)", n);
-        println!("[←] Received {} bytes", n);
-        println!("[DECRYPT]");
-        println!("Cipher: {:?}", &buffer[0..n]);
-        print!("Key: ");
-        for idx in 0..n {
-            print!("{:02X} ", keystream.next_byte());
+// 服务器和客户端共用的聊天流程：收发互不阻塞，对方的消息以对方的角色名显示
+fn chat(session: Session<Recorded<impl Transport>>, address: &str, args: &SessionArgs) -> Result<(), ChatError> {
+    let download_dir = &args.download_dir;
+    info!("✓ Secure channel established!");
+    // 录制的日志本身不含密钥，没有这个会话密钥就无法解密；密钥只写进权限为 0600 的文件，不显示出来
+    if let Some(path) = &args.record {
+        let key_file = record::save_key(path, session.session_key())
+            .map_err(ChatError::io(format!("saving the session key for {}", path.display())))?;
+        info!("[RECORD] Session key saved to {} (keep it private)", key_file.display());
+    }
+
+    // --tui 时界面从这里开始接管终端，之后的输出都显示在界面里；--verbose 及以上默认打开跟踪面板
+    let peer = session.role().peer_name();
+    let (input, screen): (Box<dyn io::BufRead + Send>, _) = if args.tui {
+        let status = tui::Status {
+            peer: format!("{peer} {address}"),
+            cipher: session.cipher().name().to_string(),
+            fingerprint: session.peer_identity().map(identity::fingerprint),
+        };
+        let (screen, input) =
+            tui::start(status, log::shows(Level::Verbose)).map_err(ChatError::io("starting the terminal UI"))?;
+        (Box::new(input), Some(screen))
+    } else {
+        (chat_input(args.transport.stdio), None)
+    };
+    info!(
+        "[CHAT] Type message ({} to rekey, {} to leave):",
+        chat::REKEY_COMMAND,
+        chat::QUIT_COMMAND
+    );
+    info!(
+        "[FILE] {} <path> offers a file; received files go to {}",
+        transfer::SEND_COMMAND,
+        download_dir.display()
+    );
+
+    let transfers = Transfers::new(download_dir);
+    let result = session.run_chat(input, transfers, |plaintext| {
+        say!("[{peer}] {}", std::str::from_utf8(plaintext).unwrap_or(""));
+    });
+    // 先恢复终端，错误信息才能正常显示
+    drop(screen);
+    result
+}
+
+// 聊天输入平常来自 stdin；--stdio 时 stdin 属于协议，改从终端读取，没有终端就只接收消息
+fn chat_input(stdio: bool) -> Box<dyn io::BufRead + Send> {
+    if !stdio {
+        return Box::new(io::BufReader::new(io::stdin()));
+    }
+    match File::open("/dev/tty") {
+        Ok(tty) => Box::new(io::BufReader::new(tty)),
+        Err(_) => {
+            info!("[STDIO] No terminal for chat input; only receiving messages until the peer leaves");
+            Box::new(io::BufReader::new(NoInput))
         }
-        println!("\nPlain: {:?} → \"{}\"", plaintext, std::str::from_utf8(&plaintext).unwrap_or(""));
-        println!("[TEST] Round-trip verified: \"{}\" → encrypt → decrypt → \"{}\" ✓",
-            std::str::from_utf8(&plaintext).unwrap_or(""),
-            std::str::from_utf8(&plaintext).unwrap_or("")
-        );
-        println!("[SERVER] {}", std::str::from_utf8(&plaintext).unwrap_or(""));
-
-        // 发送消息逻辑
-        input.clear();
-        io::stdin().read_line(&mut input).unwrap();
-        let msg = input.trim().as_bytes();
-        if msg.is_empty() {
-            input.clear();
-            continue;
+    }
+}
+
+// 永远等不到输入、也不会结束的输入源。不能用 io::empty()：输入结束会半关闭连接，对方随即结束会话
+struct NoInput;
+
+impl io::Read for NoInput {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        loop {
+            thread::park();
         }
+    }
+}
 
-        // 加密消息（流密码 XOR）：使用 idx 作为循环变量
-        let mut ciphertext = vec![0u8; msg.len()];
-        for idx in 0..msg.len() {
-            ciphertext[idx] = msg[idx] ^ keystream.next_byte();
+// 中间人演示逻辑
+fn run_mitm(
+    listen: String,
+    upstream: String,
+    hello: Hello,
+    policy: DhPolicy,
+    params: Option<&Path>,
+) -> Result<(), ChatError> {
+    let (listen, upstream) = mitm::check_localhost(&listen, &upstream).map_err(ChatError::Usage)?;
+    // 面对客户端时中间人扮演服务器，自定义群同样需要 --params
+    if hello.kex == KexKind::Dh && hello.group == DhGroup::Custom && params.is_none() {
+        return Err(ChatError::Usage("--group custom needs --params FILE (the same file the server uses)".to_string()));
+    }
+    check_group(policy, hello)?;
+    let params = params.map(|path| load_params(path, policy)).transpose()?;
+    let listener = TcpListener::bind(listen).map_err(ChatError::io(format!("binding {listen}")))?;
+    info!("[MITM] Listening on {listen}, forwarding to {}", upstream[0]);
+    info!("[MITM] Waiting for a client that thinks it is talking to the server...");
+
+    let (client, addr) = listener.accept().map_err(ChatError::io("accepting a client"))?;
+    info!("[MITM] Client connected from {addr}");
+    let server = TcpStream::connect(&upstream[..]).map_err(ChatError::io("connecting to the upstream server"))?;
+
+    // 截获的明文已经由 mitm 模块打印，这里不需要再收集
+    let (tap, _) = mpsc::channel();
+    mitm::intercept(client, server, hello, policy, params.as_ref(), tap)
+}
+
+fn run_crack(capture: &Path, known: &str, seq: Option<u64>) -> Result<(), ChatError> {
+    let mut file = File::open(capture).map_err(ChatError::io(format!("opening {}", capture.display())))?;
+    let records = crack::parse_capture(&mut file).map_err(ChatError::Usage)?;
+    info!("[CRACK] Read {} encrypted records from {}", records.len(), capture.display());
+    let seq = seq
+        .or_else(|| records.iter().find(|r| r.kind == FrameType::Message).map(|r| r.seq))
+        .ok_or_else(|| ChatError::Usage("the capture contains no chat messages".to_string()))?;
+
+    info!("[CRACK] Known plaintext of message #{seq}: \"{known}\" ({} bytes)", known.len());
+    info!("[CRACK] keystream = plaintext XOR ciphertext; each byte is the top 8 bits of one LCG state");
+    info!("[CRACK] Brute-forcing the 2^24 unknown low bits of the first state...");
+    let cracked = crack::crack(&records, seq, known.as_bytes()).map_err(ChatError::Usage)?;
+    info!(
+        "[CRACK] ✓ State recovered: seed for epoch {} is 0x{:08X} ^ seq",
+        cracked.epoch, cracked.base_seed
+    );
+
+    // 只有还原出的记录直接打印到 stdout，状态和警告都经过日志层
+    for (record, plaintext) in records.iter().zip(&cracked.plaintexts) {
+        match (record.kind, plaintext) {
+            (_, None) => say!(
+                "[#{}] ✗ key epoch {} (keys were ratcheted; needs known plaintext from that epoch)",
+                record.seq, record.epoch
+            ),
+            (FrameType::Message, Some(plaintext)) => {
+                println!("[#{}] {}", record.seq, String::from_utf8_lossy(plaintext))
+            }
+            (FrameType::File, Some(plaintext)) => {
+                println!("[#{}] file transfer record ({} bytes)", record.seq, plaintext.len())
+            }
+            (kind, Some(_)) => verbose!("[#{}] {kind:?} heartbeat", record.seq),
         }
+    }
+    Ok(())
+}
 
-        println!("[ENCRYPT]");
-        println!("Plain: {:?} (\"{}\")", msg, std::str::from_utf8(msg).unwrap_or(""));
-        print!("Key: ");
-        for idx in 0..msg.len() {
-            print!("{:02X} ", keystream.next_byte());
+fn run_genparams(bits: u64, out: &Path) -> Result<(), ChatError> {
+    if !(16..=dh::MAX_BITS).contains(&bits) {
+        return Err(ChatError::Usage(format!("--bits must be between 16 and {} (got {bits})", dh::MAX_BITS)));
+    }
+    if bits < DhPolicy::default().min_bits {
+        say!("[GENPARAMS] ⚠ {bits}-bit parameters are for teaching only; peers need --min-dh-bits {bits}");
+    }
+    info!("[GENPARAMS] Searching for a {bits}-bit safe prime p = 2q + 1 (Miller-Rabin, {} rounds)...", prime::MR_ROUNDS);
+    let mut candidates = 0u64;
+    let params = DhParams::generate(bits, || {
+        candidates += 1;
+        if candidates.is_multiple_of(100) {
+            verbose!("[GENPARAMS] {candidates} candidates tested...");
         }
-        println!("\nCipher: {:?}", ciphertext);
+    });
+    info!("[GENPARAMS] Found after {candidates} candidates, generator g = {}", params.g);
+    fs::write(out, params.to_text()).map_err(ChatError::io(format!("writing {}", out.display())))?;
+    info!("[GENPARAMS] Wrote {} (use it with server/client --params)", out.display());
+    Ok(())
+}
 
-        // 发送加密消息
-        writer.write_all(&ciphertext).unwrap();
-        println!("[NETWORK] Sending encrypted message ({} bytes)...", msg.len());
-        println!("[→] Sent {} bytes", msg.len());
-        input.clear();
+fn run_decrypt_log(path: &Path, key: Option<&Path>) -> Result<(), ChatError> {
+    let key_file = key.map_or_else(|| record::key_path(path), Path::to_path_buf);
+    let key = record::load_key(&key_file).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => ChatError::Usage(e.to_string()),
+        _ => ChatError::io(format!("reading the session key {}", key_file.display()))(e),
+    })?;
+    let mut file = File::open(path).map_err(ChatError::io(format!("opening {}", path.display())))?;
+    let log = record::read_log(&mut file).map_err(ChatError::io(format!("reading {}", path.display())))?;
+    let started = log.started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    info!(
+        "[RECORD] {} frames recorded by the {} (started at Unix time {started})",
+        log.entries.len(),
+        log.role.name()
+    );
+
+    // 只有解密出的内容直接打印到 stdout，状态和错误都经过日志层
+    for frame in record::decrypt(&log, *key).map_err(ChatError::Usage)? {
+        let at = format!("[{:>9.3}s] {}", frame.at.as_secs_f64(), frame.sender.name());
+        let seq = frame.seq.map(|seq| format!(" #{seq}")).unwrap_or_default();
+        match (frame.kind, frame.plaintext) {
+            (FrameType::Handshake, Ok(payload)) => info!("{at} handshake ({} bytes)", payload.len()),
+            (_, Err(e)) => say!("{at} ✗ {:?} frame rejected: {e}", frame.kind),
+            (FrameType::Message, Ok(plaintext)) => println!("{at}{seq} {}", String::from_utf8_lossy(&plaintext)),
+            (FrameType::File, Ok(plaintext)) => match FileMessage::decode(&plaintext) {
+                Ok(FileMessage::Chunk { id, offset, data }) => {
+                    println!("{at}{seq} file {id}: {} bytes at offset {offset}", data.len())
+                }
+                Ok(FileMessage::Offer { id, size, name, .. }) => {
+                    println!("{at}{seq} file {id}: offer \"{name}\" ({size} bytes)")
+                }
+                Ok(message) => println!("{at}{seq} file {message:?}"),
+                Err(e) => println!("{at}{seq} ✗ {e}"),
+            },
+            (kind, Ok(_)) => verbose!("{at}{seq} {kind:?}"),
+        }
     }
+    Ok(())
+}
+
+// 读取（或第一次运行时生成）本机的长期身份密钥
+fn load_identity(config_dir: &Path) -> Result<Identity, ChatError> {
+    let identity = Identity::load_or_create(config_dir)
+        .map_err(ChatError::io(format!("loading the identity from {}", config_dir.display())))?;
+    info!("[IDENTITY] Our fingerprint: {}", identity::fingerprint(&identity.public_key()));
+    Ok(identity)
 }
 
 fn main() {
-    let command = Command::parse();
-    match command {
-        Command::Server { port } => run_server(port),
-        Command::Client { addr } => run_client(addr),
+    let cli = Cli::parse();
+    log::set_level(cli.verbosity.level());
+    let result = match cli.command {
+        Command::Server { port, bind, cipher, kex, group, no_auth, session } => {
+            let hello = Hello { cipher, kex, group: session.group(group), authenticate: !no_auth };
+            run_server(port, bind.as_deref(), hello, &session)
+        }
+        Command::Room { port, bind, cipher, kex, group, no_auth, min_dh_bits, config_dir } => {
+            let config_dir = config_dir.unwrap_or_else(identity::default_config_dir);
+            let hello = Hello { cipher, kex, group, authenticate: !no_auth };
+            let policy = DhPolicy { min_bits: min_dh_bits, ..DhPolicy::default() };
+            run_room(port, bind.as_deref(), hello, policy, &config_dir)
+        }
+        Command::Client { addr, peer_name, cipher, kex, group, no_auth, session } => {
+            let hello = Hello { cipher, kex, group: session.group(group), authenticate: !no_auth };
+            run_client(addr, peer_name.as_deref(), hello, &session)
+        }
+        Command::Mitm { listen, upstream, cipher, kex, group, no_auth, params, min_dh_bits } => {
+            let group = if params.is_some() { DhGroup::Custom } else { group };
+            let policy = DhPolicy { min_bits: min_dh_bits, ..DhPolicy::default() };
+            run_mitm(listen, upstream, Hello { cipher, kex, group, authenticate: !no_auth }, policy, params.as_deref())
+        }
+        Command::Crack { capture, known, seq } => run_crack(&capture, &known, seq),
+        Command::GenParams { bits, out } => run_genparams(bits, &out),
+        Command::DecryptLog { log, key } => run_decrypt_log(&log, key.as_deref()),
+    };
+    // 每类错误使用不同的退出码，见 error.rs
+    if let Err(e) = result {
+        eprintln!("[ERROR] {e}");
+        process::exit(e.exit_code());
     }
-}
\ No newline at end of file
+}
+
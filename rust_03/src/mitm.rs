@@ -0,0 +1,278 @@
+use crate::chat::{self, DirectionKeys};
+use crate::dh::{DhParams, DhPolicy};
+use crate::error::ChatError;
+use crate::frame::FrameType;
+use crate::handshake::{Handshake, Hello, Role};
+use crate::identity::{self, Identity};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+
+// 中间人演示只允许在本机回环地址上进行
+pub fn check_localhost(listen: &str, upstream: &str) -> Result<(SocketAddr, Vec<SocketAddr>), String> {
+    let listen: SocketAddr = listen
+        .parse()
+        .map_err(|_| format!("listen address {listen} must be an IP:port such as 127.0.0.1:9090"))?;
+    if !listen.ip().is_loopback() {
+        return Err(format!("refusing to listen on {listen}: the MITM demo only runs on localhost"));
+    }
+    let upstream_addrs: Vec<SocketAddr> = upstream
+        .to_socket_addrs()
+        .map_err(|e| format!("cannot resolve upstream {upstream}: {e}"))?
+        .collect();
+    if upstream_addrs.is_empty() || upstream_addrs.iter().any(|addr| !addr.ip().is_loopback()) {
+        return Err(format!("refusing to intercept {upstream}: the MITM demo only runs on localhost"));
+    }
+    Ok((listen, upstream_addrs))
+}
+
+// 分别与客户端（扮演服务器）和服务器（扮演客户端）完成两次独立的密钥交换，
+// 然后解密、打印并重新加密双方的每一条消息。被截获的明文同时发送到 tap。
+// policy 和 params 与真正的服务器/客户端的 --min-dh-bits、--params 相同：
+// 自定义群的参数出示给客户端，并要求服务器发来的完全相同
+pub fn intercept(
+    mut client: TcpStream,
+    mut server: TcpStream,
+    hello: Hello,
+    policy: DhPolicy,
+    params: Option<&DhParams>,
+    tap: mpsc::Sender<(Role, Vec<u8>)>,
+) -> Result<(), ChatError> {
+    // 中间人自己的身份密钥：握手需要签名时只能出示这把钥匙，
+    // 它的指纹与真正的服务器/客户端不同
+    let identity = Identity::generate();
+    let handshake = |role| {
+        let handshake = Handshake::new(role, hello, &identity).with_dh_policy(policy);
+        match params {
+            Some(params) => handshake.with_params(params),
+            None => handshake,
+        }
+    };
+
+    info!("[MITM] === Handshake with the CLIENT (we pretend to be the server) ===");
+    let with_client = handshake(Role::Server).perform(&mut client)?;
+    info!("[MITM] === Handshake with the SERVER (we pretend to be the client) ===");
+    let with_server = handshake(Role::Client).perform(&mut server)?;
+
+    if with_client.peer_identity.is_some() {
+        info!(
+            "[MITM] ⚠ Peers require authentication: they will see our fingerprint {} instead of each other's",
+            identity::fingerprint(&identity.public_key())
+        );
+    } else {
+        info!("[MITM] Neither side authenticated its key: both believe they share a secret with each other");
+    }
+    info!("[MITM] Client session keys ≠ server session keys, but we hold both sets");
+
+    let cipher = hello.cipher;
+    let client_to_us = DirectionKeys::new(cipher, with_client.keys.receiving(Role::Server));
+    let us_to_client = DirectionKeys::new(cipher, with_client.keys.sending(Role::Server));
+    let server_to_us = DirectionKeys::new(cipher, with_server.keys.receiving(Role::Client));
+    let us_to_server = DirectionKeys::new(cipher, with_server.keys.sending(Role::Client));
+
+    let client_reader = client.try_clone().map_err(ChatError::io("cloning the client connection"))?;
+    let server_reader = server.try_clone().map_err(ChatError::io("cloning the server connection"))?;
+    let upstream_tap = tap.clone();
+    let upstream = thread::spawn(move || {
+        relay(Role::Client, client_reader, client_to_us, server, us_to_server, upstream_tap)
+    });
+    relay(Role::Server, server_reader, server_to_us, client, us_to_client, tap);
+    let _ = upstream.join();
+    info!("[MITM] Both sides disconnected");
+    Ok(())
+}
+
+// 单方向转发：用一端的密钥解密，打印明文，再用另一端的密钥加密发出
+fn relay(
+    from: Role,
+    mut reader: TcpStream,
+    mut reader_keys: DirectionKeys,
+    mut writer: TcpStream,
+    mut writer_keys: DirectionKeys,
+    tap: mpsc::Sender<(Role, Vec<u8>)>,
+) {
+    let to = from.peer_name();
+    let from_name = from.peer().peer_name();
+    loop {
+        match chat::receive_record(&mut reader, &mut reader_keys) {
+            Ok(Some(record)) => {
+                if record.kind == FrameType::Message {
+                    say!(
+                        "[MITM] Intercepted {from_name} → {to}: \"{}\"",
+                        String::from_utf8_lossy(&record.payload)
+                    );
+                    let _ = tap.send((from, record.payload.clone()));
+                } else if record.kind == FrameType::File {
+                    info!("[MITM] Intercepted {from_name} → {to}: {} bytes of file transfer", record.payload.len());
+                }
+                if let Err(e) = chat::send_record(&mut writer, &mut writer_keys, record.kind, &record.payload) {
+                    say!("[MITM] Forwarding to {to} failed: {e}");
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => say!("[MITM] Message from {from_name} rejected: {e}"),
+        }
+    }
+    // 一方断开后也关闭另一方的写端，让对方的接收线程退出
+    let _ = writer.shutdown(Shutdown::Write);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::CipherKind;
+    use crate::dh::{DhGroup, MAX_BITS};
+    use crate::kex::KexKind;
+    use std::net::TcpListener;
+
+    const HELLO: Hello = Hello {
+        cipher: CipherKind::Chacha20,
+        kex: KexKind::Dh,
+        group: DhGroup::Toy64,
+        authenticate: false,
+    };
+    // 服务器、客户端和中间人都要显式允许 toy64
+    const TOY: DhPolicy = DhPolicy { min_bits: 64, max_bits: MAX_BITS };
+
+    // 启动 服务器 ← 中间人 ← 客户端 的链路，返回中间人的监听地址和截获的消息
+    fn start_mitm(
+        server_addr: SocketAddr,
+        hello: Hello,
+        params: Option<DhParams>,
+    ) -> (SocketAddr, mpsc::Receiver<(Role, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tap, seen) = mpsc::channel();
+        thread::spawn(move || {
+            let (client, _) = listener.accept().unwrap();
+            let server = TcpStream::connect(server_addr).unwrap();
+            let _ = intercept(client, server, hello, TOY, params.as_ref(), tap);
+        });
+        (addr, seen)
+    }
+
+    #[test]
+    fn mitm_reads_every_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let (mitm_addr, seen) = start_mitm(server_addr, HELLO, None);
+
+        // 服务器：把收到的每条消息加上前缀回复
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let session = Handshake::new(Role::Server, HELLO, &Identity::generate())
+                .with_dh_policy(TOY)
+                .perform(&mut stream)
+                .unwrap()
+                .keys;
+            let mut send_keys = DirectionKeys::new(HELLO.cipher, session.sending(Role::Server));
+            let mut receive_keys = DirectionKeys::new(HELLO.cipher, session.receiving(Role::Server));
+            while let Some(msg) = chat::receive_message(&mut stream, &mut receive_keys).unwrap() {
+                let mut reply = b"ack: ".to_vec();
+                reply.extend_from_slice(&msg);
+                chat::send_message(&mut stream, &mut send_keys, &reply).unwrap();
+            }
+        });
+
+        // 客户端连到中间人，却以为自己在和服务器说话
+        let mut stream = TcpStream::connect(mitm_addr).unwrap();
+        let session = Handshake::new(Role::Client, HELLO, &Identity::generate())
+            .with_dh_policy(TOY)
+            .perform(&mut stream)
+            .unwrap()
+            .keys;
+        let mut send_keys = DirectionKeys::new(HELLO.cipher, session.sending(Role::Client));
+        let mut receive_keys = DirectionKeys::new(HELLO.cipher, session.receiving(Role::Client));
+
+        let secrets = ["my password is hunter2", "meet at noon", "the launch code is 0000"];
+        for secret in secrets {
+            chat::send_message(&mut stream, &mut send_keys, secret.as_bytes()).unwrap();
+            let reply = chat::receive_message(&mut stream, &mut receive_keys).unwrap().unwrap();
+            assert_eq!(reply, format!("ack: {secret}").into_bytes());
+        }
+        stream.shutdown(Shutdown::Write).unwrap();
+        server.join().unwrap();
+
+        // 双方都没有察觉，而中间人读到了每一条消息
+        let seen: Vec<(Role, Vec<u8>)> = seen.iter().collect();
+        let mut expected = Vec::new();
+        for secret in secrets {
+            expected.push((Role::Client, secret.as_bytes().to_vec()));
+            expected.push((Role::Server, format!("ack: {secret}").into_bytes()));
+        }
+        assert_eq!(seen, expected);
+    }
+
+    // 开启身份认证后，客户端看到的是中间人的指纹而不是服务器的
+    #[test]
+    fn authentication_exposes_the_mitm() {
+        let hello = Hello {
+            authenticate: true,
+            ..HELLO
+        };
+        let server_identity = Identity::generate();
+        let server_public = server_identity.public_key();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let (mitm_addr, _seen) = start_mitm(server_addr, hello, None);
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = Handshake::new(Role::Server, hello, &server_identity).with_dh_policy(TOY).perform(&mut stream);
+        });
+
+        let mut stream = TcpStream::connect(mitm_addr).unwrap();
+        let identity = Identity::generate();
+        let client = Handshake::new(Role::Client, hello, &identity).with_dh_policy(TOY);
+        let established = client.perform(&mut stream).unwrap();
+        let seen_identity = established.peer_identity.unwrap();
+        assert_ne!(seen_identity, server_public);
+
+        // 已经记住服务器指纹的客户端会硬失败
+        let dir = std::env::temp_dir().join(format!("rust_03-mitm-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut known = identity::KnownPeers::load(&dir).unwrap();
+        known.check("server", &server_public).unwrap();
+        assert!(known.check("server", &seen_identity).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // 服务器用 genparams 生成的自定义群：中间人拿着同一份 --params，照样分别完成两次握手
+    #[test]
+    fn mitm_follows_a_custom_group() {
+        let hello = Hello { group: DhGroup::Custom, ..HELLO };
+        let params = DhParams::generate(64, || {});
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let (mitm_addr, seen) = start_mitm(server_addr, hello, Some(params.clone()));
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let identity = Identity::generate();
+            let handshake = Handshake::new(Role::Server, hello, &identity).with_dh_policy(TOY).with_params(&params);
+            let keys = handshake.perform(&mut stream).unwrap().keys;
+            let mut receive_keys = DirectionKeys::new(hello.cipher, keys.receiving(Role::Server));
+            chat::receive_message(&mut stream, &mut receive_keys).unwrap()
+        });
+
+        // 客户端没有 --params：接受"服务器"（其实是中间人）发来的参数
+        let mut stream = TcpStream::connect(mitm_addr).unwrap();
+        let identity = Identity::generate();
+        let handshake = Handshake::new(Role::Client, hello, &identity).with_dh_policy(TOY);
+        let keys = handshake.perform(&mut stream).unwrap().keys;
+        let mut send_keys = DirectionKeys::new(hello.cipher, keys.sending(Role::Client));
+        chat::send_message(&mut stream, &mut send_keys, b"custom group secret").unwrap();
+
+        assert_eq!(server.join().unwrap(), Some(b"custom group secret".to_vec()));
+        assert_eq!(seen.recv().unwrap(), (Role::Client, b"custom group secret".to_vec()));
+    }
+
+    #[test]
+    fn only_localhost_is_allowed() {
+        assert!(check_localhost("127.0.0.1:9090", "127.0.0.1:8080").is_ok());
+        assert!(check_localhost("[::1]:9090", "localhost:8080").is_ok());
+        assert!(check_localhost("0.0.0.0:9090", "127.0.0.1:8080").is_err());
+        assert!(check_localhost("127.0.0.1:9090", "192.0.2.1:8080").is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use crate::secret::Secret;
+use num_bigint::BigUint;
+use subtle::{Choice, ConditionallySelectable};
+
+// 常数时间模幂：定长 limb 上的 Montgomery 乘法，加上 Montgomery ladder。
+//
+// 平方-乘算法只在指数为 1 的位上做乘法，BigUint 的运算时间又取决于数值的长度，
+// 私钥的位模式会从耗时中泄露出来。这里所有数都是和模数等长的小端 64 位 limb 数组，
+// 指数的每一位都做一次乘法和一次平方，需要按秘密选择的地方用 subtle 的掩码操作代替分支。
+// 模数和底数是公开的，可以用 BigUint 处理
+
+pub struct Montgomery {
+    p: BigUint,
+    // 模数的 limb（小端）
+    modulus: Vec<u64>,
+    // -modulus^-1 mod 2^64
+    m_prime: u64,
+    // R^2 mod p（R = 2^(64n)），乘上它就把一个数转换到 Montgomery 形式
+    r_squared: Vec<u64>,
+}
+
+impl Montgomery {
+    // Montgomery 约简要求模数是奇数；DH 用的素数都满足
+    pub fn new(p: &BigUint) -> Self {
+        assert!(p.bit(0) && p.bits() > 1, "Montgomery arithmetic needs an odd modulus > 1");
+        let n = p.to_u64_digits().len();
+        let modulus = to_limbs(p, n);
+        Montgomery {
+            p: p.clone(),
+            m_prime: inverse(modulus[0]).wrapping_neg(),
+            r_squared: to_limbs(&((BigUint::from(1u32) << (128 * n)) % p), n),
+            modulus,
+        }
+    }
+
+    pub fn limbs(&self) -> usize {
+        self.modulus.len()
+    }
+
+    // base^exp mod p，结果是 limbs() 个小端 limb。指数按 exp.len() * 64 位从高到低逐位处理，
+    // 耗时只取决于 limb 的个数，和指数的值（位长、汉明重量）无关
+    pub fn pow(&self, base: &BigUint, exp: &[u64]) -> Secret<Vec<u64>> {
+        let n = self.limbs();
+        let mut t = Secret::new(vec![0u64; n + 2]);
+        let mut one = vec![0u64; n];
+        one[0] = 1;
+
+        // x0 = 1，x1 = base（都在 Montgomery 形式下），循环中始终保持 x1 = x0 * base
+        self.mul(&one, &self.r_squared, &mut t);
+        let mut x0 = Secret::new(t[..n].to_vec());
+        self.mul(&to_limbs(&(base % &self.p), n), &self.r_squared, &mut t);
+        let mut x1 = Secret::new(t[..n].to_vec());
+
+        // 位为 0：x1 = x0 * x1，x0 = x0^2；位为 1：x0 = x0 * x1，x1 = x1^2。
+        // 用条件交换把两种情况变成同一串操作
+        for i in (0..exp.len() * 64).rev() {
+            let bit = Choice::from(((exp[i / 64] >> (i % 64)) & 1) as u8);
+            conditional_swap(&mut x0, &mut x1, bit);
+            self.mul(&x0, &x1, &mut t);
+            x1.copy_from_slice(&t[..n]);
+            self.mul(&x0, &x0, &mut t);
+            x0.copy_from_slice(&t[..n]);
+            conditional_swap(&mut x0, &mut x1, bit);
+        }
+
+        // 乘以 1 离开 Montgomery 形式
+        self.mul(&x0, &one, &mut t);
+        Secret::new(t[..n].to_vec())
+    }
+
+    // Montgomery 乘法（CIOS）：t[..n] = a * b * R^-1 mod p，要求 a、b < p，t 有 n + 2 个 limb
+    fn mul(&self, a: &[u64], b: &[u64], t: &mut [u64]) {
+        let n = self.limbs();
+        t.fill(0);
+        for &word in b {
+            let mut carry = 0;
+            for j in 0..n {
+                (t[j], carry) = mul_add(t[j], a[j], word, carry);
+            }
+            let (sum, overflow) = t[n].overflowing_add(carry);
+            t[n] = sum;
+            t[n + 1] = overflow as u64;
+
+            // 加上 m * modulus 让最低的 limb 变成 0，再整体右移一个 limb
+            let m = t[0].wrapping_mul(self.m_prime);
+            let (_, mut carry) = mul_add(t[0], m, self.modulus[0], 0);
+            for j in 1..n {
+                (t[j - 1], carry) = mul_add(t[j], m, self.modulus[j], carry);
+            }
+            let (sum, overflow) = t[n].overflowing_add(carry);
+            t[n - 1] = sum;
+            t[n] = t[n + 1] + overflow as u64;
+        }
+
+        // 现在 t < 2p。先算出 t - p 的借位，再按借位用掩码决定是否真的减，不使用分支
+        let mut borrow = 0;
+        for (&t, &m) in t.iter().zip(&self.modulus) {
+            (_, borrow) = sub_borrow(t, m, borrow);
+        }
+        (_, borrow) = sub_borrow(t[n], 0, borrow);
+        let mask = u64::conditional_select(&0, &u64::MAX, Choice::from((borrow ^ 1) as u8));
+        let mut borrow = 0;
+        for (t, &m) in t.iter_mut().zip(&self.modulus) {
+            (*t, borrow) = sub_borrow(*t, m & mask, borrow);
+        }
+    }
+}
+
+fn conditional_swap(a: &mut [u64], b: &mut [u64], choice: Choice) {
+    for (a, b) in a.iter_mut().zip(b.iter_mut()) {
+        u64::conditional_swap(a, b, choice);
+    }
+}
+
+// a + b * c + carry，结果的低 64 位和高 64 位（不会溢出 128 位）
+fn mul_add(a: u64, b: u64, c: u64, carry: u64) -> (u64, u64) {
+    let wide = a as u128 + b as u128 * c as u128 + carry as u128;
+    (wide as u64, (wide >> 64) as u64)
+}
+
+// a - b - borrow，结果和新的借位（0 或 1）
+fn sub_borrow(a: u64, b: u64, borrow: u64) -> (u64, u64) {
+    let wide = (a as u128).wrapping_sub(b as u128 + borrow as u128);
+    (wide as u64, (wide >> 127) as u64)
+}
+
+// 2^64 的模逆：a 是奇数所以一定存在，牛顿迭代每次把正确的位数翻倍（3 → 6 → ... → 96）
+fn inverse(a: u64) -> u64 {
+    let mut inv = a;
+    for _ in 0..5 {
+        inv = inv.wrapping_mul(2u64.wrapping_sub(a.wrapping_mul(inv)));
+    }
+    inv
+}
+
+// 公开的数转换成 len 个小端 limb
+pub fn to_limbs(n: &BigUint, len: usize) -> Vec<u64> {
+    let mut limbs = n.to_u64_digits();
+    assert!(limbs.len() <= len, "{}-bit number does not fit in {len} limbs", n.bits());
+    limbs.resize(len, 0);
+    limbs
+}
+
+// 小端 limb 转成 len 字节的大端编码，不经过 BigUint，方便直接放进 Secret
+pub fn to_be_bytes(limbs: &[u64], len: usize) -> Vec<u8> {
+    (0..len).rev().map(|i| limbs.get(i / 8).map_or(0, |limb| (limb >> (8 * (i % 8))) as u8)).collect()
+}
+
+// a < b（两者 limb 数相同）。逐 limb 做减法看最后的借位，耗时和数值无关
+pub fn less_than(a: &[u64], b: &[u64]) -> bool {
+    let mut borrow = 0;
+    for (&a, &b) in a.iter().zip(b) {
+        (_, borrow) = sub_borrow(a, b, borrow);
+    }
+    borrow == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dh::DhGroup;
+    use rand::RngCore;
+    use std::hint::black_box;
+    use std::time::{Duration, Instant};
+
+    fn curve25519_p() -> BigUint {
+        (BigUint::from(1u32) << 255u32) - 19u32
+    }
+
+    fn random_limbs(len: usize) -> Vec<u64> {
+        (0..len).map(|_| rand::rng().next_u64()).collect()
+    }
+
+    fn from_limbs(limbs: &[u64]) -> BigUint {
+        BigUint::from_bytes_be(&to_be_bytes(limbs, limbs.len() * 8))
+    }
+
+    #[test]
+    fn ladder_matches_library_modpow() {
+        let moduli = [
+            BigUint::from(3u32),
+            BigUint::from(0xFFFFFFFFFFFFFA43u64),
+            curve25519_p(),
+            DhGroup::Modp2048.params().unwrap().p,
+        ];
+        for p in moduli {
+            let ladder = Montgomery::new(&p);
+            let n = ladder.limbs();
+            for exp_len in [0, 1, n, n + 1] {
+                let base = from_limbs(&random_limbs(n + 1));
+                let exp = random_limbs(exp_len);
+                let expected = base.modpow(&from_limbs(&exp), &p);
+                assert_eq!(from_limbs(&ladder.pow(&base, &exp)), expected, "{}-bit, {exp_len} limbs", p.bits());
+            }
+            // 边界：指数全 1，底数 p - 1 和 0
+            let ones = vec![u64::MAX; n];
+            for base in [&p - 1u32, BigUint::ZERO] {
+                assert_eq!(from_limbs(&ladder.pow(&base, &ones)), base.modpow(&from_limbs(&ones), &p));
+            }
+        }
+    }
+
+    #[test]
+    fn limb_helpers() {
+        let n = BigUint::from(0x0102030405060708090Au128);
+        let limbs = to_limbs(&n, 3);
+        assert_eq!(limbs, [0x030405060708090A, 0x0102, 0]);
+        assert_eq!(to_be_bytes(&limbs, 12), [0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert!(less_than(&[5, 1], &[4, 2]));
+        assert!(!less_than(&[4, 2], &[4, 2]));
+        assert!(!less_than(&[0, 3], &[u64::MAX, 2]));
+        for odd in [1u64, 3, 6364136223846793005, u64::MAX] {
+            assert_eq!(inverse(odd).wrapping_mul(odd), 1);
+        }
+    }
+
+    // 原来的平方-乘算法：只在指数为 1 的位上做乘法
+    fn square_and_multiply(base: &BigUint, exp: &BigUint, p: &BigUint) -> BigUint {
+        let mut result = BigUint::from(1u32);
+        let mut base = base % p;
+        for i in 0..exp.bits() {
+            if exp.bit(i) {
+                result = (&result * &base) % p;
+            }
+            base = (&base * &base) % p;
+        }
+        result
+    }
+
+    // 对每个指数交替测量多轮，每个指数取最短耗时（过滤掉调度和其他测试线程造成的噪声），
+    // 返回最慢和最快的比值
+    fn timing_spread(exponents: &[Vec<u64>], mut run: impl FnMut(&[u64])) -> f64 {
+        let mut best = vec![Duration::MAX; exponents.len()];
+        for _ in 0..30 {
+            for (exp, best) in exponents.iter().zip(&mut best) {
+                let started = Instant::now();
+                for _ in 0..3 {
+                    run(exp);
+                }
+                *best = (*best).min(started.elapsed());
+            }
+        }
+        let slowest = best.iter().max().unwrap().as_secs_f64();
+        let fastest = best.iter().min().unwrap().as_secs_f64();
+        slowest / fastest
+    }
+
+    // 最高位固定为 1（位长相同），汉明重量从 1 到 256 的指数
+    fn exponents_by_hamming_weight() -> Vec<Vec<u64>> {
+        [1, 64, 128, 192, 256]
+            .into_iter()
+            .map(|weight| {
+                let mut exp = vec![0u64; 4];
+                for i in (0..256).rev().take(weight) {
+                    exp[i / 64] |= 1 << (i % 64);
+                }
+                exp
+            })
+            .collect()
+    }
+
+    // 耗时不随汉明重量变化：最慢和最快相差不到 35%（实测通常在 5% 以内，余量留给并行测试的干扰）。
+    // 同样的测量放在平方-乘算法上，全 1 的指数要多做一倍的乘法，比值接近 2，说明这个检测确实能发现泄露
+    #[test]
+    fn ladder_time_does_not_depend_on_hamming_weight() {
+        let p = curve25519_p();
+        let ladder = Montgomery::new(&p);
+        let base = BigUint::from(0xC0FFEEu32);
+        let exponents = exponents_by_hamming_weight();
+
+        let constant = timing_spread(&exponents, |exp| {
+            black_box(ladder.pow(black_box(&base), black_box(exp)));
+        });
+        let leaky = timing_spread(&exponents, |exp| {
+            black_box(square_and_multiply(black_box(&base), &from_limbs(exp), &p));
+        });
+        assert!(constant < 1.35, "ladder timing varies with the exponent's Hamming weight ({constant:.2}x)");
+        assert!(leaky > 1.5, "timing harness failed to see the square-and-multiply leak ({leaky:.2}x)");
+    }
+}
@@ -0,0 +1,197 @@
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// 一个连接尝试多久没有结果就并行开始下一个（RFC 8305 建议的 250ms）
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+// 单个连接尝试的上限，避免黑洞地址让客户端挂上几分钟
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 服务器默认监听 [::]:port，listen 会把它打开成双栈，同时接受 IPv4 和 IPv6 连接
+pub fn any_address(port: u16) -> SocketAddr {
+    SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port)
+}
+
+// 解析 --bind：带端口的地址（127.0.0.1:9000、[::1]:9000）原样使用，只有 IP（::1、[::1]、10.0.0.5）时端口取 port
+pub fn parse_bind(spec: &str, port: u16) -> Result<SocketAddr, String> {
+    if let Ok(addr) = spec.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let ip = spec.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(spec);
+    ip.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, port))
+        .map_err(|_| format!("--bind expects an IP address or IP:port, got {spec}"))
+}
+
+// 绑定并监听。IPv6 的任意地址关闭 IPV6_V6ONLY 成为双栈（IPv4 客户端显示为 ::ffff:a.b.c.d）；
+// 系统没有启用 IPv6 时退回到 0.0.0.0
+pub fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    let dual_stack = addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+    match bind(addr, dual_stack) {
+        Err(e) if dual_stack && !matches!(e.kind(), io::ErrorKind::AddrInUse | io::ErrorKind::PermissionDenied) => {
+            bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), addr.port()), false)
+        }
+        result => result,
+    }
+}
+
+fn bind(addr: SocketAddr, dual_stack: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // 和 std 的 TcpListener::bind 一样，服务器重启时不用等 TIME_WAIT
+    socket.set_reuse_address(true)?;
+    if dual_stack {
+        socket.set_only_v6(false)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+// 监听地址的说明，例如 "[::]:8080 (IPv4 and IPv6)"
+pub fn describe(listener: &TcpListener) -> String {
+    let Ok(addr) = listener.local_addr() else {
+        return "an unknown address".to_string();
+    };
+    let dual_stack = addr.is_ipv6() && SockRef::from(listener).only_v6().is_ok_and(|only| !only);
+    if dual_stack { format!("{addr} (IPv4 and IPv6)") } else { addr.to_string() }
+}
+
+// 双栈监听收到的 IPv4 连接显示为 ::ffff:a.b.c.d，还原成普通的 IPv4 地址
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+// 连接 host:port。主机名可能解析出多个地址（例如 IPv6 和 IPv4 各一个）：
+// 按 interleave 排好顺序后交给 connect_any，某个地址不通时自动换下一个
+pub fn connect(target: &str) -> io::Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = target.to_socket_addrs()?.collect();
+    connect_any(&interleave(addrs), ATTEMPT_DELAY)
+}
+
+// 保持解析器给出的第一个地址族优先，两个地址族交替排列，
+// 这样一个地址族整体不通时，很快就会轮到另一个地址族
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let prefer_v6 = first.is_ipv6();
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == prefer_v6);
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    while let Some(addr) = preferred.pop() {
+        ordered.push(addr);
+        ordered.extend(other.pop());
+    }
+    ordered.extend(other.into_iter().rev());
+    ordered
+}
+
+// Happy eyeballs（RFC 8305）：依次发起连接，前一个尝试在 delay 内没有结果就并行开始下一个，
+// 失败了立刻开始下一个，第一个成功的连接胜出。晚到的连接在发送结果时发现没人接收，直接关闭
+pub fn connect_any(addrs: &[SocketAddr], delay: Duration) -> io::Result<TcpStream> {
+    let (results, finished) = mpsc::channel();
+    let mut remaining = addrs.iter();
+    let mut pending = 0;
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "the host name did not resolve to any address");
+    loop {
+        let started = remaining.next().map(|&addr| {
+            verbose!("[NETWORK] Trying {addr}...");
+            let results = results.clone();
+            thread::spawn(move || {
+                let _ = results.send((addr, TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)));
+            });
+        });
+        pending += started.is_some() as usize;
+        if pending == 0 {
+            return Err(last_error);
+        }
+        // 还有没试过的地址时最多等 delay，全部发起之后一直等到有结果
+        let result = if remaining.len() > 0 {
+            finished.recv_timeout(delay).ok()
+        } else {
+            finished.recv().ok()
+        };
+        match result {
+            Some((addr, Ok(stream))) => {
+                verbose!("[NETWORK] Connected to {addr} ✓");
+                return Ok(stream);
+            }
+            Some((addr, Err(e))) => {
+                verbose!("[NETWORK] {addr}: {e}");
+                pending -= 1;
+                last_error = e;
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    // 一个确定没有人监听的本机端口
+    fn closed_port() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn bind_accepts_addresses_with_or_without_a_port() {
+        assert_eq!(parse_bind("127.0.0.1", 8080), Ok(addr("127.0.0.1:8080")));
+        assert_eq!(parse_bind("[::1]:9000", 8080), Ok(addr("[::1]:9000")));
+        assert_eq!(parse_bind("[::1]", 8080), Ok(addr("[::1]:8080")));
+        assert_eq!(parse_bind("::", 8080), Ok(any_address(8080)));
+        assert!(parse_bind("localhost", 8080).is_err());
+    }
+
+    #[test]
+    fn interleave_alternates_address_families() {
+        let resolved = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"].map(addr).to_vec();
+        let expected = ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"].map(addr).to_vec();
+        assert_eq!(interleave(resolved), expected);
+        // 解析器把 IPv4 排在前面时尊重它的选择
+        let resolved = ["10.0.0.1:1", "10.0.0.2:1", "10.0.0.3:1", "[::1]:1"].map(addr).to_vec();
+        let expected = ["10.0.0.1:1", "[::1]:1", "10.0.0.2:1", "10.0.0.3:1"].map(addr).to_vec();
+        assert_eq!(interleave(resolved), expected);
+        assert_eq!(interleave(Vec::new()), Vec::new());
+    }
+
+    // 默认的 [::] 监听同时接受 IPv4 和 IPv6 客户端
+    #[test]
+    fn default_listener_is_dual_stack() {
+        let listener = listen(any_address(0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut targets = vec![addr(&format!("127.0.0.1:{port}"))];
+        if listener.local_addr().unwrap().is_ipv6() {
+            assert!(describe(&listener).ends_with("(IPv4 and IPv6)"));
+            targets.push(addr(&format!("[::1]:{port}")));
+        }
+        for target in targets {
+            let client = TcpStream::connect(target).unwrap();
+            let (_, peer) = listener.accept().unwrap();
+            assert_eq!(canonical(peer), client.local_addr().unwrap());
+        }
+    }
+
+    // 前面的地址拒绝连接时依次尝试后面的地址；全部失败时报告最后一个错误
+    #[test]
+    fn connect_falls_through_to_a_working_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live = listener.local_addr().unwrap();
+        let stream = connect_any(&[closed_port(), closed_port(), live], ATTEMPT_DELAY).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), live);
+
+        let error = connect_any(&[closed_port(), closed_port()], ATTEMPT_DELAY).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(connect_any(&[], ATTEMPT_DELAY).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
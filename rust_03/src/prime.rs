@@ -0,0 +1,136 @@
+use num_bigint::BigUint;
+use rand::RngCore;
+use std::sync::OnceLock;
+
+// Miller-Rabin 的轮数：每一轮把合数被误判为素数的概率至少降低到 1/4，
+// 32 轮之后误判概率小于 2^-64
+pub const MR_ROUNDS: usize = 32;
+
+// 1000 以内的素数，用来试除：大部分候选在这一步就被排除，省掉昂贵的模幂
+fn small_primes() -> &'static [u32] {
+    static PRIMES: OnceLock<Vec<u32>> = OnceLock::new();
+    PRIMES.get_or_init(|| (2u32..1000).filter(|&n| (2..n).take_while(|d| d * d <= n).all(|d| n % d != 0)).collect())
+}
+
+fn small(n: &BigUint, r: u32) -> u32 {
+    (n % r).to_u32_digits().first().copied().unwrap_or(0)
+}
+
+// [2, n-2] 中的随机数（多取 8 字节再取模，偏差可以忽略）
+fn random_witness(n: &BigUint) -> BigUint {
+    let mut bytes = vec![0u8; n.bits().div_ceil(8) as usize + 8];
+    rand::rng().fill_bytes(&mut bytes);
+    BigUint::from_bytes_be(&bytes) % (n - 3u32) + 2u32
+}
+
+// Miller-Rabin 概率素性检验：n - 1 = d * 2^s，对随机底数 a 检查
+// a^d ≡ 1 或者 a^(d*2^r) ≡ -1 (mod n)；任意一轮不满足，n 一定是合数
+pub fn is_probable_prime(n: &BigUint, rounds: usize) -> bool {
+    let one = BigUint::from(1u32);
+    for &r in small_primes() {
+        if *n == BigUint::from(r) {
+            return true;
+        }
+        if small(n, r) == 0 || *n < BigUint::from(r) {
+            return false;
+        }
+    }
+    let n_minus_one = n - &one;
+    let s = n_minus_one.trailing_zeros().expect("n is odd and greater than 1");
+    let d = &n_minus_one >> s;
+    'witness: for _ in 0..rounds {
+        let mut x = random_witness(n).modpow(&d, n);
+        if x == one || x == n_minus_one {
+            continue;
+        }
+        for _ in 1..s {
+            x = x.modpow(&BigUint::from(2u32), n);
+            if x == n_minus_one {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+// 安全素数：p 和 q = (p-1)/2 都是素数。这样 p-1 只有 2 和 q 两个素因子，
+// 离散对数不能被拆成小的子群问题（Pohlig-Hellman）
+pub fn is_safe_prime(p: &BigUint, rounds: usize) -> bool {
+    // 最小的安全素数是 5（q = 2）；先排除 0 这样的输入，p - 1 不会下溢
+    if *p < BigUint::from(5u32) || !p.bit(0) {
+        return false;
+    }
+    let q = (p - 1u32) >> 1u32;
+    is_probable_prime(&q, rounds) && is_probable_prime(p, rounds)
+}
+
+// 随机搜索 bits 位的安全素数。每试一个通过试除的候选就调用一次 on_candidate（用于显示进度）
+pub fn generate_safe_prime(bits: u64, mut on_candidate: impl FnMut()) -> BigUint {
+    assert!(bits >= 16, "safe primes below 16 bits are not supported");
+    let mut bytes = vec![0u8; (bits - 1).div_ceil(8) as usize];
+    loop {
+        // q 恰好是 bits-1 位的奇数，所以 p = 2q + 1 恰好是 bits 位
+        rand::rng().fill_bytes(&mut bytes);
+        let mut q = BigUint::from_bytes_be(&bytes) >> (bytes.len() as u64 * 8 - (bits - 1));
+        q.set_bit(bits - 2, true);
+        q.set_bit(0, true);
+        // 试除 q 和 p：p ≡ 0 (mod r) 等价于 q ≡ (r-1)/2 (mod r)
+        let sieved = small_primes()
+            .iter()
+            .skip(1)
+            .all(|&r| BigUint::from(r) >= q || (small(&q, r) != 0 && small(&q, r) != (r - 1) / 2));
+        if !sieved {
+            continue;
+        }
+        on_candidate();
+        // 先各做一轮快速排除，两个都通过再做完整的检验
+        let p: BigUint = (&q << 1u32) + 1u32;
+        if is_probable_prime(&q, 1) && is_probable_prime(&p, 1) && is_safe_prime(&p, MR_ROUNDS) {
+            return p;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn miller_rabin_agrees_with_trial_division() {
+        for n in 2u32..5000 {
+            let prime = (2..n).take_while(|d| d * d <= n).all(|d| n % d != 0);
+            assert_eq!(is_probable_prime(&BigUint::from(n), 8), prime, "{n}");
+        }
+        // Carmichael 数能骗过 Fermat 检验，骗不过 Miller-Rabin
+        for carmichael in [561u64, 41041, 825265, 321197185, 5394826801] {
+            assert!(!is_probable_prime(&BigUint::from(carmichael), MR_ROUNDS), "{carmichael}");
+        }
+        // 2^127 - 1 是素数，2^128 + 1 不是
+        let mersenne = (BigUint::from(1u32) << 127u32) - 1u32;
+        assert!(is_probable_prime(&mersenne, MR_ROUNDS));
+        assert!(!is_probable_prime(&((BigUint::from(1u32) << 128u32) + 1u32), MR_ROUNDS));
+    }
+
+    // 对方发来的 p 可能是任意值，包括 0
+    #[test]
+    fn safe_prime_check_handles_tiny_and_even_inputs() {
+        let safe: Vec<u32> = (0u32..100).filter(|&p| is_safe_prime(&BigUint::from(p), MR_ROUNDS)).collect();
+        assert_eq!(safe, vec![5, 7, 11, 23, 47, 59, 83]);
+        assert!(!is_safe_prime(&(BigUint::from(1u32) << 64u32), MR_ROUNDS));
+    }
+
+    #[test]
+    fn generated_primes_are_safe_and_have_the_requested_size() {
+        for bits in [16, 64, 128] {
+            let mut candidates = 0;
+            let p = generate_safe_prime(bits, || candidates += 1);
+            assert_eq!(p.bits(), bits);
+            assert!(is_safe_prime(&p, MR_ROUNDS));
+            assert!(candidates > 0);
+        }
+        // 7 是安全素数（q = 3），13 是素数但不是安全素数
+        assert!(is_safe_prime(&BigUint::from(7u32), 8));
+        assert!(!is_safe_prime(&BigUint::from(13u32), 8));
+    }
+}
@@ -0,0 +1,373 @@
+use crate::chat::{self, DirectionKeys};
+use crate::cipher::CipherKind;
+use crate::frame::{self, Frame, FrameType};
+use crate::handshake::Role;
+use crate::kdf::SessionKeys;
+use crate::secret::Secret;
+use crate::transport::Transport;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// 录制文件格式（类似 pcap，所有整数都是大端）：
+// 文件头：magic "RUST03LG" | 版本 1 B | 录制方的角色 1 B | 开始时间（Unix 微秒）u64
+// 每条记录：距开始的微秒数 u64 | 方向 1 B（0 = 发出，1 = 收到）| 线路上的完整帧（帧头 + 负载）
+// 只记录线路上的字节，不包含任何密钥；解密需要另外提供会话密钥（见 save_key）
+pub const MAGIC: &[u8; 8] = b"RUST03LG";
+const LOG_VERSION: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+// 所有句柄共享的日志文件
+struct LogWriter {
+    out: BufWriter<File>,
+    started: Instant,
+}
+
+impl LogWriter {
+    fn write_entry(&mut self, direction: Direction, frame: &[u8]) -> io::Result<()> {
+        let micros = self.started.elapsed().as_micros() as u64;
+        self.out.write_all(&micros.to_be_bytes())?;
+        self.out.write_all(&[direction as u8])?;
+        self.out.write_all(frame)?;
+        // 每一帧都落盘，程序异常退出时日志仍然完整
+        self.out.flush()
+    }
+}
+
+// 把读写的字节流重新切成完整的帧：TCP 可能把一帧拆成几段，也可能把几帧合并
+#[derive(Default)]
+struct FrameSplitter {
+    pending: Vec<u8>,
+}
+
+impl FrameSplitter {
+    fn push(&mut self, bytes: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(bytes);
+        let mut frames = Vec::new();
+        while self.pending.len() >= frame::HEADER_LEN {
+            let len = u32::from_be_bytes(self.pending[2..6].try_into().expect("header checked above")) as usize;
+            if len > frame::MAX_PAYLOAD {
+                // 不是合法的帧，读帧的一方会报错；这里丢掉剩下的字节，不再记录
+                self.pending.clear();
+                break;
+            }
+            if self.pending.len() < frame::HEADER_LEN + len {
+                break;
+            }
+            frames.push(self.pending.drain(..frame::HEADER_LEN + len).collect());
+        }
+        frames
+    }
+}
+
+// 可以选择录制的传输层：不录制时原样转发，录制时把每一帧连同时间戳写进日志
+pub struct Recorded<S> {
+    inner: S,
+    log: Option<Arc<Mutex<LogWriter>>>,
+    sent: FrameSplitter,
+    received: FrameSplitter,
+}
+
+impl<S: Transport> Recorded<S> {
+    // 不录制
+    pub fn plain(inner: S) -> Self {
+        Recorded { inner, log: None, sent: FrameSplitter::default(), received: FrameSplitter::default() }
+    }
+
+    // 创建（覆盖）录制文件并写入文件头；role 是本端的角色，解密时用来区分两个方向
+    pub fn to_file(inner: S, role: Role, path: &Path) -> io::Result<Self> {
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&[LOG_VERSION, role_id(role)])?;
+        out.write_all(&(started_at.as_micros() as u64).to_be_bytes())?;
+        out.flush()?;
+        let log = LogWriter { out, started: Instant::now() };
+        Ok(Recorded { log: Some(Arc::new(Mutex::new(log))), ..Recorded::plain(inner) })
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.log.is_some()
+    }
+
+    fn record(&mut self, direction: Direction, bytes: &[u8]) -> io::Result<()> {
+        let Some(log) = &self.log else {
+            return Ok(());
+        };
+        let splitter = match direction {
+            Direction::Sent => &mut self.sent,
+            Direction::Received => &mut self.received,
+        };
+        let mut log = log.lock().unwrap();
+        for frame in splitter.push(bytes) {
+            log.write_entry(direction, &frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Transport> Read for Recorded<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.record(Direction::Received, &buf[..n])?;
+        Ok(n)
+    }
+}
+
+impl<S: Transport> Write for Recorded<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.record(Direction::Sent, &buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Transport> Transport for Recorded<S> {
+    // 克隆出的句柄写同一个日志文件，但各自拼装自己读写的帧
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Recorded { log: self.log.clone(), ..Recorded::plain(self.inner.try_clone()?) })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+fn role_id(role: Role) -> u8 {
+    match role {
+        Role::Server => 0,
+        Role::Client => 1,
+    }
+}
+
+// 读回的录制文件
+pub struct Log {
+    // 录制方的角色
+    pub role: Role,
+    pub started_at: SystemTime,
+    pub entries: Vec<Entry>,
+}
+
+pub struct Entry {
+    pub at: Duration,
+    pub direction: Direction,
+    pub frame: Frame,
+}
+
+impl Entry {
+    // 这一帧是哪一方发出的
+    pub fn sender(&self, recorder: Role) -> Role {
+        match self.direction {
+            Direction::Sent => recorder,
+            Direction::Received => recorder.peer(),
+        }
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+pub fn read_log(reader: &mut impl Read) -> io::Result<Log> {
+    let mut header = [0u8; MAGIC.len() + 2 + 8];
+    reader.read_exact(&mut header)?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not a rust_03 recording"));
+    }
+    let rest = &header[MAGIC.len()..];
+    if rest[0] != LOG_VERSION {
+        return Err(invalid(format!("unsupported recording version {}", rest[0])));
+    }
+    let role = match rest[1] {
+        0 => Role::Server,
+        1 => Role::Client,
+        other => return Err(invalid(format!("unknown role {other}"))),
+    };
+    let micros = u64::from_be_bytes(rest[2..].try_into().expect("8-byte timestamp"));
+    let started_at = UNIX_EPOCH + Duration::from_micros(micros);
+
+    let mut entries = Vec::new();
+    loop {
+        let mut prefix = [0u8; 9];
+        match reader.read_exact(&mut prefix) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let at = Duration::from_micros(u64::from_be_bytes(prefix[..8].try_into().expect("8-byte timestamp")));
+        let direction = match prefix[8] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            other => return Err(invalid(format!("unknown direction {other}"))),
+        };
+        let frame = frame::read_frame(reader)?.ok_or_else(|| invalid("recording ends in the middle of an entry"))?;
+        entries.push(Entry { at, direction, frame });
+    }
+    Ok(Log { role, started_at, entries })
+}
+
+// 会话密钥保存在日志旁边的 <日志>.key 里，只有文件所有者能读；它不打印到屏幕上，也不会出现在界面的记录里
+pub fn key_path(log: &Path) -> PathBuf {
+    let mut name = log.as_os_str().to_owned();
+    name.push(".key");
+    PathBuf::from(name)
+}
+
+pub fn save_key(log: &Path, session_key: &[u8; 32]) -> io::Result<PathBuf> {
+    let path = key_path(log);
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&path)?;
+    // mode 只对新建的文件生效：覆盖旧文件时先收紧权限再写入
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    writeln!(file, "{}", chat::to_hex(session_key))?;
+    Ok(path)
+}
+
+pub fn load_key(path: &Path) -> io::Result<Secret<[u8; 32]>> {
+    chat::parse_hex32(fs::read_to_string(path)?.trim())
+        .map(Secret::new)
+        .ok_or_else(|| invalid(format!("{} does not hold a session key (64 hex characters)", path.display())))
+}
+
+// 离线解密得到的一帧：握手帧原样返回负载，其他帧是解密后的明文或者拒绝的原因
+pub struct Replayed {
+    pub at: Duration,
+    pub sender: Role,
+    pub kind: FrameType,
+    // 被拒绝的帧没有可信的序列号
+    pub seq: Option<u64>,
+    pub plaintext: Result<Vec<u8>, String>,
+}
+
+// 用会话密钥重放整个日志。两个方向各自按接收方的规则检查标签、序列号和换钥，
+// 所以日志里的篡改、重放和丢帧会像实时会话一样被报告出来
+pub fn decrypt(log: &Log, session_key: [u8; 32]) -> Result<Vec<Replayed>, String> {
+    // 第一帧握手消息是 hello，它的第一个字节是双方约定的密码算法
+    let cipher = log
+        .entries
+        .iter()
+        .find(|entry| entry.frame.kind == FrameType::Handshake)
+        .and_then(|entry| entry.frame.payload.first())
+        .and_then(|&id| CipherKind::from_id(id))
+        .ok_or("the recording does not contain the hello message")?;
+    let keys = SessionKeys::from_session_key(session_key);
+    let mut from_client = DirectionKeys::new(cipher, keys.sending(Role::Client));
+    let mut from_server = DirectionKeys::new(cipher, keys.sending(Role::Server));
+
+    let mut replayed = Vec::new();
+    for entry in &log.entries {
+        let sender = entry.sender(log.role);
+        let kind = entry.frame.kind;
+        if kind == FrameType::Handshake {
+            replayed.push(Replayed { at: entry.at, sender, kind, seq: None, plaintext: Ok(entry.frame.payload.clone()) });
+            continue;
+        }
+        let keys = match sender {
+            Role::Client => &mut from_client,
+            Role::Server => &mut from_server,
+        };
+        let mut wire = Vec::new();
+        frame::write_frame(&mut wire, &entry.frame).map_err(|e| e.to_string())?;
+        let plaintext = match chat::receive_record(&mut wire.as_slice(), keys) {
+            Ok(Some(frame)) => Ok(frame.payload),
+            Ok(None) => Err("truncated frame".to_string()),
+            Err(e) => Err(e),
+        };
+        // 通过校验后，序列号就是接收方刚刚接受的那一个
+        let seq = plaintext.is_ok().then(|| keys.next_seq() - 1);
+        replayed.push(Replayed { at: entry.at, sender, kind, seq, plaintext });
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{RekeyPolicy, Timeouts};
+    use crate::dh::DhGroup;
+    use crate::handshake::{Handshake, Hello};
+    use crate::identity::Identity;
+    use crate::kex::KexKind;
+    use crate::session::Session;
+    use crate::transport::{self, PipeEnd};
+    use std::thread;
+
+    #[test]
+    fn splitter_reassembles_split_and_merged_frames() {
+        let mut wire = Vec::new();
+        for payload in [&b"first"[..], b"", b"third frame"] {
+            frame::write_frame(&mut wire, &Frame::new(FrameType::Message, payload.to_vec())).unwrap();
+        }
+        let mut splitter = FrameSplitter::default();
+        let mut frames = splitter.push(&wire[..3]);
+        frames.extend(splitter.push(&wire[3..20]));
+        frames.extend(splitter.push(&wire[20..]));
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames.concat(), wire);
+    }
+
+    // 录制一次真实的会话（握手 + 双向消息），再只用会话密钥离线还原对话
+    #[test]
+    fn recorded_session_decrypts_offline() {
+        let path = std::env::temp_dir().join(format!("rust_03-record-{}.log", std::process::id()));
+        let hello = Hello { cipher: CipherKind::AesCtr, kex: KexKind::X25519, group: DhGroup::Toy64, authenticate: true };
+        let (client_end, server_end) = transport::pipe();
+        let server = thread::spawn(move || {
+            let identity = Identity::generate();
+            let handshake = Handshake::new(Role::Server, hello, &identity);
+            let mut session: Session<PipeEnd> =
+                Session::establish(server_end, &handshake, RekeyPolicy::default(), Timeouts::default()).unwrap();
+            assert_eq!(session.receive(), Ok(Some(b"hi server".to_vec())));
+            session.send(b"hi client").unwrap();
+        });
+        let identity = Identity::generate();
+        let handshake = Handshake::new(Role::Client, hello, &identity);
+        let recorded = Recorded::to_file(client_end, Role::Client, &path).unwrap();
+        let mut client = Session::establish(recorded, &handshake, RekeyPolicy::default(), Timeouts::default()).unwrap();
+        client.send(b"hi server").unwrap();
+        assert_eq!(client.receive(), Ok(Some(b"hi client".to_vec())));
+        server.join().unwrap();
+        let key_file = save_key(&path, client.session_key()).unwrap();
+        drop(client);
+        assert_eq!(key_file, key_path(&path));
+        assert_eq!(fs::metadata(&key_file).unwrap().permissions().mode() & 0o777, 0o600);
+        let session_key = *load_key(&key_file).unwrap();
+        fs::remove_file(&key_file).unwrap();
+
+        let log = read_log(&mut File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(log.role, Role::Client);
+        assert!(log.entries.windows(2).all(|pair| pair[0].at <= pair[1].at));
+
+        let replayed = decrypt(&log, session_key).unwrap();
+        let chat: Vec<_> = replayed
+            .iter()
+            .filter(|r| r.kind == FrameType::Message)
+            .map(|r| (r.sender, r.seq, r.plaintext.clone()))
+            .collect();
+        assert_eq!(
+            chat,
+            vec![
+                (Role::Client, Some(0), Ok(b"hi server".to_vec())),
+                (Role::Server, Some(0), Ok(b"hi client".to_vec())),
+            ]
+        );
+        assert!(replayed.iter().any(|r| r.kind == FrameType::Handshake && r.sender == Role::Server));
+
+        // 密钥不对时每条加密记录都被拒绝，而不是解出乱码
+        let wrong = decrypt(&log, [0u8; 32]).unwrap();
+        assert!(wrong.iter().filter(|r| r.kind != FrameType::Handshake).all(|r| r.plaintext.is_err()));
+    }
+}
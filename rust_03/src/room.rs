@@ -0,0 +1,408 @@
+use crate::chat::{self, DirectionKeys};
+use crate::dh::DhPolicy;
+use crate::frame::FrameType;
+use crate::handshake::{Handshake, Hello, Role};
+use crate::identity::{self, Identity};
+use crate::net;
+use crate::session;
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Duration;
+
+// 每个成员的发送队列最多积压这么多条记录；积压满了说明对方已经不读了，把它移出房间
+const QUEUE_LEN: usize = 256;
+
+// 每个连接线程发给广播线程的事件
+enum RoomEvent {
+    Joined {
+        id: usize,
+        name: String,
+        writer: TcpStream,
+        keys: DirectionKeys,
+    },
+    Message {
+        id: usize,
+        text: Vec<u8>,
+    },
+    // 客户端的心跳，由广播线程用该成员的发送密钥回复 Pong
+    Ping {
+        id: usize,
+        counter: Vec<u8>,
+    },
+    Left {
+        id: usize,
+    },
+}
+
+// 已完成握手的成员。广播线程只把记录放进它的队列，真正的写由它自己的发送线程完成，
+// 一个不读数据的成员最多堵住自己的发送线程；stream 用来在移出房间时关闭连接
+struct Member {
+    name: String,
+    stream: TcpStream,
+    outbox: mpsc::SyncSender<(FrameType, Vec<u8>)>,
+}
+
+impl Member {
+    // 发送线程独占这个成员的发送密钥，因此密钥流只有一个使用者
+    fn start(name: String, stream: TcpStream, mut writer: TcpStream, mut keys: DirectionKeys) -> Self {
+        let (outbox, queue) = mpsc::sync_channel::<(FrameType, Vec<u8>)>(QUEUE_LEN);
+        thread::spawn(move || {
+            for (kind, payload) in queue {
+                if chat::send_record(&mut writer, &mut keys, kind, &payload).is_err() {
+                    // 关闭连接后接收线程读到结尾，会报告 Left
+                    let _ = writer.shutdown(Shutdown::Both);
+                    break;
+                }
+            }
+        });
+        Member { name, stream, outbox }
+    }
+
+    // 不会阻塞：队列满了或发送线程已经退出时返回 false
+    fn queue(&self, kind: FrameType, payload: Vec<u8>) -> bool {
+        self.outbox.try_send((kind, payload)).is_ok()
+    }
+}
+
+// 接受任意多个客户端：每个连接在自己的线程里做握手和接收，在 handshake 内没有完成握手就断开。
+// 广播线程决定每条消息发给谁，再交给各个成员自己的发送线程
+pub fn serve(listener: TcpListener, hello: Hello, policy: DhPolicy, identity: Arc<Identity>, handshake: Duration) {
+    let (events, inbox) = mpsc::channel();
+    thread::spawn(move || broadcast(inbox));
+
+    for (id, conn) in listener.incoming().enumerate() {
+        let stream = match conn {
+            Ok(stream) => stream,
+            Err(e) => {
+                say!("[ROOM] Accept failed: {e}");
+                continue;
+            }
+        };
+        let events = events.clone();
+        let identity = Arc::clone(&identity);
+        thread::spawn(move || serve_member(id, stream, hello, policy, &identity, handshake, events));
+    }
+}
+
+// 与单个客户端握手，然后把它发来的消息转交给广播线程
+fn serve_member(
+    id: usize,
+    mut stream: TcpStream,
+    hello: Hello,
+    policy: DhPolicy,
+    identity: &Identity,
+    limit: Duration,
+    events: mpsc::Sender<RoomEvent>,
+) {
+    let name = match stream.peer_addr() {
+        Ok(addr) => net::canonical(addr).to_string(),
+        Err(_) => format!("client-{id}"),
+    };
+    info!("[ROOM] {name} connected, starting handshake...");
+
+    // 每个客户端都有独立的 DH 交换和独立的密钥
+    let handshake = Handshake::new(Role::Server, hello, identity).with_dh_policy(policy);
+    let session = match session::perform_within(&handshake, &mut stream, limit) {
+        Ok(established) => {
+            match &established.peer_identity {
+                Some(peer) => info!("[ROOM] {name} authenticated as {}", identity::fingerprint(peer)),
+                None => info!("[ROOM] {name} is NOT authenticated"),
+            }
+            established.keys
+        }
+        Err(e) => {
+            say!("[ROOM] Handshake with {name} failed: {e}");
+            return;
+        }
+    };
+    let send_keys = DirectionKeys::new(hello.cipher, session.sending(Role::Server));
+    let mut receive_keys = DirectionKeys::new(hello.cipher, session.receiving(Role::Server));
+
+    let writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            say!("[ROOM] Cannot register {name}: {e}");
+            return;
+        }
+    };
+    let joined = RoomEvent::Joined {
+        id,
+        name: name.clone(),
+        writer,
+        keys: send_keys,
+    };
+    if events.send(joined).is_err() {
+        return;
+    }
+
+    loop {
+        let event = match chat::receive_record(&mut stream, &mut receive_keys) {
+            Ok(Some(record)) => match record.kind {
+                FrameType::Message => RoomEvent::Message { id, text: record.payload },
+                FrameType::Ping => RoomEvent::Ping { id, counter: record.payload },
+                FrameType::Pong => continue,
+                kind => {
+                    info!("[ROOM] Ignoring {kind:?} record from {name}: not supported in the room");
+                    continue;
+                }
+            },
+            Ok(None) => break,
+            Err(e) => {
+                say!("[AUTH] ✗ Message from {name} rejected: {e}");
+                continue;
+            }
+        };
+        if events.send(event).is_err() {
+            return;
+        }
+    }
+    let _ = events.send(RoomEvent::Left { id });
+}
+
+fn broadcast(inbox: mpsc::Receiver<RoomEvent>) {
+    let mut members: HashMap<usize, Member> = HashMap::new();
+
+    for event in inbox {
+        match event {
+            RoomEvent::Joined {
+                id,
+                name,
+                writer,
+                keys,
+            } => {
+                let Ok(stream) = writer.try_clone() else { continue };
+                let member = Member::start(name, stream, writer, keys);
+                let welcome = format!(
+                    "*** Welcome {}, {} other participant(s) online ***",
+                    member.name,
+                    members.len()
+                );
+                if !member.queue(FrameType::Message, welcome.into_bytes()) {
+                    continue;
+                }
+                let notice = format!("*** {} joined the room ***", member.name);
+                info!("[ROOM] {} joined ({} online)", member.name, members.len() + 1);
+                members.insert(id, member);
+                send_to_others(&mut members, id, notice.as_bytes());
+            }
+            RoomEvent::Message { id, text } => {
+                let Some(sender) = members.get(&id) else { continue };
+                // 用每个接收者自己的密钥重新加密
+                let mut line = format!("{}: ", sender.name).into_bytes();
+                line.extend_from_slice(&text);
+                send_to_others(&mut members, id, &line);
+            }
+            RoomEvent::Ping { id, counter } => {
+                let Some(member) = members.get(&id) else { continue };
+                if !member.queue(FrameType::Pong, counter) {
+                    leave(&mut members, id);
+                }
+            }
+            RoomEvent::Left { id } => leave(&mut members, id),
+        }
+    }
+}
+
+fn send_to_others(members: &mut HashMap<usize, Member>, from: usize, msg: &[u8]) {
+    let mut failed = Vec::new();
+    for (&id, member) in members.iter() {
+        if id == from {
+            continue;
+        }
+        if !member.queue(FrameType::Message, msg.to_vec()) {
+            say!("[ROOM] {} is not keeping up, dropping it", member.name);
+            failed.push(id);
+        }
+    }
+    // 跟不上的成员视为已离开，其余人继续聊天
+    for id in failed {
+        leave(members, id);
+    }
+}
+
+fn leave(members: &mut HashMap<usize, Member>, id: usize) {
+    if let Some(member) = members.remove(&id) {
+        let _ = member.stream.shutdown(Shutdown::Both);
+        info!("[ROOM] {} left ({} online)", member.name, members.len());
+        let notice = format!("*** {} left the room ***", member.name);
+        send_to_others(members, id, notice.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::CipherKind;
+    use crate::dh::DhGroup;
+    use crate::kex::KexKind;
+    use std::net::SocketAddr;
+
+    const HELLO: Hello = Hello {
+        cipher: CipherKind::Chacha20,
+        kex: KexKind::X25519,
+        group: DhGroup::Toy64,
+        authenticate: true,
+    };
+
+    struct TestClient {
+        name: String,
+        stream: TcpStream,
+        send_keys: DirectionKeys,
+        receive_keys: DirectionKeys,
+    }
+
+    impl TestClient {
+        fn connect(addr: SocketAddr) -> Self {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let name = stream.local_addr().unwrap().to_string();
+            let session = Handshake::new(Role::Client, HELLO, &Identity::generate()).perform(&mut stream)
+                .unwrap()
+                .keys;
+            TestClient {
+                name,
+                stream,
+                send_keys: DirectionKeys::new(HELLO.cipher, session.sending(Role::Client)),
+                receive_keys: DirectionKeys::new(HELLO.cipher, session.receiving(Role::Client)),
+            }
+        }
+
+        fn say(&mut self, msg: &str) {
+            chat::send_message(&mut self.stream, &mut self.send_keys, msg.as_bytes()).unwrap();
+        }
+
+        fn hear(&mut self) -> String {
+            let msg = chat::receive_message(&mut self.stream, &mut self.receive_keys);
+            String::from_utf8(msg.unwrap().unwrap()).unwrap()
+        }
+    }
+
+    fn start_room() -> SocketAddr {
+        open_room(TcpListener::bind("127.0.0.1:0").unwrap())
+    }
+
+    fn open_room(listener: TcpListener) -> SocketAddr {
+        let addr = listener.local_addr().unwrap();
+        let identity = Arc::new(Identity::generate());
+        thread::spawn(move || serve(listener, HELLO, DhPolicy::default(), identity, Duration::from_millis(300)));
+        addr
+    }
+
+    // 依次加入 n 个客户端，每次都等已在房间里的人收到加入通知
+    fn join_clients(addr: SocketAddr, n: usize) -> Vec<TestClient> {
+        let mut clients: Vec<TestClient> = Vec::new();
+        for i in 0..n {
+            let mut client = TestClient::connect(addr);
+            let welcome = client.hear();
+            assert!(welcome.contains(&format!("{i} other participant(s)")), "{welcome}");
+            for other in clients.iter_mut() {
+                assert_eq!(other.hear(), format!("*** {} joined the room ***", client.name));
+            }
+            clients.push(client);
+        }
+        clients
+    }
+
+    #[test]
+    fn messages_are_broadcast_to_everyone_else() {
+        let mut clients = join_clients(start_room(), 4);
+
+        clients[0].say("hello room");
+        let expected = format!("{}: hello room", clients[0].name);
+        for client in clients[1..].iter_mut() {
+            assert_eq!(client.hear(), expected);
+        }
+
+        // 发送者自己不会收到回显：它收到的下一条是别人的消息
+        clients[3].say("hi");
+        let expected = format!("{}: hi", clients[3].name);
+        for client in clients[..3].iter_mut() {
+            assert_eq!(client.hear(), expected);
+        }
+    }
+
+    #[test]
+    fn room_survives_a_client_leaving() {
+        let mut clients = join_clients(start_room(), 3);
+
+        let gone = clients.remove(1);
+        let gone_name = gone.name.clone();
+        drop(gone);
+        for client in clients.iter_mut() {
+            assert_eq!(client.hear(), format!("*** {gone_name} left the room ***"));
+        }
+
+        clients[1].say("still here?");
+        assert_eq!(clients[0].hear(), format!("{}: still here?", clients[1].name));
+
+        // 之后加入的客户端也能正常聊天
+        let mut late = TestClient::connect(clients[0].stream.peer_addr().unwrap());
+        assert!(late.hear().contains("2 other participant(s)"));
+        for client in clients.iter_mut() {
+            assert_eq!(client.hear(), format!("*** {} joined the room ***", late.name));
+        }
+        late.say("made it");
+        for client in clients.iter_mut() {
+            assert_eq!(client.hear(), format!("{}: made it", late.name));
+        }
+    }
+
+    // 一个成员完全不读数据时，其他人照常收到每一条消息，堵住的成员被移出房间
+    #[test]
+    fn a_stalled_member_does_not_block_the_room() {
+        // 小缓冲区让堵住的成员很快就积压满（接受的连接继承监听 socket 的 SO_SNDBUF）
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        socket2::SockRef::from(&listener).set_send_buffer_size(64 * 1024).unwrap();
+        let mut clients = join_clients(open_room(listener), 3);
+        let stalled = clients.remove(0);
+        socket2::SockRef::from(&stalled.stream).set_recv_buffer_size(64 * 1024).unwrap();
+        let big = "x".repeat(4 * 1024);
+        let (mut speaker, mut listener) = (clients.remove(0), clients.remove(0));
+        let expected = format!("{}: {big}", speaker.name);
+
+        // 消息总量远超堵住的成员的队列和 socket 缓冲区
+        // 说话的一方要留到最后：带着没读的通知关闭连接会发出 RST，房间可能丢掉还没读的消息
+        let speaking = thread::spawn(move || {
+            for _ in 0..QUEUE_LEN * 2 {
+                speaker.say(&big);
+            }
+            speaker
+        });
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            let mut heard = 0;
+            while heard < QUEUE_LEN * 2 {
+                let msg = listener.hear();
+                if msg.starts_with("***") {
+                    continue;
+                }
+                assert_eq!(msg, expected);
+                heard += 1;
+            }
+            let _ = done.send(());
+        });
+        let result = finished.recv_timeout(Duration::from_secs(20));
+        drop(stalled);
+        assert!(result.is_ok(), "the room stopped delivering messages");
+        drop(speaking.join());
+    }
+
+    // 只连接不握手的客户端在截止时间后被断开
+    #[test]
+    fn silent_connections_are_dropped_after_the_handshake_deadline() {
+        use std::io::Read;
+        let mut silent = TcpStream::connect(start_room()).unwrap();
+        silent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = [0u8; 1024];
+        // 服务器先发来自己的 Hello，随后因为超时关闭连接
+        loop {
+            match silent.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => break,
+                Err(e) => panic!("the room kept a silent connection open: {e}"),
+            }
+        }
+    }
+}
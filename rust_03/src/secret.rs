@@ -0,0 +1,61 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use zeroize::Zeroize;
+
+// 秘密值（私钥、共享密钥、会话密钥）的容器：离开作用域时用 zeroize 清零内存，
+// 避免密钥在释放后还留在堆或栈上；Debug 不显示内容，打印秘密必须显式转换（只在 --teach 下这样做）
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+}
+
+impl<T: Zeroize> Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> DerefMut for Secret<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Self {
+        Secret(self.0.clone())
+    }
+}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_output_hides_the_value() {
+        let mut secret = Secret::new(vec![0xABu8; 4]);
+        assert_eq!(format!("{secret:?}"), "Secret(<redacted>)");
+        secret[0] = 1;
+        assert_eq!(secret[..], [1, 0xAB, 0xAB, 0xAB]);
+        // Drop 调用的就是 zeroize：内容清零，长度归零
+        secret.zeroize();
+        assert!(secret.is_empty());
+    }
+}
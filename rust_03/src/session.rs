@@ -0,0 +1,307 @@
+use crate::chat::{self, DirectionKeys, RekeyPolicy, Timeouts};
+use crate::cipher::CipherKind;
+use crate::error::ChatError;
+use crate::handshake::{Established, Handshake, Role};
+use crate::secret::Secret;
+use crate::transfer::Transfers;
+use crate::transport::Transport;
+use ed25519_dalek::VerifyingKey;
+use std::io::{self, BufRead, Read, Write};
+use std::net::Shutdown;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// 握手完成后的加密会话：底层字节流加上两个方向各自的密钥。
+// 服务器和客户端在握手之后的流程完全相同，都通过它收发消息
+pub struct Session<S> {
+    stream: S,
+    role: Role,
+    cipher: CipherKind,
+    send_keys: DirectionKeys,
+    receive_keys: DirectionKeys,
+    peer_identity: Option<VerifyingKey>,
+    session_key: Secret<[u8; 32]>,
+    timeouts: Timeouts,
+}
+
+impl<S: Read + Write> Session<S> {
+    // 用握手结果建立会话；每个方向有独立的密钥流和 MAC 密钥
+    pub fn new(stream: S, role: Role, established: Established, policy: RekeyPolicy, timeouts: Timeouts) -> Self {
+        let (cipher, keys) = (established.hello.cipher, established.keys);
+        verbose!("[VERIFY] Both sides computed the same secret ✓");
+        verbose!("[STREAM] Generating keystreams from session keys...");
+        info!("Algorithm: {}", cipher.describe());
+        Session {
+            stream,
+            role,
+            cipher,
+            send_keys: DirectionKeys::new(cipher, keys.sending(role)).with_policy(policy),
+            receive_keys: DirectionKeys::new(cipher, keys.receiving(role)),
+            peer_identity: established.peer_identity,
+            session_key: Secret::new(keys.session_key),
+            timeouts,
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn cipher(&self) -> CipherKind {
+        self.cipher
+    }
+
+    // 对方已通过签名验证的长期身份公钥；未认证的握手为 None
+    pub fn peer_identity(&self) -> Option<&VerifyingKey> {
+        self.peer_identity.as_ref()
+    }
+
+    // 两个方向密钥的来源，录制的日志要用它离线解密；和私钥一样不能泄露
+    pub fn session_key(&self) -> &[u8; 32] {
+        &self.session_key
+    }
+
+    pub fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        chat::send_message(&mut self.stream, &mut self.send_keys, msg)
+    }
+
+    // 返回 Ok(None) 表示对方已断开；Err 表示消息被拒绝
+    pub fn receive(&mut self) -> Result<Option<Vec<u8>>, String> {
+        chat::receive_message(&mut self.stream, &mut self.receive_keys)
+    }
+}
+
+impl<S: Transport> Session<S> {
+    // 在 stream 上完成握手并建立会话。对方在 timeouts.handshake 内没有完成握手时关闭连接，
+    // 避免一个只连接不说话的对方让我们永远等下去
+    pub fn establish(
+        mut stream: S,
+        handshake: &Handshake,
+        policy: RekeyPolicy,
+        timeouts: Timeouts,
+    ) -> Result<Self, ChatError> {
+        let established = perform_within(handshake, &mut stream, timeouts.handshake)?;
+        Ok(Session::new(stream, handshake.role, established, policy, timeouts))
+    }
+
+    // 进入全双工聊天，直到任意一方结束会话
+    pub fn run_chat(
+        self,
+        input: impl BufRead + Send + 'static,
+        transfers: Transfers,
+        on_message: impl FnMut(&[u8]),
+    ) -> Result<(), ChatError> {
+        chat::run_chat(
+            self.stream,
+            self.send_keys,
+            self.receive_keys,
+            input,
+            transfers,
+            self.timeouts,
+            on_message,
+        )
+    }
+}
+
+// 在 limit 内完成握手，超时就关闭连接让 perform 立刻返回。聊天室的每个成员也用它，
+// 只连接不说话的客户端不会一直占着一个线程
+pub fn perform_within<S: Transport>(
+    handshake: &Handshake,
+    stream: &mut S,
+    limit: Duration,
+) -> Result<Established, ChatError> {
+    let watchdog = stream.try_clone().map_err(ChatError::io("cloning the connection"))?;
+    let (done, finished) = mpsc::channel::<()>();
+    let timer = thread::spawn(move || {
+        let expired = finished.recv_timeout(limit) == Err(mpsc::RecvTimeoutError::Timeout);
+        if expired {
+            let _ = watchdog.shutdown(Shutdown::Both);
+        }
+        expired
+    });
+    let result = handshake.perform(stream);
+    drop(done);
+    if timer.join().unwrap_or(false) {
+        return Err(ChatError::Timeout(format!(
+            "the peer did not complete the handshake within {}s",
+            limit.as_secs_f64()
+        )));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dh::DhGroup;
+    use crate::handshake::Hello;
+    use crate::identity::Identity;
+    use crate::kex::KexKind;
+    use crate::transport::{self, PipeEnd};
+    use std::io::{BufReader, Cursor};
+    use std::os::unix::net::UnixStream;
+    use std::time::{Duration, Instant};
+
+    const HELLO: Hello = Hello {
+        cipher: CipherKind::Chacha20,
+        kex: KexKind::X25519,
+        group: DhGroup::Toy64,
+        authenticate: true,
+    };
+
+    // 测试用的短时限：心跳 50ms，300ms 没有回应就算掉线
+    const FAST: Timeouts = Timeouts {
+        handshake: Duration::from_millis(300),
+        heartbeat: Duration::from_millis(50),
+        dead_peer: Duration::from_millis(300),
+        idle: None,
+    };
+
+    // 在内存管道上完成握手，不需要任何网络连接
+    fn connected_sessions(timeouts: Timeouts) -> (Session<PipeEnd>, Session<PipeEnd>) {
+        connected_over(transport::pipe(), timeouts)
+    }
+
+    fn connected_over<S: Transport>((client_end, server_end): (S, S), timeouts: Timeouts) -> (Session<S>, Session<S>) {
+        let server = thread::spawn(move || {
+            let identity = Identity::generate();
+            let handshake = Handshake::new(Role::Server, HELLO, &identity);
+            Session::establish(server_end, &handshake, RekeyPolicy::default(), timeouts).unwrap()
+        });
+        let identity = Identity::generate();
+        let handshake = Handshake::new(Role::Client, HELLO, &identity);
+        let client = Session::establish(client_end, &handshake, RekeyPolicy::default(), timeouts).unwrap();
+        (client, server.join().unwrap())
+    }
+
+    // 一直打开、但永远没有输入的终端
+    fn silent_input() -> (BufReader<PipeEnd>, PipeEnd) {
+        let (keyboard, input) = transport::pipe();
+        (BufReader::new(input), keyboard)
+    }
+
+    #[test]
+    fn sessions_talk_over_a_memory_pipe() {
+        let (mut client, mut server) = connected_sessions(Timeouts::default());
+        assert!(client.peer_identity().is_some() && server.peer_identity().is_some());
+        assert_eq!(client.role().peer(), server.role());
+
+        client.send(b"ping").unwrap();
+        assert_eq!(server.receive(), Ok(Some(b"ping".to_vec())));
+        server.send(b"pong").unwrap();
+        assert_eq!(client.receive(), Ok(Some(b"pong".to_vec())));
+
+        drop(client);
+        assert_eq!(server.receive(), Ok(None));
+    }
+
+    // --stdio 和 --unix 用到的传输层：同样的会话跑在 OS 管道和 Unix 域套接字上，不需要打开端口
+    #[test]
+    fn sessions_talk_over_os_pipes_and_unix_sockets() {
+        fn talk<S: Transport>(ends: (S, S)) {
+            let (mut client, mut server) = connected_over(ends, Timeouts::default());
+            client.send(b"ping").unwrap();
+            assert_eq!(server.receive(), Ok(Some(b"ping".to_vec())));
+            server.send(b"pong").unwrap();
+            assert_eq!(client.receive(), Ok(Some(b"pong".to_vec())));
+            drop(client);
+            assert_eq!(server.receive(), Ok(None));
+        }
+        let (a_reader, b_writer) = io::pipe().unwrap();
+        let (b_reader, a_writer) = io::pipe().unwrap();
+        talk((transport::duplex(a_reader, a_writer).unwrap(), transport::duplex(b_reader, b_writer).unwrap()));
+        talk(UnixStream::pair().unwrap());
+    }
+
+    // 双方运行同一个聊天循环，输入读完后半关闭，收齐对方的消息后结束
+    #[test]
+    fn chat_loop_runs_over_a_memory_pipe() {
+        let (client, server) = connected_sessions(Timeouts::default());
+        let downloads = std::env::temp_dir().join(format!("rust_03-session-{}", std::process::id()));
+        let chat = |session: Session<PipeEnd>, lines: &'static str| {
+            let downloads = downloads.clone();
+            thread::spawn(move || {
+                let mut received = Vec::new();
+                session
+                    .run_chat(Cursor::new(lines), Transfers::new(downloads), |msg| received.push(msg.to_vec()))
+                    .unwrap();
+                received
+            })
+        };
+        let client = chat(client, "hello\nhow are you?\n");
+        let server = chat(server, "fine, thanks\n");
+
+        assert_eq!(client.join().unwrap(), vec![b"fine, thanks".to_vec()]);
+        assert_eq!(server.join().unwrap(), vec![b"hello".to_vec(), b"how are you?".to_vec()]);
+    }
+
+    // 只连接不说话的对方不能让握手永远等下去
+    #[test]
+    fn silent_peer_times_out_during_the_handshake() {
+        let (client_end, _silent_server) = transport::pipe();
+        let identity = Identity::generate();
+        let handshake = Handshake::new(Role::Client, HELLO, &identity);
+        let started = Instant::now();
+        let result = Session::establish(client_end, &handshake, RekeyPolicy::default(), FAST);
+        assert!(matches!(result, Err(ChatError::Timeout(_))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    // 对方进程卡死（连接还在，但不再读也不再写）：在 dead_peer 时限内报告超时，而不是一直阻塞
+    #[test]
+    fn dead_peer_is_detected_by_missing_heartbeats() {
+        let (client, frozen_server) = connected_sessions(FAST);
+        let (input, _keyboard) = silent_input();
+        let started = Instant::now();
+        let result = client.run_chat(input, Transfers::new(std::env::temp_dir()), |_| {});
+        assert!(matches!(result, Err(ChatError::Timeout(_))), "{result:?}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(frozen_server);
+    }
+
+    // 双方都不说话时，心跳让连接保持有效，直到空闲时限到期才正常结束
+    #[test]
+    fn heartbeats_keep_a_quiet_session_alive_until_idle() {
+        let idle = Duration::from_millis(1000);
+        let (client, server) = connected_sessions(Timeouts { idle: Some(idle), ..FAST });
+        let started = Instant::now();
+        let peers: Vec<_> = [client, server]
+            .into_iter()
+            .map(|session| {
+                thread::spawn(move || {
+                    let (input, _keyboard) = silent_input();
+                    session.run_chat(input, Transfers::new(std::env::temp_dir()), |_| {})
+                })
+            })
+            .collect();
+        for peer in peers {
+            assert!(peer.join().unwrap().is_ok());
+        }
+        // 远远超过 dead_peer（300ms），说明心跳一直在起作用
+        assert!(started.elapsed() >= idle);
+    }
+
+    // 提议大文件时聊天主循环照常运行：计算哈希期间心跳不断，对方不会误判掉线
+    #[test]
+    fn heartbeats_continue_while_a_large_file_is_hashed() {
+        let dir = std::env::temp_dir().join(format!("rust_03-session-hash-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let big = dir.join("big.bin");
+        // debug 构建下算完它的哈希要一秒左右，远远超过 dead_peer（300ms）
+        std::fs::write(&big, vec![7u8; 32 << 20]).unwrap();
+        let (client, server) = connected_sessions(Timeouts { idle: Some(Duration::from_millis(1500)), ..FAST });
+
+        let downloads = dir.join("downloads");
+        let server = thread::spawn(move || {
+            let (input, _keyboard) = silent_input();
+            server.run_chat(input, Transfers::new(downloads), |_| {})
+        });
+        let (input, mut keyboard) = silent_input();
+        writeln!(keyboard, "/send {}", big.display()).unwrap();
+        let result = client.run_chat(input, Transfers::new(&dir), |_| {});
+        assert!(result.is_ok(), "{result:?}");
+        assert!(server.join().unwrap().is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,723 @@
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+// 每个数据块的明文大小
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+// 输入 /send <path> 向对方提供文件，/accept、/reject 回应对方最早的一个提议
+pub const SEND_COMMAND: &str = "/send";
+pub const ACCEPT_COMMAND: &str = "/accept";
+pub const REJECT_COMMAND: &str = "/reject";
+
+// 文件传输消息，作为 File 类型的记录加密发送：
+//   op(1) || id(u32) || 各自的字段
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileMessage {
+    // 发送方提议：文件大小、整个文件的 SHA-256 和文件名
+    Offer { id: u32, size: u64, hash: [u8; 32], name: String },
+    // 接收方接受；offset 是已经收到的字节数（断点续传时大于 0）
+    Accept { id: u32, offset: u64 },
+    Reject { id: u32 },
+    Chunk { id: u32, offset: u64, data: Vec<u8> },
+    Done { id: u32 },
+    // 接收方校验整个文件的哈希后回报结果；接收方这边出错时也用 ok = false 通知发送方
+    Verified { id: u32, ok: bool },
+    // 发送方读取文件出错，放弃这次传输。Reject 是接收方发的，id 属于对方的编号，不能混用
+    Cancel { id: u32 },
+}
+
+impl FileMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (op, id) = match self {
+            FileMessage::Offer { id, .. } => (1u8, id),
+            FileMessage::Accept { id, .. } => (2, id),
+            FileMessage::Reject { id } => (3, id),
+            FileMessage::Chunk { id, .. } => (4, id),
+            FileMessage::Done { id } => (5, id),
+            FileMessage::Verified { id, .. } => (6, id),
+            FileMessage::Cancel { id } => (7, id),
+        };
+        let mut out = vec![op];
+        out.extend_from_slice(&id.to_be_bytes());
+        match self {
+            FileMessage::Offer { size, hash, name, .. } => {
+                out.extend_from_slice(&size.to_be_bytes());
+                out.extend_from_slice(hash);
+                out.extend_from_slice(name.as_bytes());
+            }
+            FileMessage::Accept { offset, .. } => out.extend_from_slice(&offset.to_be_bytes()),
+            FileMessage::Chunk { offset, data, .. } => {
+                out.extend_from_slice(&offset.to_be_bytes());
+                out.extend_from_slice(data);
+            }
+            FileMessage::Verified { ok, .. } => out.push(*ok as u8),
+            FileMessage::Reject { .. } | FileMessage::Done { .. } | FileMessage::Cancel { .. } => {}
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let malformed = || format!("malformed file message ({} bytes)", bytes.len());
+        if bytes.len() < 5 {
+            return Err(malformed());
+        }
+        let id = u32::from_be_bytes(bytes[1..5].try_into().expect("length checked above"));
+        let rest = &bytes[5..];
+        let u64_at = |at: usize| -> Result<u64, String> {
+            rest.get(at..at + 8)
+                .map(|b| u64::from_be_bytes(b.try_into().expect("slice of 8 bytes")))
+                .ok_or_else(malformed)
+        };
+        match bytes[0] {
+            1 => {
+                let size = u64_at(0)?;
+                let hash: [u8; 32] = rest.get(8..40).ok_or_else(malformed)?.try_into().expect("slice of 32 bytes");
+                let name = String::from_utf8(rest[40..].to_vec()).map_err(|_| "file name is not UTF-8".to_string())?;
+                Ok(FileMessage::Offer { id, size, hash, name })
+            }
+            2 => Ok(FileMessage::Accept { id, offset: u64_at(0)? }),
+            3 => Ok(FileMessage::Reject { id }),
+            4 => Ok(FileMessage::Chunk {
+                id,
+                offset: u64_at(0)?,
+                data: rest[8..].to_vec(),
+            }),
+            5 => Ok(FileMessage::Done { id }),
+            6 => match rest {
+                [ok] => Ok(FileMessage::Verified { id, ok: *ok == 1 }),
+                _ => Err(malformed()),
+            },
+            7 => Ok(FileMessage::Cancel { id }),
+            op => Err(format!("unknown file message type {op}")),
+        }
+    }
+}
+
+// 我们提议发送的文件。大文件的哈希和发送都要很久，由 Transfers::step 每次只前进一个数据块
+struct Outgoing {
+    name: String,
+    size: u64,
+    file: File,
+    stage: Stage,
+    progress: Progress,
+}
+
+enum Stage {
+    // 正在计算整个文件的哈希，算完才能发出提议
+    Hashing { hasher: Sha256, hashed: u64 },
+    // 已经提议，等对方回应
+    Offered,
+    // 对方已接受，下一个数据块从 sent 开始
+    Sending { sent: u64 },
+    // 数据已全部发出，等对方的校验结果
+    Sent,
+}
+
+// 对方提议、还在等用户决定的文件
+struct Offer {
+    id: u32,
+    size: u64,
+    hash: [u8; 32],
+    name: String,
+}
+
+// 正在接收的文件：数据先写入以哈希命名的 .part 文件，校验通过后才改成正式文件名。
+// 哈希随数据块更新；续传时 .part 里已有的前缀由 step 逐块补算，hashed 追上 received 之后才直接更新
+struct Incoming {
+    name: String,
+    size: u64,
+    hash: [u8; 32],
+    part_path: PathBuf,
+    file: File,
+    received: u64,
+    hasher: Sha256,
+    hashed: u64,
+    // 对方已发来 Done，哈希追上之后就可以校验
+    done: bool,
+    progress: Progress,
+}
+
+impl Incoming {
+    fn busy(&self) -> bool {
+        self.hashed < self.received || self.done
+    }
+}
+
+// 每前进 10% 打印一次进度
+struct Progress {
+    last_step: u64,
+}
+
+impl Progress {
+    fn new() -> Self {
+        Progress { last_step: u64::MAX }
+    }
+
+    fn report(&mut self, verb: &str, name: &str, done: u64, size: u64) {
+        let percent = (done * 100).checked_div(size).unwrap_or(100);
+        if percent / 10 != self.last_step {
+            self.last_step = percent / 10;
+            info!("[FILE] {verb} {name}: {percent}% ({done}/{size} bytes)");
+        }
+    }
+}
+
+// 一个会话中双方向的所有文件传输状态，由聊天主循环独占。
+// 命令和对方的消息只改变状态；真正的文件读写在 step 里进行，每次一个数据块，
+// 主循环在两次 step 之间照常处理输入、心跳和对方的消息
+pub struct Transfers {
+    download_dir: PathBuf,
+    next_id: u32,
+    outgoing: HashMap<u32, Outgoing>,
+    offers: VecDeque<Offer>,
+    incoming: HashMap<u32, Incoming>,
+}
+
+impl Transfers {
+    pub fn new(download_dir: impl Into<PathBuf>) -> Self {
+        Transfers {
+            download_dir: download_dir.into(),
+            next_id: 0,
+            outgoing: HashMap::new(),
+            offers: VecDeque::new(),
+            incoming: HashMap::new(),
+        }
+    }
+
+    // /send：打开文件，由 step 计算哈希，算完后 step 返回提议
+    pub fn offer(&mut self, path: &Path) -> io::Result<()> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+        let id = self.next_id;
+        self.next_id += 1;
+        info!("[FILE] Hashing {name} ({size} bytes)...");
+        let stage = Stage::Hashing { hasher: Sha256::new(), hashed: 0 };
+        self.outgoing.insert(id, Outgoing { name, size, file, stage, progress: Progress::new() });
+        Ok(())
+    }
+
+    // /accept：接受最早的提议；之前中断过的同一个文件从已收到的位置继续。
+    // .part 文件按哈希命名才能跨会话续传，所以同样内容的文件正在接收时，另一份直接拒绝，不能共用一个 .part
+    pub fn accept(&mut self) -> io::Result<FileMessage> {
+        let offer = self
+            .offers
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no pending file offer"))?;
+        if self.incoming.values().any(|incoming| incoming.hash == offer.hash) {
+            say!("[FILE] ✗ {} has the same content as a file still being received, rejecting this copy", offer.name);
+            return Ok(FileMessage::Reject { id: offer.id });
+        }
+        fs::create_dir_all(&self.download_dir)?;
+        let part_path = self
+            .download_dir
+            .join(format!(".{}.part", crate::chat::to_hex(&offer.hash)));
+        let file = OpenOptions::new().create(true).read(true).append(true).open(&part_path)?;
+        let mut received = file.metadata()?.len();
+        if received > offer.size {
+            // 残留的数据比文件还大，不可能是同一个文件的前缀，重新开始
+            file.set_len(0)?;
+            received = 0;
+        }
+        if received > 0 {
+            info!("[FILE] Resuming {} from byte {received}", offer.name);
+        } else {
+            info!("[FILE] Accepted {}", offer.name);
+        }
+        let id = offer.id;
+        self.incoming.insert(
+            id,
+            Incoming {
+                name: offer.name,
+                size: offer.size,
+                hash: offer.hash,
+                part_path,
+                file,
+                received,
+                hasher: Sha256::new(),
+                hashed: 0,
+                done: false,
+                progress: Progress::new(),
+            },
+        );
+        Ok(FileMessage::Accept { id, offset: received })
+    }
+
+    // /reject：拒绝最早的提议
+    pub fn reject(&mut self) -> io::Result<FileMessage> {
+        let offer = self
+            .offers
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no pending file offer"))?;
+        info!("[FILE] Rejected {}", offer.name);
+        Ok(FileMessage::Reject { id: offer.id })
+    }
+
+    // 处理对方发来的文件消息。这里不读写大块数据，需要做的工作留给 step
+    pub fn handle(&mut self, msg: FileMessage) -> io::Result<()> {
+        match msg {
+            FileMessage::Offer { id, size, hash, name } => {
+                let name = safe_file_name(&name);
+                say!("[FILE] Peer wants to send {name} ({size} bytes). Type {ACCEPT_COMMAND} or {REJECT_COMMAND}");
+                self.offers.push_back(Offer { id, size, hash, name });
+            }
+            FileMessage::Accept { id, offset } => {
+                let Some(outgoing) = self.outgoing.get_mut(&id).filter(|o| matches!(o.stage, Stage::Offered)) else {
+                    return Err(protocol_error(format!("peer accepted unknown transfer {id}")));
+                };
+                if offset > outgoing.size {
+                    return Err(protocol_error(format!("resume offset {offset} is past the end of {}", outgoing.name)));
+                }
+                if offset > 0 {
+                    info!("[FILE] Peer already has {offset} bytes of {}, resuming", outgoing.name);
+                }
+                outgoing.stage = Stage::Sending { sent: offset };
+            }
+            FileMessage::Reject { id } => {
+                if let Some(outgoing) = self.outgoing.remove(&id) {
+                    info!("[FILE] Peer rejected {}", outgoing.name);
+                }
+            }
+            FileMessage::Chunk { id, offset, data } => {
+                let Some(incoming) = self.incoming.get_mut(&id).filter(|i| !i.done) else {
+                    return Err(protocol_error(format!("data for unknown transfer {id}")));
+                };
+                if offset != incoming.received || offset + data.len() as u64 > incoming.size {
+                    return Err(protocol_error(format!(
+                        "chunk at byte {offset} does not fit {} (have {} of {} bytes)",
+                        incoming.name, incoming.received, incoming.size
+                    )));
+                }
+                incoming.file.write_all(&data)?;
+                if incoming.hashed == incoming.received {
+                    incoming.hasher.update(&data);
+                    incoming.hashed += data.len() as u64;
+                }
+                incoming.received += data.len() as u64;
+                incoming.progress.report("Receiving", &incoming.name, incoming.received, incoming.size);
+            }
+            FileMessage::Done { id } => {
+                let Some(incoming) = self.incoming.get_mut(&id) else {
+                    return Err(protocol_error(format!("end of unknown transfer {id}")));
+                };
+                incoming.done = true;
+            }
+            FileMessage::Verified { id, ok } => {
+                if let Some(outgoing) = self.outgoing.remove(&id) {
+                    if ok {
+                        info!("[FILE] ✓ Peer received {} intact", outgoing.name);
+                    } else {
+                        say!("[FILE] ✗ Peer could not save {} intact", outgoing.name);
+                    }
+                }
+            }
+            FileMessage::Cancel { id } => {
+                self.offers.retain(|offer| offer.id != id);
+                // .part 文件留着：对方下次发送同一个文件时从这里续传
+                if let Some(incoming) = self.incoming.remove(&id) {
+                    say!("[FILE] ✗ Peer cancelled {} after {} bytes", incoming.name, incoming.received);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 还有哈希要算、数据要发或者校验要做
+    pub fn busy(&self) -> bool {
+        self.incoming.values().any(Incoming::busy)
+            || self.outgoing.values().any(|o| matches!(o.stage, Stage::Hashing { .. } | Stage::Sending { .. }))
+    }
+
+    // 推进一个传输一步（读一个数据块），返回需要发给对方的消息。
+    // 出错的传输被放弃并通知对方，否则对方会一直等数据块或校验结果；其余的传输不受影响
+    pub fn step(&mut self) -> Option<FileMessage> {
+        if let Some(id) = self.incoming.iter().filter(|(_, i)| i.busy()).map(|(&id, _)| id).min() {
+            let name = self.incoming[&id].name.clone();
+            return self.step_incoming(id).unwrap_or_else(|e| {
+                self.incoming.remove(&id);
+                say!("[FILE] ✗ Receiving {name} failed: {e}");
+                Some(FileMessage::Verified { id, ok: false })
+            });
+        }
+        let active = self.outgoing.iter().filter(|(_, o)| !matches!(o.stage, Stage::Offered | Stage::Sent));
+        if let Some(id) = active.map(|(&id, _)| id).min() {
+            return self.step_outgoing(id).unwrap_or_else(|e| {
+                let outgoing = self.outgoing.remove(&id).expect("caller picked an existing transfer");
+                say!("[FILE] ✗ Sending {} failed: {e}", outgoing.name);
+                // 哈希还没算完时对方还不知道这个文件
+                (!matches!(outgoing.stage, Stage::Hashing { .. })).then_some(FileMessage::Cancel { id })
+            });
+        }
+        None
+    }
+
+    fn step_incoming(&mut self, id: u32) -> io::Result<Option<FileMessage>> {
+        let incoming = self.incoming.get_mut(&id).expect("caller picked an existing transfer");
+        if incoming.hashed < incoming.received {
+            let mut buffer = vec![0u8; CHUNK_SIZE.min((incoming.received - incoming.hashed) as usize)];
+            incoming.file.read_exact_at(&mut buffer, incoming.hashed)?;
+            incoming.hasher.update(&buffer);
+            incoming.hashed += buffer.len() as u64;
+            return Ok(None);
+        }
+
+        let incoming = self.incoming.remove(&id).expect("caller picked an existing transfer");
+        drop(incoming.file);
+        let ok = incoming.received == incoming.size && <[u8; 32]>::from(incoming.hasher.finalize()) == incoming.hash;
+        if ok {
+            let path = unique_path(&self.download_dir, &incoming.name);
+            fs::rename(&incoming.part_path, &path)?;
+            info!("[FILE] ✓ Saved {} (SHA-256 verified)", path.display());
+        } else {
+            // 校验失败的数据不能再用来续传
+            fs::remove_file(&incoming.part_path)?;
+            say!("[FILE] ✗ {} failed the SHA-256 check and was discarded", incoming.name);
+        }
+        Ok(Some(FileMessage::Verified { id, ok }))
+    }
+
+    fn step_outgoing(&mut self, id: u32) -> io::Result<Option<FileMessage>> {
+        let outgoing = self.outgoing.get_mut(&id).expect("caller picked an existing transfer");
+        match &mut outgoing.stage {
+            Stage::Hashing { hasher, hashed } => {
+                let mut buffer = vec![0u8; CHUNK_SIZE.min((outgoing.size - *hashed) as usize)];
+                outgoing.file.read_exact_at(&mut buffer, *hashed)?;
+                hasher.update(&buffer);
+                *hashed += buffer.len() as u64;
+                if *hashed < outgoing.size {
+                    return Ok(None);
+                }
+                let hash: [u8; 32] = std::mem::take(hasher).finalize().into();
+                outgoing.stage = Stage::Offered;
+                info!(
+                    "[FILE] Offering {} ({} bytes, SHA-256 {}), waiting for the peer...",
+                    outgoing.name,
+                    outgoing.size,
+                    crate::chat::to_hex(&hash)
+                );
+                Ok(Some(FileMessage::Offer { id, size: outgoing.size, hash, name: outgoing.name.clone() }))
+            }
+            Stage::Sending { sent } if *sent < outgoing.size => {
+                let offset = *sent;
+                let mut data = vec![0u8; CHUNK_SIZE.min((outgoing.size - offset) as usize)];
+                outgoing.file.read_exact_at(&mut data, offset)?;
+                *sent += data.len() as u64;
+                outgoing.progress.report("Sending", &outgoing.name, *sent, outgoing.size);
+                Ok(Some(FileMessage::Chunk { id, offset, data }))
+            }
+            Stage::Sending { .. } => {
+                outgoing.stage = Stage::Sent;
+                Ok(Some(FileMessage::Done { id }))
+            }
+            Stage::Offered | Stage::Sent => Ok(None),
+        }
+    }
+}
+
+fn protocol_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// 只保留对方文件名的最后一段，防止写到下载目录之外
+fn safe_file_name(name: &str) -> String {
+    match Path::new(name).file_name().and_then(|name| name.to_str()) {
+        Some(name) if !name.starts_with('.') => name.to_string(),
+        _ => "download".to_string(),
+    }
+}
+
+// 文件名冲突时依次尝试 “name (1).ext”、“name (2).ext”……
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let candidate = dir.join(name);
+    if !candidate.exists() {
+        return candidate;
+    }
+    let path = Path::new(name);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(name);
+    let extension = path.extension().and_then(|e| e.to_str());
+    (1..)
+        .map(|i| match extension {
+            Some(ext) => dir.join(format!("{stem} ({i}).{ext}")),
+            None => dir.join(format!("{stem} ({i})")),
+        })
+        .find(|candidate| !candidate.exists())
+        .expect("some numbered name is free")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust_03-transfer-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    // 让 transfers 做完手头的工作，返回途中要发给对方的消息
+    fn drain(transfers: &mut Transfers) -> Vec<FileMessage> {
+        let mut out = Vec::new();
+        while transfers.busy() {
+            out.extend(transfers.step());
+        }
+        out
+    }
+
+    // 把一条消息交给对方处理，返回对方回复的所有消息
+    fn deliver(to: &mut Transfers, msg: FileMessage) -> Vec<FileMessage> {
+        to.handle(FileMessage::decode(&msg.encode()).unwrap()).unwrap();
+        drain(to)
+    }
+
+    // /send：等哈希算完，返回提议
+    fn offer(sender: &mut Transfers, path: &Path) -> FileMessage {
+        sender.offer(path).unwrap();
+        let mut messages = drain(sender);
+        assert_eq!(messages.len(), 1);
+        messages.remove(0)
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = [
+            FileMessage::Offer { id: 7, size: 1 << 40, hash: [9u8; 32], name: "報告.pdf".to_string() },
+            FileMessage::Accept { id: 7, offset: 12345 },
+            FileMessage::Reject { id: 7 },
+            FileMessage::Chunk { id: 7, offset: 3, data: vec![1, 2, 3] },
+            FileMessage::Done { id: 7 },
+            FileMessage::Verified { id: 7, ok: true },
+            FileMessage::Cancel { id: 7 },
+        ];
+        for msg in messages {
+            assert_eq!(FileMessage::decode(&msg.encode()).unwrap(), msg);
+        }
+        assert!(FileMessage::decode(&[4, 0, 0, 0, 7, 1]).is_err());
+        assert!(FileMessage::decode(&[99, 0, 0, 0, 7]).is_err());
+    }
+
+    #[test]
+    fn file_is_chunked_verified_and_renamed_on_collision() {
+        let dir = temp_dir("collision");
+        let source = dir.join("notes.txt");
+        let content = sample(CHUNK_SIZE * 3 + 100);
+        fs::write(&source, &content).unwrap();
+        let downloads = dir.join("downloads");
+        fs::create_dir_all(&downloads).unwrap();
+        fs::write(downloads.join("notes.txt"), b"already here").unwrap();
+
+        let mut sender = Transfers::new(&dir);
+        let mut receiver = Transfers::new(&downloads);
+        let offer = offer(&mut sender, &source);
+        assert!(deliver(&mut receiver, offer).is_empty());
+
+        let accept = receiver.accept().unwrap();
+        assert_eq!(accept, FileMessage::Accept { id: 0, offset: 0 });
+        let stream = deliver(&mut sender, accept);
+        assert_eq!(stream.len(), 4 + 1);
+        let mut replies = Vec::new();
+        for msg in stream {
+            replies.extend(deliver(&mut receiver, msg));
+        }
+        assert_eq!(replies, vec![FileMessage::Verified { id: 0, ok: true }]);
+        assert!(deliver(&mut sender, replies.remove(0)).is_empty());
+
+        assert_eq!(fs::read(downloads.join("notes.txt")).unwrap(), b"already here");
+        assert_eq!(fs::read(downloads.join("notes (1).txt")).unwrap(), content);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // 传输中断后重新发送同一个文件，只传剩下的部分
+    #[test]
+    fn interrupted_transfer_resumes() {
+        let dir = temp_dir("resume");
+        let source = dir.join("video.bin");
+        let content = sample(CHUNK_SIZE * 5);
+        fs::write(&source, &content).unwrap();
+        let downloads = dir.join("downloads");
+
+        let mut sender = Transfers::new(&dir);
+        let mut receiver = Transfers::new(&downloads);
+        deliver(&mut receiver, offer(&mut sender, &source));
+        let stream = deliver(&mut sender, receiver.accept().unwrap());
+        for msg in stream.into_iter().take(2) {
+            deliver(&mut receiver, msg);
+        }
+        // 连接断开：双方的状态都丢失了，只剩磁盘上的 .part 文件
+        drop(receiver);
+
+        let mut sender = Transfers::new(&dir);
+        let mut receiver = Transfers::new(&downloads);
+        deliver(&mut receiver, offer(&mut sender, &source));
+        let accept = receiver.accept().unwrap();
+        assert_eq!(accept, FileMessage::Accept { id: 0, offset: 2 * CHUNK_SIZE as u64 });
+        let stream = deliver(&mut sender, accept);
+        assert_eq!(stream.len(), 3 + 1);
+        let mut replies = Vec::new();
+        for msg in stream {
+            replies.extend(deliver(&mut receiver, msg));
+        }
+        assert_eq!(replies, vec![FileMessage::Verified { id: 0, ok: true }]);
+        assert_eq!(fs::read(downloads.join("video.bin")).unwrap(), content);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_file_fails_the_hash_check() {
+        let dir = temp_dir("corrupt");
+        let source = dir.join("data.bin");
+        fs::write(&source, sample(1000)).unwrap();
+        let downloads = dir.join("downloads");
+
+        let mut sender = Transfers::new(&dir);
+        let mut receiver = Transfers::new(&downloads);
+        deliver(&mut receiver, offer(&mut sender, &source));
+        let mut stream = deliver(&mut sender, receiver.accept().unwrap());
+        if let FileMessage::Chunk { data, .. } = &mut stream[0] {
+            data[500] ^= 1;
+        }
+        let mut replies = Vec::new();
+        for msg in stream {
+            replies.extend(deliver(&mut receiver, msg));
+        }
+        assert_eq!(replies, vec![FileMessage::Verified { id: 0, ok: false }]);
+        assert!(!downloads.join("data.bin").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejected_offer_sends_nothing() {
+        let dir = temp_dir("reject");
+        let source = dir.join("secret.txt");
+        fs::write(&source, b"no thanks").unwrap();
+
+        let mut sender = Transfers::new(&dir);
+        let mut receiver = Transfers::new(dir.join("downloads"));
+        deliver(&mut receiver, offer(&mut sender, &source));
+        let reject = receiver.reject().unwrap();
+        assert!(deliver(&mut sender, reject).is_empty());
+        assert!(receiver.accept().is_err());
+        assert!(!dir.join("downloads").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // 哈希和发送都按数据块分步进行，每一步最多读一个数据块，聊天主循环在两步之间照常处理别的事件
+    #[test]
+    fn large_files_advance_one_chunk_per_step() {
+        let dir = temp_dir("steps");
+        let source = dir.join("big.bin");
+        fs::write(&source, sample(CHUNK_SIZE * 4)).unwrap();
+        let mut sender = Transfers::new(&dir);
+        let mut receiver = Transfers::new(dir.join("downloads"));
+
+        sender.offer(&source).unwrap();
+        for _ in 0..3 {
+            assert_eq!(sender.step(), None);
+        }
+        let offer = sender.step().unwrap();
+        assert!(matches!(offer, FileMessage::Offer { size, .. } if size == 4 * CHUNK_SIZE as u64));
+        assert!(!sender.busy(), "nothing to do until the peer answers");
+
+        deliver(&mut receiver, offer);
+        sender.handle(receiver.accept().unwrap()).unwrap();
+        for i in 0..4 {
+            match sender.step() {
+                Some(FileMessage::Chunk { offset, data, .. }) => {
+                    assert_eq!(offset, (i * CHUNK_SIZE) as u64);
+                    assert_eq!(data.len(), CHUNK_SIZE);
+                }
+                other => panic!("expected chunk {i}, got {other:?}"),
+            }
+        }
+        assert_eq!(sender.step(), Some(FileMessage::Done { id: 0 }));
+        assert!(!sender.busy());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // 同样内容的两份提议不能同时接收：它们会写进同一个 .part 文件
+    #[test]
+    fn duplicate_content_is_not_received_twice_at_once() {
+        let dir = temp_dir("duplicate");
+        let (first, second) = (dir.join("a.bin"), dir.join("b.bin"));
+        let content = sample(CHUNK_SIZE * 2 + 10);
+        fs::write(&first, &content).unwrap();
+        fs::write(&second, &content).unwrap();
+        let downloads = dir.join("downloads");
+
+        let mut sender = Transfers::new(&dir);
+        let mut receiver = Transfers::new(&downloads);
+        deliver(&mut receiver, offer(&mut sender, &first));
+        deliver(&mut receiver, offer(&mut sender, &second));
+        let accepted = receiver.accept().unwrap();
+        assert_eq!(receiver.accept().unwrap(), FileMessage::Reject { id: 1 });
+        assert!(deliver(&mut sender, FileMessage::Reject { id: 1 }).is_empty());
+
+        let mut replies = Vec::new();
+        for msg in deliver(&mut sender, accepted) {
+            replies.extend(deliver(&mut receiver, msg));
+        }
+        assert_eq!(replies, vec![FileMessage::Verified { id: 0, ok: true }]);
+        assert_eq!(fs::read(downloads.join("a.bin")).unwrap(), content);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // 任何一方出错放弃传输时都要通知对方，对方不能一直等下去
+    #[test]
+    fn failed_transfers_notify_the_peer() {
+        let dir = temp_dir("failed");
+        let source = dir.join("shrinking.bin");
+        fs::write(&source, sample(CHUNK_SIZE * 3)).unwrap();
+        let downloads = dir.join("downloads");
+
+        // 发送途中源文件变短了：发送方放弃并发出 Cancel，接收方清理状态，保留 .part 以便续传
+        let mut sender = Transfers::new(&dir);
+        let mut receiver = Transfers::new(&downloads);
+        deliver(&mut receiver, offer(&mut sender, &source));
+        sender.handle(receiver.accept().unwrap()).unwrap();
+        deliver(&mut receiver, sender.step().unwrap());
+        fs::write(&source, sample(CHUNK_SIZE)).unwrap();
+        assert_eq!(sender.step(), Some(FileMessage::Cancel { id: 0 }));
+        assert!(!sender.busy());
+        assert!(deliver(&mut receiver, FileMessage::Cancel { id: 0 }).is_empty());
+        assert!(receiver.incoming.is_empty());
+        let part = fs::read_dir(&downloads).unwrap().next().unwrap().unwrap().path();
+        assert_eq!(fs::metadata(&part).unwrap().len(), CHUNK_SIZE as u64);
+
+        // 接收方收齐了数据却没能改成正式文件名：回报失败，发送方不再等待
+        let mut sender = Transfers::new(&dir);
+        let mut receiver = Transfers::new(&downloads);
+        let offer = offer(&mut sender, &source);
+        deliver(&mut receiver, offer);
+        let mut stream = deliver(&mut sender, receiver.accept().unwrap());
+        let done = stream.pop().unwrap();
+        for msg in stream {
+            assert!(deliver(&mut receiver, msg).is_empty());
+        }
+        for part in fs::read_dir(&downloads).unwrap() {
+            fs::remove_file(part.unwrap().path()).unwrap();
+        }
+        assert_eq!(deliver(&mut receiver, done), vec![FileMessage::Verified { id: 0, ok: false }]);
+        assert!(deliver(&mut sender, FileMessage::Verified { id: 0, ok: false }).is_empty());
+        assert!(sender.outgoing.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn peer_file_names_stay_inside_the_download_dir() {
+        assert_eq!(safe_file_name("../../etc/passwd"), "passwd");
+        assert_eq!(safe_file_name("/tmp/x.txt"), "x.txt");
+        assert_eq!(safe_file_name(".."), "download");
+        assert_eq!(safe_file_name(".bashrc"), "download");
+    }
+}
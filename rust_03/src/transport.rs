@@ -0,0 +1,388 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+// 聊天会话可以运行在任何双向字节流上。除了读写之外，
+// 还需要复制出一个句柄交给接收线程，以及主动关闭某个方向
+pub trait Transport: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+impl Transport for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
+
+// 内存中的单向字节通道
+#[derive(Default)]
+struct Channel {
+    state: Mutex<ChannelState>,
+    readable: Condvar,
+}
+
+#[derive(Default)]
+struct ChannelState {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Channel {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_all();
+    }
+
+    // 阻塞直到有数据或通道关闭；关闭且读完之后返回 0（EOF）
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        while state.data.is_empty() && !state.closed {
+            state = self.readable.wait(state).unwrap();
+        }
+        let n = buf.len().min(state.data.len());
+        for (slot, byte) in buf.iter_mut().zip(state.data.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.data.extend(buf);
+        self.readable.notify_all();
+        Ok(buf.len())
+    }
+}
+
+// 内存双工管道的一端，行为与 TCP 连接相同：读取会阻塞直到有数据，
+// 对方关闭写方向（或所有句柄都被丢弃）后读到 EOF，向已关闭的方向写入返回 BrokenPipe
+pub struct PipeEnd {
+    incoming: Arc<Channel>,
+    outgoing: Arc<Channel>,
+    // 同一端的所有句柄共享，最后一个句柄被丢弃时关闭连接
+    handles: Arc<()>,
+}
+
+// 创建一对相连的内存管道端，用于在测试中代替 TCP 连接
+pub fn pipe() -> (PipeEnd, PipeEnd) {
+    let (a_to_b, b_to_a) = (Arc::new(Channel::default()), Arc::new(Channel::default()));
+    let a = PipeEnd {
+        incoming: b_to_a.clone(),
+        outgoing: a_to_b.clone(),
+        handles: Arc::new(()),
+    };
+    let b = PipeEnd {
+        incoming: a_to_b,
+        outgoing: b_to_a,
+        handles: Arc::new(()),
+    };
+    (a, b)
+}
+
+impl Read for PipeEnd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.incoming.read(buf)
+    }
+}
+
+impl Write for PipeEnd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for PipeEnd {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(PipeEnd {
+            incoming: self.incoming.clone(),
+            outgoing: self.outgoing.clone(),
+            handles: self.handles.clone(),
+        })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Write {
+            self.incoming.close();
+        }
+        if how != Shutdown::Read {
+            self.outgoing.close();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.handles) == 1 {
+            let _ = self.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+// 由一个读端和一个写端拼成的连接，例如 stdin/stdout 或者一对 OS 管道。
+// 普通文件描述符上阻塞的读取无法被 shutdown 唤醒（握手超时需要这一点），
+// 所以读端由后台线程搬进内存通道；写端见 Outgoing
+pub struct Duplex {
+    incoming: Arc<Channel>,
+    outgoing: Arc<Outgoing>,
+    handles: Arc<()>,
+}
+
+// 阻塞在文件描述符上的写同样无法被唤醒，关闭或 dup2 替换这个描述符都不行，而对方不再读取时
+// 写超时的看门狗要靠 shutdown 让它返回。所以写之前先和一个唤醒管道一起 poll，
+// 等到可写再写入不超过 PIPE_BUF 字节（管道报告可写时，这样的写不会阻塞），整个过程不持有任何锁
+struct Outgoing {
+    file: File,
+    closed: AtomicBool,
+    // shutdown 往写端写一个字节，卡在 poll 里的写随之返回
+    wake: (io::PipeReader, io::PipeWriter),
+    // shutdown 时 dup2 到 file 上：原来的写端随之关闭，对方读到 EOF，而文件描述符号不会被别的文件复用
+    null: File,
+}
+
+impl Outgoing {
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut fds = [
+            libc::pollfd { fd: self.file.as_raw_fd(), events: libc::POLLOUT, revents: 0 },
+            libc::pollfd { fd: self.wake.0.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        ];
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            // SAFETY: fds 是两个有效的 pollfd，poll 只写回 revents
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            // 对方关闭了读端时 poll 报告 POLLERR，下面的写会返回 BrokenPipe
+            if fds[0].revents != 0 {
+                break;
+            }
+        }
+        let written = (&self.file).write(&buf[..buf.len().min(libc::PIPE_BUF)])?;
+        // 刚好和 shutdown 赛跑时数据可能写进了 /dev/null
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        Ok(written)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let _ = (&self.wake.1).write(&[0]);
+        // SAFETY: 两个描述符都由 self 持有；dup2 只替换描述符表里 file 的那一项
+        if unsafe { libc::dup2(self.null.as_raw_fd(), self.file.as_raw_fd()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+pub fn duplex(mut reader: impl Read + Send + 'static, writer: impl Into<OwnedFd>) -> io::Result<Duplex> {
+    let outgoing = Outgoing {
+        file: File::from(writer.into()),
+        closed: AtomicBool::new(false),
+        wake: io::pipe()?,
+        null: File::options().write(true).open("/dev/null")?,
+    };
+    let incoming = Arc::new(Channel::default());
+    let pump = incoming.clone();
+    thread::spawn(move || {
+        let mut buf = [0u8; 8192];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if pump.write(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+        pump.close();
+    });
+    Ok(Duplex {
+        incoming,
+        outgoing: Arc::new(outgoing),
+        handles: Arc::new(()),
+    })
+}
+
+// 在 stdin/stdout 上运行协议，用于 ssh、socat 之类的隧道。协议独占原来的 stdout：
+// 先复制一份给协议使用，再把文件描述符 1 指向 stderr，之后程序打印的所有提示都出现在 stderr 上
+pub fn stdio() -> io::Result<Duplex> {
+    io::stdout().flush()?;
+    let protocol_out = io::stdout().as_fd().try_clone_to_owned()?;
+    // SAFETY: dup2 只替换文件描述符表中的 1 号，不触及任何 Rust 对象
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    duplex(io::stdin(), protocol_out)
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.incoming.read(buf)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Duplex {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Duplex {
+            incoming: self.incoming.clone(),
+            outgoing: self.outgoing.clone(),
+            handles: self.handles.clone(),
+        })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Write {
+            self.incoming.close();
+        }
+        if how != Shutdown::Read {
+            self.outgoing.close()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Duplex {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.handles) == 1 {
+            let _ = self.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn pipe_carries_bytes_both_ways() {
+        let (mut a, mut b) = pipe();
+        let echo = thread::spawn(move || {
+            let mut buffer = [0u8; 5];
+            b.read_exact(&mut buffer).unwrap();
+            b.write_all(&buffer).unwrap();
+        });
+        a.write_all(b"hello").unwrap();
+        let mut reply = [0u8; 5];
+        a.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"hello");
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn half_close_gives_eof_after_pending_data() {
+        let (mut a, mut b) = pipe();
+        a.write_all(b"last words").unwrap();
+        a.shutdown(Shutdown::Write).unwrap();
+        assert!(a.write_all(b"more").is_err());
+
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"last words");
+        // 另一个方向仍然可用
+        b.write_all(b"ok").unwrap();
+        let mut reply = [0u8; 2];
+        a.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"ok");
+    }
+
+    // 克隆出的句柄还在时连接保持打开，最后一个句柄被丢弃才关闭
+    #[test]
+    fn dropping_the_last_handle_closes_the_pipe() {
+        let (a, mut b) = pipe();
+        let mut clone = a.try_clone().unwrap();
+        drop(a);
+        clone.write_all(b"x").unwrap();
+        drop(clone);
+
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"x");
+        assert!(b.write_all(b"anyone?").is_err());
+    }
+
+    // 用两对 OS 管道模拟 --stdio 的两端：数据双向流动，关闭写方向后对方读到 EOF
+    #[test]
+    fn duplex_over_os_pipes_half_closes() {
+        let (mut a, mut b) = os_pipes();
+        a.write_all(b"over the pipe").unwrap();
+        a.shutdown(Shutdown::Write).unwrap();
+        assert!(a.write_all(b"more").is_err());
+
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"over the pipe");
+        b.write_all(b"ok").unwrap();
+        let mut reply = [0u8; 2];
+        a.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"ok");
+    }
+
+    // 握手超时要靠 shutdown 唤醒阻塞中的读取，即使底层管道另一端什么都没发
+    #[test]
+    fn shutdown_wakes_a_blocked_duplex_read() {
+        let (a, _silent) = os_pipes();
+        let mut reader = a.try_clone().unwrap();
+        let blocked = thread::spawn(move || reader.read(&mut [0u8; 1]).unwrap());
+        thread::sleep(std::time::Duration::from_millis(50));
+        a.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(blocked.join().unwrap(), 0);
+    }
+
+    fn os_pipes() -> (Duplex, Duplex) {
+        let (a_reader, b_writer) = io::pipe().unwrap();
+        let (b_reader, a_writer) = io::pipe().unwrap();
+        (duplex(a_reader, a_writer).unwrap(), duplex(b_reader, b_writer).unwrap())
+    }
+}
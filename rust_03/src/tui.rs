@@ -0,0 +1,546 @@
+use crate::log::{self, Level};
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::ExecutableCommand;
+use ratatui::crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{Frame, Terminal};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read};
+use std::os::fd::AsFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use unicode_width::UnicodeWidthChar;
+
+// 全屏聊天界面：上面是可以翻页的消息窗格（可选的右侧面板显示 --verbose / --teach 的协议跟踪），
+// 下面是固定的输入行和状态栏。界面线程独占终端，其他线程的输出经 log::redirect 发给它，
+// 所以收到的消息不会打断正在输入的内容
+
+// 每个窗格最多保留的行数
+const SCROLLBACK: usize = 10_000;
+// 界面线程检查新输出和退出标志的间隔
+const TICK: Duration = Duration::from_millis(50);
+const PROMPT: &str = "> ";
+
+// 状态栏显示的会话信息
+pub struct Status {
+    // 对方的角色和地址，例如 "SERVER 127.0.0.1:8080"
+    pub peer: String,
+    pub cipher: String,
+    // 对方身份密钥的指纹；None 表示会话没有认证
+    pub fingerprint: Option<String>,
+}
+
+// 运行中的界面。drop 时恢复终端，并把聊天窗格的内容打印出来，留在终端的滚动记录里
+pub struct Tui {
+    stop: Arc<AtomicBool>,
+    ui: Option<JoinHandle<Vec<String>>>,
+}
+
+// 接管终端并启动界面线程，返回界面和交给 run_chat 的输入（每按一次回车得到一行，Ctrl-D 结束输入）。
+// show_trace 决定跟踪面板一开始是否显示，运行中可以用 F2 切换
+pub fn start(status: Status, show_trace: bool) -> io::Result<(Tui, BufReader<LineInput>)> {
+    let mut terminal = open_screen()?;
+    let (lines, input) = mpsc::channel();
+    let (output, incoming) = mpsc::channel();
+    log::redirect(Some(output));
+    let stop = Arc::new(AtomicBool::new(false));
+    let ui = thread::spawn({
+        let stop = Arc::clone(&stop);
+        move || {
+            let mut view = View::new(status, show_trace, lines);
+            let result = view.run(&mut terminal, &incoming, &stop);
+            close_screen(&mut terminal);
+            // 收下停止前最后一刻的输出，让打印出来的记录是完整的
+            for (level, text) in incoming.try_iter() {
+                view.output(level, &text);
+            }
+            if let Err(e) = result {
+                // 界面坏了也不能丢输出：改回直接打印
+                log::redirect(None);
+                eprintln!("[TUI] ✗ Terminal error: {e}");
+            }
+            view.chat.lines.into_iter().map(|(_, text)| text).collect()
+        }
+    });
+    let input = LineInput { lines: input, pending: Cursor::new(Vec::new()) };
+    Ok((Tui { stop, ui: Some(ui) }, BufReader::new(input)))
+}
+
+// 界面画在终端上的类型
+type Screen = Terminal<CrosstermBackend<File>>;
+
+// 终端的一个复制句柄（dup 的 fd 1）。界面经它绘制，不经过 io::stdout() 和它的锁：
+// 别的线程卡在 stdout 上时界面照常刷新，Ctrl-C 也照常能退出
+fn tty() -> io::Result<File> {
+    Ok(File::from(io::stdout().as_fd().try_clone_to_owned()?))
+}
+
+// 进入原始模式和备用屏幕。panic 时先恢复终端，错误信息才显示得出来
+fn open_screen() -> io::Result<Screen> {
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = terminal::disable_raw_mode();
+        if let Ok(mut tty) = tty() {
+            let _ = tty.execute(LeaveAlternateScreen);
+        }
+        hook(info);
+    }));
+    let mut out = tty()?;
+    terminal::enable_raw_mode()?;
+    if let Err(e) = out.execute(EnterAlternateScreen) {
+        let _ = terminal::disable_raw_mode();
+        return Err(e);
+    }
+    Terminal::new(CrosstermBackend::new(out))
+}
+
+fn close_screen(screen: &mut Screen) {
+    let _ = terminal::disable_raw_mode();
+    let _ = screen.backend_mut().execute(LeaveAlternateScreen);
+    let _ = screen.show_cursor();
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        log::redirect(None);
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(ui) = self.ui.take()
+            && let Ok(transcript) = ui.join()
+        {
+            for line in transcript {
+                println!("{line}");
+            }
+        }
+    }
+}
+
+// run_chat 的输入：界面线程把每一行发过来；界面关闭输入（Ctrl-D）或退出时读到 EOF
+pub struct LineInput {
+    lines: Receiver<String>,
+    pending: Cursor<Vec<u8>>,
+}
+
+impl Read for LineInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.position() as usize == self.pending.get_ref().len() {
+            match self.lines.recv() {
+                Ok(line) => self.pending = Cursor::new(format!("{line}\n").into_bytes()),
+                Err(_) => return Ok(0),
+            }
+        }
+        self.pending.read(buf)
+    }
+}
+
+// 一个可以往回翻的窗格
+#[derive(Default)]
+struct Pane {
+    lines: VecDeque<(Style, String)>,
+    // 从底部往上翻过的屏幕行数（折行之后），0 表示跟随最新的内容
+    scroll: usize,
+    // 上次绘制时的内部尺寸，用于折行和翻页
+    width: usize,
+    height: usize,
+}
+
+impl Pane {
+    fn push(&mut self, style: Style, text: &str) {
+        // 控制字符（包括对方消息里的转义序列）会破坏界面，换成空格
+        let text: String = text.chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+        // 翻到历史里时，新内容不应该把正在看的内容顶走
+        if self.scroll > 0 {
+            self.scroll += wrap(&text, self.width).len();
+        }
+        self.lines.push_back((style, text));
+        if self.lines.len() > SCROLLBACK {
+            self.lines.pop_front();
+        }
+    }
+
+    fn page_up(&mut self) {
+        self.scroll += (self.height / 2).max(1);
+    }
+
+    fn page_down(&mut self) {
+        self.scroll = self.scroll.saturating_sub((self.height / 2).max(1));
+    }
+
+    // 折行后从底部往上数 scroll 行、高 height 的一屏。只折需要的那部分行，长历史也不会变慢
+    fn visible(&mut self, width: usize, height: usize) -> Vec<Line<'static>> {
+        self.width = width;
+        self.height = height;
+        let needed = self.scroll + height;
+        let mut rows = Vec::new();
+        for (style, text) in self.lines.iter().rev() {
+            rows.extend(wrap(text, width).into_iter().rev().map(|row| Line::styled(row, *style)));
+            if rows.len() >= needed {
+                break;
+            }
+        }
+        // 已经翻到最上面时不能再往上
+        self.scroll = self.scroll.min(rows.len().saturating_sub(height));
+        let mut screen: Vec<_> = rows.into_iter().skip(self.scroll).take(height).collect();
+        screen.reverse();
+        screen
+    }
+}
+
+// 按显示宽度折行：中文等宽字符占两列。width 为 0（还没绘制过）时不折行
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut rows = vec![String::new()];
+    let mut used = 0;
+    for c in text.chars() {
+        let w = c.width().unwrap_or(0);
+        if width > 0 && used > 0 && used + w > width {
+            rows.push(String::new());
+            used = 0;
+        }
+        rows.last_mut().expect("rows is never empty").push(c);
+        used += w;
+    }
+    rows
+}
+
+fn width(chars: &[char]) -> usize {
+    chars.iter().map(|c| c.width().unwrap_or(0)).sum()
+}
+
+// 界面状态，只在界面线程里使用
+struct View {
+    status: Status,
+    chat: Pane,
+    trace: Pane,
+    show_trace: bool,
+    // PageUp/PageDown 翻的是跟踪面板还是聊天窗格
+    trace_focused: bool,
+    input: Vec<char>,
+    cursor: usize,
+    // None 表示已经用 Ctrl-D 结束了输入
+    lines: Option<Sender<String>>,
+}
+
+impl View {
+    fn new(status: Status, show_trace: bool, lines: Sender<String>) -> Self {
+        View {
+            status,
+            chat: Pane::default(),
+            trace: Pane::default(),
+            show_trace,
+            trace_focused: false,
+            input: Vec::new(),
+            cursor: 0,
+            lines: Some(lines),
+        }
+    }
+
+    fn run(
+        &mut self,
+        terminal: &mut Screen,
+        incoming: &Receiver<(Level, String)>,
+        stop: &AtomicBool,
+    ) -> io::Result<()> {
+        loop {
+            self.refresh(terminal, incoming)?;
+            if stop.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            if event::poll(TICK)?
+                && let Event::Key(key) = event::read()?
+                && key.kind != KeyEventKind::Release
+            {
+                self.key(key);
+            }
+        }
+    }
+
+    // 收下其他线程的输出，画一帧
+    fn refresh(&mut self, terminal: &mut Screen, incoming: &Receiver<(Level, String)>) -> io::Result<()> {
+        for (level, text) in incoming.try_iter() {
+            self.output(level, &text);
+        }
+        terminal.draw(|frame| self.draw(frame))?;
+        Ok(())
+    }
+
+    // 聊天内容和状态进聊天窗格；--verbose 的协议细节和 --teach 的演示进跟踪面板
+    fn output(&mut self, level: Level, text: &str) {
+        for line in text.split('\n') {
+            match level {
+                Level::Quiet if line.contains('✗') || line.contains('⚠') => {
+                    self.chat.push(Style::new().fg(Color::Red), line)
+                }
+                Level::Quiet => self.chat.push(Style::new(), line),
+                Level::Normal => self.chat.push(Style::new().add_modifier(Modifier::DIM), line),
+                Level::Verbose => self.trace.push(Style::new(), line),
+                // 教学输出可能包含秘密，用醒目的颜色
+                Level::Teach => self.trace.push(Style::new().fg(Color::Yellow), line),
+            }
+        }
+    }
+
+    fn key(&mut self, key: KeyEvent) {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            // 原始模式下 Ctrl-C 只是一个按键，转成 SIGINT 交给 run_chat 原有的处理：立即关闭连接
+            KeyCode::Char('c') if ctrl => unsafe {
+                libc::raise(libc::SIGINT);
+            },
+            // 和普通终端一样，空行上的 Ctrl-D 结束输入：半关闭连接，等对方结束
+            KeyCode::Char('d') if ctrl && self.input.is_empty() => self.lines = None,
+            KeyCode::Char('u') if ctrl => {
+                self.input.clear();
+                self.cursor = 0;
+            }
+            KeyCode::Char(c) if !ctrl => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::Enter => self.submit(),
+            KeyCode::PageUp => self.focused().page_up(),
+            KeyCode::PageDown => self.focused().page_down(),
+            KeyCode::Tab if self.show_trace => self.trace_focused = !self.trace_focused,
+            KeyCode::F(2) => {
+                self.show_trace = !self.show_trace;
+                self.trace_focused &= self.show_trace;
+            }
+            _ => {}
+        }
+    }
+
+    fn focused(&mut self) -> &mut Pane {
+        if self.trace_focused { &mut self.trace } else { &mut self.chat }
+    }
+
+    fn submit(&mut self) {
+        let Some(lines) = &self.lines else { return };
+        let line: String = self.input.drain(..).collect();
+        self.cursor = 0;
+        if line.trim().is_empty() {
+            return;
+        }
+        self.chat.push(Style::new().fg(Color::Cyan), &format!("[YOU] {line}"));
+        // 自己发出的消息总是回到最新的位置
+        self.chat.scroll = 0;
+        let _ = lines.send(line);
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [body, input, status] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(1), Constraint::Length(1)]).areas(frame.area());
+        if self.show_trace {
+            let [chat, trace] =
+                Layout::horizontal([Constraint::Percentage(55), Constraint::Percentage(45)]).areas(body);
+            draw_pane(frame, chat, &mut self.chat, "Chat", !self.trace_focused);
+            draw_pane(frame, trace, &mut self.trace, "Trace", self.trace_focused);
+        } else {
+            draw_pane(frame, body, &mut self.chat, "Chat", true);
+        }
+        self.draw_input(frame, input);
+        let bar = Paragraph::new(self.status_line()).style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_widget(bar, status);
+    }
+
+    // 输入行。内容比屏幕宽时水平滚动，保证光标可见
+    fn draw_input(&self, frame: &mut Frame, area: Rect) {
+        if self.lines.is_none() {
+            let closed = Span::styled("(input closed, waiting for the peer to finish)", Modifier::DIM);
+            frame.render_widget(Paragraph::new(closed), area);
+            return;
+        }
+        let room = (area.width as usize).saturating_sub(PROMPT.len() + 1);
+        let mut start = 0;
+        while start < self.cursor && width(&self.input[start..self.cursor]) > room {
+            start += 1;
+        }
+        let shown: String = self.input[start..].iter().collect();
+        frame.render_widget(Paragraph::new(Line::from(vec![Span::raw(PROMPT), Span::raw(shown)])), area);
+        let x = area.x as usize + PROMPT.len() + width(&self.input[start..self.cursor]);
+        frame.set_cursor_position((x.min(u16::MAX as usize) as u16, area.y));
+    }
+
+    fn status_line(&self) -> Line<'static> {
+        let fingerprint = match &self.status.fingerprint {
+            Some(fingerprint) => Span::raw(fingerprint.clone()),
+            None => Span::styled("⚠ NOT authenticated", Style::new().fg(Color::Red).add_modifier(Modifier::BOLD)),
+        };
+        Line::from(vec![
+            Span::raw(format!(" {} │ {} │ ", self.status.peer, self.status.cipher)),
+            fingerprint,
+            Span::raw(" │ PgUp/PgDn scroll · Tab pane · F2 trace · Ctrl-C quit"),
+        ])
+    }
+}
+
+fn draw_pane(frame: &mut Frame, area: Rect, pane: &mut Pane, title: &str, focused: bool) {
+    let block = Block::bordered();
+    let inner = block.inner(area);
+    let rows = pane.visible(inner.width as usize, inner.height as usize);
+    let title = match pane.scroll {
+        0 => format!(" {title} "),
+        n => format!(" {title} (scrolled back {n} rows) "),
+    };
+    let border = if focused { Style::new().fg(Color::Cyan) } else { Style::new() };
+    frame.render_widget(Paragraph::new(rows).block(block.title(title).border_style(border)), area);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::Terminal;
+    use ratatui::backend::TestBackend;
+    use ratatui::buffer::Buffer;
+    use ratatui::{TerminalOptions, Viewport};
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    fn rows(buffer: &Buffer) -> Vec<String> {
+        (0..buffer.area.height)
+            .map(|y| (0..buffer.area.width).map(|x| buffer[(x, y)].symbol()).collect())
+            .collect()
+    }
+
+    fn press(view: &mut View, code: KeyCode, modifiers: KeyModifiers) {
+        view.key(KeyEvent::new(code, modifiers));
+    }
+
+    fn type_text(view: &mut View, text: &str) {
+        for c in text.chars() {
+            press(view, KeyCode::Char(c), KeyModifiers::NONE);
+        }
+    }
+
+    #[test]
+    fn wrap_counts_display_width() {
+        assert_eq!(wrap("abcdef", 4), ["abcd", "ef"]);
+        // 中文字符占两列
+        assert_eq!(wrap("你好世界", 5), ["你好", "世界"]);
+        assert_eq!(wrap("", 4), [""]);
+        assert_eq!(wrap("abcdef", 0), ["abcdef"]);
+    }
+
+    // 翻到历史里时新消息不会挪动视图；翻页在最上面和最下面停住
+    #[test]
+    fn scrollback_holds_its_place() {
+        let mut pane = Pane::default();
+        for i in 0..20 {
+            pane.push(Style::new(), &format!("line {i}"));
+        }
+        let text = |lines: Vec<Line>| lines.iter().map(|line| line.to_string()).collect::<Vec<_>>();
+        assert_eq!(text(pane.visible(10, 4)), ["line 16", "line 17", "line 18", "line 19"]);
+
+        pane.page_up();
+        assert_eq!(text(pane.visible(10, 4)), ["line 14", "line 15", "line 16", "line 17"]);
+        pane.push(Style::new(), "line 20 is long enough to wrap");
+        assert_eq!(text(pane.visible(10, 4)), ["line 14", "line 15", "line 16", "line 17"]);
+
+        for _ in 0..20 {
+            pane.page_up();
+        }
+        assert_eq!(text(pane.visible(10, 4))[0], "line 0");
+        for _ in 0..20 {
+            pane.page_down();
+        }
+        assert_eq!(text(pane.visible(10, 4)), ["line 19", "line 20 is", " long enou", "gh to wrap"]);
+    }
+
+    // 大量消息涌入时输入行和状态栏保持原样；回车把这一行交给 run_chat，Ctrl-D 结束输入
+    #[test]
+    fn input_line_is_never_clobbered() {
+        let (lines, received) = mpsc::channel();
+        let status = Status {
+            peer: "SERVER 127.0.0.1:8080".to_string(),
+            cipher: "chacha20".to_string(),
+            fingerprint: Some("SHA256:abcd".to_string()),
+        };
+        let mut view = View::new(status, true, lines);
+        let mut terminal = Terminal::new(TestBackend::new(100, 12)).unwrap();
+
+        type_text(&mut view, "helo");
+        press(&mut view, KeyCode::Left, KeyModifiers::NONE);
+        type_text(&mut view, "l");
+        for i in 0..50 {
+            view.output(Level::Quiet, &format!("[SERVER] message {i}"));
+            view.output(Level::Verbose, &format!("[DECRYPT] Message #{i}"));
+        }
+        terminal.draw(|frame| view.draw(frame)).unwrap();
+        let screen = rows(terminal.backend().buffer());
+        assert!(screen[10].starts_with("> hello "), "{:?}", screen[10]);
+        assert!(screen[11].contains("SERVER 127.0.0.1:8080 │ chacha20 │ SHA256:abcd"), "{:?}", screen[11]);
+        assert!(screen[8].contains("[SERVER] message 49"), "{:?}", screen[8]);
+        assert!(screen[8].contains("[DECRYPT] Message #49"), "{:?}", screen[8]);
+        terminal.backend_mut().assert_cursor_position((6, 10));
+
+        press(&mut view, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(received.try_recv().unwrap(), "hello");
+        assert_eq!(view.chat.lines.back().unwrap().1, "[YOU] hello");
+
+        let mut input = LineInput { lines: received, pending: Cursor::new(Vec::new()) };
+        press(&mut view, KeyCode::Char('d'), KeyModifiers::CONTROL);
+        let mut rest = String::new();
+        input.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "");
+    }
+
+    // 对方不再读取、发送卡在网络写上时（以前这时还持有 stdout 锁），界面照常收下输出、刷新画面
+    #[test]
+    fn ui_keeps_drawing_while_the_peer_stalls() {
+        let (mut stalled, peer) = UnixStream::pair().unwrap();
+        let (locked, holding) = mpsc::channel();
+        let sender = thread::spawn(move || {
+            let _stdout = io::stdout().lock();
+            locked.send(()).unwrap();
+            // 对方不读，写满缓冲区后一直阻塞，直到对方关闭
+            let _ = stalled.write_all(&vec![0; 16 << 20]);
+        });
+        holding.recv().unwrap();
+
+        let path = std::env::temp_dir().join(format!("rust_03-tui-{}", std::process::id()));
+        let out = File::create(&path).unwrap();
+        let (output, incoming) = mpsc::channel();
+        let (drawn, frames) = mpsc::channel();
+        thread::spawn(move || {
+            let (lines, _input) = mpsc::channel();
+            let status = Status {
+                peer: "SERVER 127.0.0.1:8080".to_string(),
+                cipher: "chacha20".to_string(),
+                fingerprint: None,
+            };
+            let mut view = View::new(status, false, lines);
+            // 测试里没有真正的终端，用固定大小的画面
+            let options = TerminalOptions { viewport: Viewport::Fixed(Rect::new(0, 0, 80, 24)) };
+            let mut terminal = Terminal::with_options(CrosstermBackend::new(out), options).unwrap();
+            for i in 0..20 {
+                output.send((Level::Quiet, format!("[SERVER] message {i}"))).unwrap();
+                view.refresh(&mut terminal, &incoming).unwrap();
+                drawn.send(view.chat.lines.len()).unwrap();
+            }
+        });
+        for i in 1..=20 {
+            assert_eq!(frames.recv_timeout(Duration::from_secs(5)), Ok(i), "the UI stopped drawing");
+        }
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+
+        drop(peer);
+        sender.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}